@@ -1,7 +1,7 @@
 mod common;
 
 use agatedb::ChecksumVerificationMode::NoVerification;
-use agatedb::{AgateIterator, Table, TableBuilder, TableOptions, Value};
+use agatedb::{AgateIterator, CompressionType, Table, TableBuilder, TableOptions, Value};
 
 use std::ops::{Deref, DerefMut};
 
@@ -29,14 +29,19 @@ fn bench_table_builder(c: &mut Criterion) {
             bloom_false_positive: 0.01,
             table_size: 5 << 20,
             checksum_mode: NoVerification,
+            prefix_extractor: None,
+            compression: CompressionType::None,
+            block_cache: None,
+            index_cache: None,
+            data_key: None,
         };
 
         b.iter(|| {
             let mut builder = TableBuilder::new(opt.clone());
             for j in 0..KEY_COUNT {
-                builder.add(&key_list[j], vs.clone(), 0);
+                builder.add(&key_list[j], vs.clone(), 0).unwrap();
             }
-            builder.finish()
+            builder.finish().unwrap()
         });
     });
 }
@@ -73,6 +78,11 @@ fn get_table_for_benchmark(count: usize) -> TableGuard {
         bloom_false_positive: 0.01,
         table_size: 0,
         checksum_mode: NoVerification,
+        prefix_extractor: None,
+        compression: CompressionType::None,
+        block_cache: None,
+        index_cache: None,
+        data_key: None,
     };
 
     let mut builder = TableBuilder::new(opts.clone());
@@ -81,11 +91,11 @@ fn get_table_for_benchmark(count: usize) -> TableGuard {
     for i in 0..count {
         let k = Bytes::from(format!("{:016x}", i));
         let v = Bytes::from(i.to_string());
-        builder.add(&k, Value::new(v), 0);
+        builder.add(&k, Value::new(v), 0).unwrap();
     }
 
     TableGuard {
-        table: Table::create(&filename, builder.finish(), opts).unwrap(),
+        table: Table::create(&filename, builder.finish().unwrap(), opts).unwrap(),
         _tmp_dir: tmp_dir,
     }
 }
@@ -108,6 +118,11 @@ fn bench_table(c: &mut Criterion) {
         bloom_false_positive: 0.01,
         table_size: 0,
         checksum_mode: NoVerification,
+        prefix_extractor: None,
+        compression: CompressionType::None,
+        block_cache: None,
+        index_cache: None,
+        data_key: None,
     };
 
     c.bench_function("table read and build", |b| {
@@ -117,10 +132,12 @@ fn bench_table(c: &mut Criterion) {
             let mut builder = TableBuilder::new(builder_opts.clone());
             it.seek_to_first();
             while it.valid() {
-                builder.add(&Bytes::copy_from_slice(it.key()), it.value(), 0);
+                builder
+                    .add(&Bytes::copy_from_slice(it.key()), it.value(), 0)
+                    .unwrap();
                 it.next();
             }
-            builder.finish()
+            builder.finish().unwrap()
         });
     });
 
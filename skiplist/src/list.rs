@@ -10,6 +10,9 @@ use std::{mem, ptr, u32};
 
 const HEIGHT_INCREASE: u32 = u32::MAX / 3;
 
+/// The maximum size a node could take in arena.
+pub const MAX_NODE_SIZE: usize = mem::size_of::<Node>();
+
 // Uses C layout to make sure tower is at the bottom
 #[derive(Debug)]
 #[repr(C)]
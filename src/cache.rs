@@ -0,0 +1,313 @@
+use crate::table::Block;
+
+use parking_lot::Mutex;
+use proto::meta::TableIndex;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+const NUM_SHARDS: usize = 16;
+
+/// Key of a cached block, which is `(table id, block index)`.
+pub type BlockKey = (u64, usize);
+
+/// Cache of decoded SST blocks.
+pub type BlockCache = Cache<BlockKey, Block>;
+
+/// Cache of SST indices (block offsets and bloom filter), keyed by table id.
+pub type IndexCache = Cache<u64, TableIndex>;
+
+/// Values stored in cache.
+pub trait CacheValue {
+    /// Size charged against capacity of cache.
+    fn charge(&self) -> u64;
+}
+
+impl CacheValue for Block {
+    fn charge(&self) -> u64 {
+        self.size()
+    }
+}
+
+impl CacheValue for TableIndex {
+    fn charge(&self) -> u64 {
+        prost::Message::encoded_len(self) as u64
+    }
+}
+
+/// An LRU shard of cache.
+struct Shard<K, V> {
+    capacity: u64,
+    size: u64,
+    /// Increased on each access, used to order values by last access.
+    tick: u64,
+    values: HashMap<K, (Arc<V>, u64)>,
+    /// Keys ordered by last access, from the least recently used.
+    lru: BTreeMap<u64, K>,
+}
+
+impl<K: Hash + Eq + Clone, V: CacheValue> Shard<K, V> {
+    fn new(capacity: u64) -> Self {
+        Self {
+            capacity,
+            size: 0,
+            tick: 0,
+            values: HashMap::new(),
+            lru: BTreeMap::new(),
+        }
+    }
+
+    fn get(&mut self, key: &K) -> Option<Arc<V>> {
+        let (value, tick) = self.values.get_mut(key)?;
+        self.lru.remove(tick);
+        self.tick += 1;
+        *tick = self.tick;
+        self.lru.insert(self.tick, key.clone());
+        Some(value.clone())
+    }
+
+    fn insert(&mut self, key: K, value: Arc<V>) {
+        let size = value.charge();
+        if size > self.capacity {
+            return;
+        }
+        self.remove(&key);
+        while self.size + size > self.capacity {
+            let (_, key) = self.lru.pop_first().unwrap();
+            let (value, _) = self.values.remove(&key).unwrap();
+            self.size -= value.charge();
+        }
+        self.tick += 1;
+        self.lru.insert(self.tick, key.clone());
+        self.values.insert(key, (value, self.tick));
+        self.size += size;
+    }
+
+    fn remove(&mut self, key: &K) {
+        if let Some((value, tick)) = self.values.remove(key) {
+            self.lru.remove(&tick);
+            self.size -= value.charge();
+        }
+    }
+}
+
+/// `Cache` is a size-bounded cache shared by all tables of an agatedb
+/// instance, which evicts least recently used values once total size of
+/// values exceeds capacity.
+///
+/// The cache is split into shards by key to reduce lock contention.
+pub struct Cache<K, V> {
+    capacity: u64,
+    shards: Vec<Mutex<Shard<K, V>>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl<K: Hash + Eq + Clone, V: CacheValue> Cache<K, V> {
+    /// Create a cache of which total size of values is at most `capacity`
+    /// bytes.
+    pub fn new(capacity: u64) -> Self {
+        let shards = (0..NUM_SHARDS)
+            .map(|_| Mutex::new(Shard::new(capacity / NUM_SHARDS as u64)))
+            .collect();
+        Self {
+            capacity,
+            shards,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    fn shard(&self, key: &K) -> &Mutex<Shard<K, V>> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % NUM_SHARDS]
+    }
+
+    /// Get a value, and mark it as recently used.
+    pub fn get(&self, key: &K) -> Option<Arc<V>> {
+        let value = self.shard(key).lock().get(key);
+        if value.is_some() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+        value
+    }
+
+    /// Insert a value. Values bigger than capacity of a shard are never
+    /// cached.
+    pub fn insert(&self, key: K, value: Arc<V>) {
+        self.shard(&key).lock().insert(key, value);
+    }
+
+    pub fn remove(&self, key: &K) {
+        self.shard(key).lock().remove(key);
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    /// Total size of cached values.
+    pub fn size(&self) -> u64 {
+        self.shards.iter().map(|s| s.lock().size).sum()
+    }
+
+    /// Number of values found in cache.
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Number of values not found in cache.
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+impl<K, V> fmt::Debug for Cache<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Cache")
+            .field("capacity", &self.capacity)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::user_key;
+    use crate::table::tests::{build_test_table, get_test_table_options};
+    use crate::table::{ITERATOR_NOCACHE, ITERATOR_REVERSED};
+    use crate::AgateIterator;
+
+    #[test]
+    fn test_block_cache() {
+        let cache = Arc::new(BlockCache::new(NUM_SHARDS as u64 * (16 << 10)));
+        let mut opts = get_test_table_options();
+        opts.block_cache = Some(cache.clone());
+        let table = build_test_table(b"key", 10000, opts);
+        let n = table.offsets_length();
+        assert!(n > NUM_SHARDS * 2);
+
+        // Blocks are cached on first read. Blocks read when opening table
+        // are not cached.
+        assert_eq!(cache.size(), 0);
+        let misses = cache.misses();
+        let block = table.block(0, true).unwrap();
+        assert_eq!((cache.hits(), cache.misses()), (0, misses + 1));
+        assert!(Arc::ptr_eq(&block, &table.block(0, true).unwrap()));
+        assert_eq!((cache.hits(), cache.misses()), (1, misses + 1));
+        assert_eq!(cache.size(), block.size());
+
+        // Scans with `ITERATOR_NOCACHE` don't fill cache.
+        let mut it = table.new_iterator(ITERATOR_NOCACHE | ITERATOR_REVERSED);
+        it.rewind();
+        while it.valid() {
+            it.next();
+        }
+        assert_eq!(cache.size(), block.size());
+        cache.remove(&(table.id(), 0));
+        assert_eq!(cache.size(), 0);
+
+        // Least recently used blocks are evicted when cache is full.
+        let mut it = table.new_iterator(0);
+        it.rewind();
+        while it.valid() {
+            it.next();
+        }
+        let total_size: u64 = (0..n).map(|i| table.block(i, false).unwrap().size()).sum();
+        assert!(cache.size() <= cache.capacity());
+        assert!(cache.size() < total_size);
+        let misses = cache.misses();
+        table.block(n - 1, true).unwrap();
+        assert_eq!(cache.misses(), misses);
+    }
+
+    fn check_table(table: &crate::Table, n: usize) {
+        let mut it = table.new_iterator(0);
+        it.rewind();
+        let mut count = 0;
+        while it.valid() {
+            assert!(!table
+                .does_not_have(farmhash::fingerprint32(user_key(it.key())))
+                .unwrap());
+            count += 1;
+            it.next();
+        }
+        assert_eq!(count, n);
+    }
+
+    #[test]
+    fn test_index_cache() {
+        let cache = Arc::new(IndexCache::new(NUM_SHARDS as u64 * (1 << 20)));
+        let mut opts = get_test_table_options();
+        opts.index_cache = Some(cache.clone());
+        let table = build_test_table(b"key", 10000, opts.clone());
+
+        // Index is put into cache when opening table.
+        let index = table.fetch_index().unwrap();
+        assert_eq!(cache.size(), index.charge());
+        assert!(Arc::ptr_eq(&index, &table.fetch_index().unwrap()));
+
+        // Evicted index is loaded again on demand.
+        cache.remove(&table.id());
+        assert_eq!(cache.size(), 0);
+        let misses = cache.misses();
+        check_table(&table, 10000);
+        assert_eq!(cache.misses(), misses + 1);
+        assert_eq!(cache.size(), index.charge());
+
+        // Index is removed from cache once table is dropped.
+        drop(table);
+        assert_eq!(cache.size(), 0);
+
+        // Index bigger than cache is read from table every time.
+        let cache = Arc::new(IndexCache::new(NUM_SHARDS as u64));
+        opts.index_cache = Some(cache.clone());
+        let table = build_test_table(b"key", 10000, opts);
+        check_table(&table, 10000);
+        assert_eq!(cache.size(), 0);
+        assert_eq!(cache.hits(), 0);
+    }
+
+    #[test]
+    fn test_index_cache_read_error() {
+        use bytes::Bytes;
+        use std::convert::TryInto;
+        use std::fs::OpenOptions;
+        use std::io::{Seek, SeekFrom, Write};
+
+        let cache = Arc::new(IndexCache::new(NUM_SHARDS as u64 * (1 << 20)));
+        let mut opts = get_test_table_options();
+        opts.index_cache = Some(cache.clone());
+        let table = build_test_table(b"key", 1000, opts);
+
+        // Overwrite index, which is followed by index length, checksum and
+        // checksum length.
+        let data = std::fs::read(table.filename()).unwrap();
+        let u32_at = |end: usize| u32::from_be_bytes(data[end - 4..end].try_into().unwrap());
+        let index_end = data.len() - 4 - u32_at(data.len()) as usize - 4;
+        let index_len = u32_at(index_end + 4) as usize;
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(table.filename())
+            .unwrap();
+        file.seek(SeekFrom::Start((index_end - index_len) as u64))
+            .unwrap();
+        file.write_all(&vec![0xff; index_len]).unwrap();
+        file.sync_all().unwrap();
+
+        // Evicted index can't be loaded again, which fails reads instead of
+        // panicking.
+        cache.remove(&table.id());
+        assert!(table.block(0, true).is_err());
+        assert!(table.does_not_have(0).is_err());
+        let mut it = table.new_iterator(0);
+        it.seek(&Bytes::from("key"));
+        assert!(!it.valid());
+    }
+}
@@ -0,0 +1,135 @@
+use crossbeam_channel::{Receiver, Sender};
+use std::sync::{Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+
+/// `Closer` manages background workers of an agatedb instance.
+///
+/// Workers are spawned with a receiver, which becomes ready (disconnected)
+/// once `close` is called. `close` then waits for all workers to exit.
+pub struct Closer {
+    tx: Mutex<Option<Sender<()>>>,
+    rx: Receiver<()>,
+    handles: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Closer {
+    pub fn new() -> Self {
+        let (tx, rx) = crossbeam_channel::bounded(0);
+        Self {
+            tx: Mutex::new(Some(tx)),
+            rx,
+            handles: Mutex::new(vec![]),
+        }
+    }
+
+    /// Spawn a named background worker. The worker should exit once
+    /// the given receiver is ready.
+    pub fn spawn<F>(&self, name: &str, f: F)
+    where
+        F: FnOnce(Receiver<()>) + Send + 'static,
+    {
+        let rx = self.rx.clone();
+        let handle = thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || f(rx))
+            .unwrap();
+        self.handles.lock().unwrap().push(handle);
+    }
+
+    /// Get a receiver which becomes ready (disconnected) once `close` is
+    /// called.
+    pub fn closed(&self) -> Receiver<()> {
+        self.rx.clone()
+    }
+
+    /// Signal all workers to stop and wait for them.
+    pub fn close(&self) {
+        self.tx.lock().unwrap().take();
+        let handles: Vec<_> = self.handles.lock().unwrap().drain(..).collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+}
+
+impl Drop for Closer {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// `Notifier` wakes up routines waiting for background workers, e.g.
+/// writers waiting for flush or compaction to make room.
+#[derive(Default)]
+pub struct Notifier {
+    lock: Mutex<()>,
+    cond: Condvar,
+}
+
+impl Notifier {
+    /// Wake up all waiting routines, which should be called after the state
+    /// they wait for is changed.
+    pub fn notify(&self) {
+        let _guard = self.lock.lock().unwrap();
+        self.cond.notify_all();
+    }
+
+    /// Block until `ready` returns `Some`. `ready` is checked first, and
+    /// then each time `notify` is called.
+    pub fn wait_until<T>(&self, mut ready: impl FnMut() -> Option<T>) -> T {
+        let mut guard = self.lock.lock().unwrap();
+        loop {
+            if let Some(result) = ready() {
+                return result;
+            }
+            guard = self.cond.wait(guard).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_closer() {
+        let closer = Closer::new();
+        let counter = Arc::new(AtomicUsize::new(0));
+        for i in 0..4 {
+            let counter = counter.clone();
+            closer.spawn(&format!("worker-{}", i), move |closed| {
+                assert!(closed.recv().is_err());
+                counter.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        closer.close();
+        assert_eq!(counter.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn test_notifier() {
+        let notifier = Arc::new(Notifier::default());
+        let counter = Arc::new(AtomicUsize::new(0));
+        let handle = {
+            let notifier = notifier.clone();
+            let counter = counter.clone();
+            thread::spawn(move || {
+                notifier.wait_until(|| {
+                    let count = counter.load(Ordering::SeqCst);
+                    if count >= 3 {
+                        Some(count)
+                    } else {
+                        None
+                    }
+                })
+            })
+        };
+        for _ in 0..3 {
+            counter.fetch_add(1, Ordering::SeqCst);
+            notifier.notify();
+        }
+        assert_eq!(handle.join().unwrap(), 3);
+    }
+}
@@ -0,0 +1,52 @@
+use crate::opt::CompressionType;
+use crate::{Error, Result};
+
+/// Compression level of zstd, which favors speed over ratio.
+const ZSTD_COMPRESSION_LEVEL: i32 = 1;
+
+pub fn compress(data: &[u8], compression: CompressionType) -> Result<Vec<u8>> {
+    match compression {
+        CompressionType::None => Ok(data.to_vec()),
+        CompressionType::Snappy => snap::raw::Encoder::new()
+            .compress_vec(data)
+            .map_err(|e| Error::Compression(e.to_string())),
+        CompressionType::Lz4 => Ok(lz4_flex::compress_prepend_size(data)),
+        CompressionType::Zstd => Ok(zstd::encode_all(data, ZSTD_COMPRESSION_LEVEL)?),
+    }
+}
+
+pub fn decompress(data: &[u8], compression: CompressionType) -> Result<Vec<u8>> {
+    match compression {
+        CompressionType::None => Ok(data.to_vec()),
+        CompressionType::Snappy => snap::raw::Decoder::new()
+            .decompress_vec(data)
+            .map_err(|e| Error::Compression(e.to_string())),
+        CompressionType::Lz4 => {
+            lz4_flex::decompress_size_prepended(data).map_err(|e| Error::Compression(e.to_string()))
+        }
+        CompressionType::Zstd => Ok(zstd::decode_all(data)?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compression() {
+        let data: Vec<u8> = (0..10000).map(|i| (i % 7) as u8).collect();
+        for compression in [
+            CompressionType::None,
+            CompressionType::Snappy,
+            CompressionType::Lz4,
+            CompressionType::Zstd,
+        ] {
+            let compressed = compress(&data, compression).unwrap();
+            if compression != CompressionType::None {
+                assert!(compressed.len() < data.len());
+                assert!(decompress(&compressed[..compressed.len() / 2], compression).is_err());
+            }
+            assert_eq!(decompress(&compressed, compression).unwrap(), data);
+        }
+    }
+}
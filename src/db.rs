@@ -1,46 +1,141 @@
 mod opt;
+#[cfg(test)]
+mod tests;
 
-use super::memtable::{MemTable, MemTables};
-use super::Result;
+use super::memtable::{MemTable, MemTables, SkiplistIterator};
+use super::{Error, Result};
+use crate::closer::{Closer, Notifier};
+use crate::discard::DiscardStats;
 use crate::entry::Entry;
-use crate::value::{Request, Value};
+use crate::format::{self, get_ts};
+use crate::iterator::{Item, IteratorOptions};
+use crate::key_registry::KeyRegistry;
+use crate::levels::{LevelInfo, LevelsController};
+use crate::manifest::ManifestFile;
+use crate::metrics::{Metrics, WriteStallReason};
+use crate::ops::oracle::Oracle;
+use crate::ops::transaction::AGATE_PREFIX;
+use crate::table::{self, Table, TableIterators};
+use crate::util::{is_deleted_or_expired, make_comparator, Comparator};
+use crate::value::{self, Request, Value, ValuePointer};
+use crate::value_log::ValueLog;
+use crate::wal::Wal;
+use crate::{TableBuilder, TableOptions};
 
-pub use opt::AgateOptions;
+pub use opt::{build_table_options, AgateOptions};
 
+use bytes::{Bytes, BytesMut};
+use crossbeam_channel::{select, Receiver, Sender, TryRecvError};
+use skiplist::Skiplist;
+use std::collections::VecDeque;
 use std::fs;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::time::{Duration, Instant};
 
 pub struct Core {
-    mt: Mutex<MemTables>,
-    opts: AgateOptions,
-    next_mem_fid: usize,
+    mt: RwLock<MemTables>,
+    pub(crate) opts: AgateOptions,
+    next_mem_fid: AtomicUsize,
+    pub(crate) vlog: Option<ValueLog>,
+    lvctl: LevelsController,
+    /// `write_lock` serializes writers, as value log and memtable WAL
+    /// could only be appended by one routine at a time.
+    write_lock: Mutex<()>,
+    /// Notifies flush worker that there are immutable memtables to flush.
+    flush_tx: Sender<()>,
+    flush_rx: Receiver<()>,
+    /// Error of the last failed flush, which is returned to writers waiting
+    /// for the flush. Cleared once a flush succeeds.
+    flush_error: Mutex<Option<String>>,
+    /// Becomes ready once the database is closed, which stops operations
+    /// waiting for background workers.
+    closed: Receiver<()>,
+    /// Wakes up writers once flush or compaction makes room for writes, or
+    /// the database is closed.
+    notifier: Arc<Notifier>,
+    metrics: Arc<Metrics>,
+    pub(crate) orc: Arc<Oracle>,
 }
 
 #[derive(Clone)]
 pub struct Agate {
-    core: Arc<Core>,
+    pub(crate) core: Arc<Core>,
+    closer: Arc<Closer>,
 }
 
 const MEMTABLE_FILE_EXT: &str = ".mem";
 
 impl Agate {
-    /*
-    pub fn get_with_ts(&self, key: &[u8], ts: u64) -> Result<Option<Bytes>> {
-        let key = format::key_with_ts(key, ts);
-        let view = self.core.memtable.view();
-        if let Some(value) = view.get(&key) {
-            return Ok(Some(value.clone()));
+    /// Get the latest version of `key` at or below `read_ts`. Returns
+    /// `Error::KeyNotFound` if key doesn't exist or has been deleted.
+    /// Internal keys are invisible, as in iterators.
+    pub(crate) fn get_with_ts(&self, key: &[u8], read_ts: u64) -> Result<Item> {
+        if key.starts_with(AGATE_PREFIX) {
+            return Err(Error::KeyNotFound);
         }
-        unimplemented!()
+        let seek = format::key_with_ts(key, read_ts);
+        let value = match self.core.get(&seek)? {
+            Some(value) => value,
+            None => return Err(Error::KeyNotFound),
+        };
+        if is_deleted_or_expired(value.meta, value.expires_at) {
+            return Err(Error::KeyNotFound);
+        }
+        Ok(Item::new(
+            Bytes::copy_from_slice(key),
+            value,
+            self.core.clone(),
+        ))
     }
-    */
 }
 
 impl Core {
-    fn new(_opts: AgateOptions) -> Result<Self> {
-        unimplemented!()
+    fn new(opts: AgateOptions, manifest: ManifestFile, closed: Receiver<()>) -> Result<Self> {
+        let orc = Arc::new(Oracle::new(&opts));
+        let metrics = Arc::new(Metrics::new(
+            opts.block_cache.clone(),
+            opts.index_cache.clone(),
+        ));
+        // Tables are checked against manifest first, so that nothing is
+        // written if they don't match.
+        let notifier = Arc::new(Notifier::default());
+        let lvctl = LevelsController::new(
+            opts.clone(),
+            Arc::new(manifest),
+            orc.clone(),
+            metrics.clone(),
+            notifier.clone(),
+        )?;
+        let (immutables, next_mem_fid) = Self::open_mem_tables(&opts)?;
+        let mutable = Self::open_mem_table(&opts.dir, opts.clone(), next_mem_fid)?;
+        let vlog = ValueLog::new(opts.clone())?;
+        let (flush_tx, flush_rx) = crossbeam_channel::bounded(1);
+        if !immutables.is_empty() {
+            // Memtables recovered from WAL should be flushed as soon as
+            // flush worker starts.
+            flush_tx.send(()).unwrap();
+        }
+
+        let mt = MemTables::new(mutable, immutables);
+        orc.init(mt.max_version().max(lvctl.max_version()));
+
+        Ok(Self {
+            mt: RwLock::new(mt),
+            opts,
+            next_mem_fid: AtomicUsize::new(next_mem_fid + 1),
+            vlog,
+            lvctl,
+            write_lock: Mutex::new(()),
+            flush_tx,
+            flush_rx,
+            flush_error: Mutex::new(None),
+            closed,
+            notifier,
+            metrics,
+            orc,
+        })
     }
 
     fn memtable_file_path(base_path: &Path, file_id: usize) -> PathBuf {
@@ -50,28 +145,153 @@ impl Core {
     }
 
     fn open_mem_table<P: AsRef<Path>>(
-        _base_path: P,
-        _opts: AgateOptions,
-        _file_id: usize,
+        base_path: P,
+        opts: AgateOptions,
+        file_id: usize,
     ) -> Result<MemTable> {
-        unimplemented!()
+        let skl = Skiplist::with_capacity(make_comparator(), opts.arena_size() as u32);
+        if opts.in_memory {
+            return Ok(MemTable::new(skl, None, opts));
+        }
+
+        let path = Self::memtable_file_path(base_path.as_ref(), file_id);
+        // WAL of memtable shares the same format with value log,
+        // only the preallocated size is different.
+        let mut wal_opts = opts.clone();
+        wal_opts.value_log_file_size = opts.mem_table_size;
+        let wal = Wal::open(path, wal_opts)?;
+        Ok(MemTable::new(skl, Some(wal), opts))
     }
 
-    fn open_mem_tables(&mut self) -> Result<()> {
-        unimplemented!()
+    /// Replay all memtable WALs left in `opts.dir`.
+    ///
+    /// Returns recovered memtables ordered from the oldest to the newest,
+    /// which should all be treated as immutable, together with the next
+    /// memtable file ID.
+    fn open_mem_tables(opts: &AgateOptions) -> Result<(VecDeque<MemTable>, usize)> {
+        let mut immutables = VecDeque::new();
+        if opts.in_memory {
+            return Ok((immutables, 0));
+        }
+
+        let mut fids = vec![];
+        for file in fs::read_dir(&opts.dir)? {
+            let filename = file?.file_name().into_string().map_err(|filename| {
+                Error::InvalidFilename(format!("Unrecognized filename {:?}", filename))
+            })?;
+            if let Some(fid) = filename.strip_suffix(MEMTABLE_FILE_EXT) {
+                let fid = fid.parse().map_err(|err| {
+                    Error::InvalidFilename(format!("failed to parse memtable file ID {:?}", err))
+                })?;
+                fids.push(fid);
+            }
+        }
+        fids.sort_unstable();
+
+        for fid in fids.iter() {
+            let memtable = Self::open_mem_table(&opts.dir, opts.clone(), *fid)?;
+            memtable.update_skip_list()?;
+            if memtable.skl.is_empty() {
+                memtable.delete()?;
+                continue;
+            }
+            immutables.push_back(memtable);
+        }
+
+        let next_mem_fid = fids.last().map_or(0, |fid| fid + 1);
+        Ok((immutables, next_mem_fid))
     }
 
-    fn new_mem_table(&mut self) -> Result<MemTable> {
-        unimplemented!()
+    fn new_mem_table(&self) -> Result<MemTable> {
+        let file_id = self.next_mem_fid.fetch_add(1, Ordering::SeqCst);
+        Self::open_mem_table(&self.opts.dir, self.opts.clone(), file_id)
     }
 
     pub fn is_closed(&self) -> bool {
-        // TODO: check db closed
-        false
+        // Sender of `closed` is dropped once the database is closed.
+        matches!(self.closed.try_recv(), Err(TryRecvError::Disconnected))
     }
 
-    pub(crate) fn get(&self, _key: &[u8]) -> Result<Value> {
-        unimplemented!()
+    /// Get the value of `key` from LSM tree. `key` should contain timestamp.
+    ///
+    /// Memtables are searched from the newest to the oldest, and then
+    /// all levels. The value with the highest version that is less than or
+    /// equal to the timestamp of `key` is returned. Value pointers are not
+    /// resolved.
+    pub(crate) fn get(&self, key: &[u8]) -> Result<Option<Value>> {
+        if self.is_closed() {
+            return Err(Error::DBClosed);
+        }
+
+        let view = self.mt.read().unwrap().view();
+        let version = get_ts(key);
+        let mut max_value: Option<Value> = None;
+
+        for table in view.tables() {
+            if let Some((found_key, found_value)) = table.get_with_key(key) {
+                let mut value = Value::default();
+                value.decode(found_value);
+                value.version = get_ts(found_key);
+
+                if value.version == version {
+                    return Ok(Some(value));
+                }
+                if max_value.as_ref().map(|v| v.version) < Some(value.version) {
+                    max_value = Some(value);
+                }
+            }
+        }
+
+        self.lvctl.get(&Bytes::copy_from_slice(key), max_value)
+    }
+
+    /// Returns `true` if exactly `key` (with timestamp) is in the mutable
+    /// memtable, where it can't be overwritten by another put.
+    pub(crate) fn in_mutable_memtable(&self, key: &[u8]) -> bool {
+        let mt = self.mt.read().unwrap();
+        matches!(mt.table_mut().skl.get_with_key(key), Some((found, _)) if found == key)
+    }
+
+    /// Sync WAL of the mutable memtable and the current value log.
+    pub(crate) fn sync_writes(&self) -> Result<()> {
+        let _guard = self.write_lock.lock().unwrap();
+        self.mt.read().unwrap().table_mut().sync_wal()?;
+        if let Some(vlog) = &self.vlog {
+            vlog.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Build iterators of all memtables and levels, newer data first.
+    pub(crate) fn new_table_iterators(&self, opts: &IteratorOptions) -> Vec<TableIterators> {
+        let view = self.mt.read().unwrap().view();
+        let mut iters: Vec<TableIterators> = view
+            .tables()
+            .iter()
+            .map(|skl| TableIterators::from(SkiplistIterator::new(skl, opts.reverse)))
+            .collect();
+        self.lvctl.append_iterators(&mut iters, opts);
+        iters
+    }
+
+    /// If `value` is a value pointer, read actual value from value log.
+    pub(crate) fn read_value(&self, mut value: Value) -> Result<Value> {
+        if value.meta & value::VALUE_POINTER == 0 {
+            return Ok(value);
+        }
+
+        let mut vptr = ValuePointer::default();
+        vptr.decode(&value.value)?;
+        let vlog = self
+            .vlog
+            .as_ref()
+            .ok_or(Error::VlogNotFound(vptr.file_id))?;
+        let mut buf = vlog.read(vptr)?;
+        let entry = Wal::decode_entry(&mut buf)?;
+
+        value.value = entry.value;
+        value.meta &= !value::VALUE_POINTER;
+        Ok(value)
     }
 
     /// `write_to_lsm` will only be called in write thread (or write coroutine).
@@ -80,33 +300,464 @@ impl Core {
     /// 1. read lock of memtable list (only block flush)
     /// 2. write lock of mutable memtable WAL (won't block mut-table read).
     /// 3. level controller lock (TBD)
-    pub(crate) fn write_to_lsm(&self, _request: Request) -> Result<()> {
-        unimplemented!()
+    pub(crate) fn write_to_lsm(&self, request: Request) -> Result<()> {
+        assert_eq!(request.entries.len(), request.ptrs.len());
+        let mt = self.mt.read().unwrap();
+        let memtable = mt.table_mut();
+
+        for (entry, ptr) in request.entries.into_iter().zip(request.ptrs.iter()) {
+            if self.vlog.is_none() || self.opts.skip_vlog(&entry) {
+                // Small values are stored inline in LSM tree.
+                memtable.put(
+                    entry.key,
+                    Value {
+                        meta: entry.meta & !value::VALUE_POINTER,
+                        user_meta: entry.user_meta,
+                        expires_at: entry.expires_at,
+                        value: entry.value,
+                        version: 0,
+                    },
+                )?;
+            } else {
+                // Large values have been written to value log, and we only
+                // store the value pointer in LSM tree.
+                let mut vptr_buf = BytesMut::new();
+                ptr.encode(&mut vptr_buf);
+                memtable.put(
+                    entry.key,
+                    Value {
+                        meta: entry.meta | value::VALUE_POINTER,
+                        user_meta: entry.user_meta,
+                        expires_at: entry.expires_at,
+                        value: vptr_buf.freeze(),
+                        version: 0,
+                    },
+                )?;
+            }
+        }
+        // Small entries of transactions are written to value log but stored
+        // inline, which also moves the head.
+        if let Some(ptr) = request.ptrs.iter().rev().find(|ptr| ptr.file_id != 0) {
+            memtable.update_vlog_head(ptr);
+        }
+
+        if self.opts.sync_writes {
+            memtable.sync_wal()?;
+        }
+        Ok(())
+    }
+
+    /// Make sure mutable memtable has room for a new request.
+    ///
+    /// If mutable memtable is full, it will be replaced by a new one and
+    /// scheduled for flush. Returns `false` if there are already too many
+    /// memtables, and caller should wait for flush to make room.
+    fn ensure_room_for_write(&self) -> Result<bool> {
+        if !self.mt.read().unwrap().table_mut().is_full() {
+            return Ok(true);
+        }
+
+        let mut mt = self.mt.write().unwrap();
+        if mt.num_immutable() + 1 >= self.opts.num_memtables {
+            // Writers would wait forever if flush keeps failing.
+            if let Some(err) = self.flush_error.lock().unwrap().clone() {
+                return Err(Error::FlushError(err));
+            }
+            let _ = self.flush_tx.try_send(());
+            return Ok(false);
+        }
+
+        let memtable = self.new_mem_table()?;
+        mt.use_new_table(memtable);
+        // The channel may be full, which means flush worker has already
+        // been notified.
+        let _ = self.flush_tx.try_send(());
+        Ok(true)
+    }
+
+    /// Returns `true` if writers should wait for level 0 to be compacted.
+    fn level_zero_full(&self) -> bool {
+        self.opts.level_zero_stalls()
+            && self.lvctl.num_level_zero_tables() >= self.opts.num_level_zero_tables_stall
+    }
+
+    /// Slow down or block writers when there are too many level 0 tables,
+    /// so that compaction could catch up and read amplification won't
+    /// grow unbounded. It's called without `write_lock`, so that a stalled
+    /// writer doesn't block others.
+    ///
+    /// Returns an error if the database is closed or compaction keeps
+    /// failing while writers are blocked.
+    fn throttle_write(&self) -> Result<()> {
+        if !self.opts.level_zero_stalls() {
+            return Ok(());
+        }
+        if self.level_zero_full() {
+            let start = Instant::now();
+            self.notifier.wait_until(|| {
+                if self.is_closed() {
+                    return Some(Err(Error::DBClosed));
+                }
+                if !self.level_zero_full() {
+                    return Some(Ok(()));
+                }
+                // Writers would wait forever if compaction keeps failing.
+                self.lvctl
+                    .compaction_error()
+                    .map(|err| Err(Error::CompactionError(err)))
+            })?;
+            self.metrics
+                .record_write_stall(WriteStallReason::Level0Stop, start.elapsed());
+        } else if self.lvctl.num_level_zero_tables() >= self.opts.num_level_zero_tables {
+            let start = Instant::now();
+            std::thread::sleep(Duration::from_millis(1));
+            self.metrics
+                .record_write_stall(WriteStallReason::Level0SlowDown, start.elapsed());
+        }
+        Ok(())
+    }
+
+    /// Block until flush makes room for a new memtable. Returns an error if
+    /// the database is closed or flush keeps failing.
+    fn wait_for_flush(&self) -> Result<()> {
+        let start = Instant::now();
+        self.notifier.wait_until(|| {
+            if self.is_closed() {
+                return Some(Err(Error::DBClosed));
+            }
+            if self.mt.read().unwrap().num_immutable() + 1 < self.opts.num_memtables {
+                return Some(Ok(()));
+            }
+            self.flush_error
+                .lock()
+                .unwrap()
+                .clone()
+                .map(|err| Err(Error::FlushError(err)))
+        })?;
+        self.metrics
+            .record_write_stall(WriteStallReason::MemtableLimit, start.elapsed());
+        Ok(())
+    }
+
+    /// Wait until there is room for a write, and then take `write_lock`.
+    ///
+    /// Writers wait for flush and compaction without `write_lock`, and check
+    /// again after taking it, as other writers may have used up the room.
+    fn lock_for_write(&self) -> Result<MutexGuard<'_, ()>> {
+        loop {
+            self.throttle_write()?;
+            let guard = self.write_lock.lock().unwrap();
+            if self.is_closed() {
+                return Err(Error::DBClosed);
+            }
+            if self.level_zero_full() {
+                continue;
+            }
+            if self.ensure_room_for_write()? {
+                return Ok(guard);
+            }
+            drop(guard);
+            self.wait_for_flush()?;
+        }
+    }
+
+    fn check_request_size(&self, request: &Request) -> Result<()> {
+        let count = request.entries.len() as u64;
+        let size: u64 = request
+            .entries
+            .iter()
+            .map(|entry| entry.estimate_size(self.opts.value_threshold) as u64)
+            .sum();
+        if count > self.opts.max_batch_count || size > self.opts.max_batch_size {
+            return Err(Error::TxnTooBig);
+        }
+        Ok(())
+    }
+
+    /// Write requests to value log and then to LSM tree.
+    ///
+    /// Writers are serialized by `write_lock`, which is taken once there is
+    /// room for the request. After a request is persisted, its `done`
+    /// channel (if any) will be notified.
+    pub(crate) fn write_requests(&self, requests: Vec<Request>) -> Result<()> {
+        for req in requests.iter() {
+            self.check_request_size(req)?;
+        }
+
+        for mut req in requests {
+            let done = req.done.take();
+            let _guard = self.lock_for_write()?;
+            match &self.vlog {
+                Some(vlog) => vlog.write(std::slice::from_mut(&mut req))?,
+                None => req.ptrs = vec![Default::default(); req.entries.len()],
+            }
+            self.write_to_lsm(req)?;
+            if let Some(done) = done {
+                // The receiver may have been dropped if caller doesn't
+                // care about the result.
+                let _ = done.send(Ok(()));
+            }
+        }
+        Ok(())
     }
+
+    /// Flush the oldest immutable memtable to a level 0 table, and then
+    /// remove it together with its WAL.
+    pub(crate) fn flush_memtable(&self) -> Result<()> {
+        let (skl, vlog_head) = match self.mt.read().unwrap().table_flush() {
+            Some(memtable) => (memtable.skl.clone(), memtable.vlog_head()),
+            None => return Ok(()),
+        };
+
+        if !skl.is_empty() {
+            let mut table_opts = build_table_options(&self.opts);
+            table_opts.compression = self.opts.compression_of_level(0);
+            table_opts.data_key = self.opts.latest_data_key()?;
+            let data = build_l0_table(&skl, table_opts.clone())?.finish()?;
+            let file_id = self.lvctl.reserve_file_id();
+
+            let table = if self.opts.in_memory {
+                Table::open_in_memory(data, file_id, table_opts)?
+            } else {
+                Table::create(
+                    &table::new_filename(file_id, &self.opts.dir),
+                    data,
+                    table_opts,
+                )?
+            };
+            self.lvctl.add_l0_table(table, &self.closed)?;
+        }
+        // Entries before the head don't need to be replayed once the table
+        // is persisted.
+        if let Some(vlog) = &self.vlog {
+            if vlog_head.file_id != 0 {
+                vlog.update_head(&vlog_head)?;
+            }
+        }
+
+        // Only flush worker removes immutable memtables, so the front one
+        // must be the memtable just flushed.
+        let memtable = self.mt.write().unwrap().pop_flushed().unwrap();
+        self.notifier.notify();
+        memtable.delete()
+    }
+
+    /// Replay value log entries written after the head recorded by the last
+    /// flush, which are lost if memtable WALs are lost or torn.
+    ///
+    /// Only entries in value log could be recovered, i.e. values not smaller
+    /// than `value_threshold` and transaction markers. Entries already in
+    /// LSM tree are skipped, and entries of a transaction are replayed only
+    /// if its marker is found.
+    fn replay_value_log(&self) -> Result<()> {
+        let vlog = match &self.vlog {
+            Some(vlog) => vlog,
+            None => return Ok(()),
+        };
+        let head = match vlog.head() {
+            Some(head) => head,
+            // Tables flushed without head may have dropped entries in value
+            // log by compaction, which shouldn't be replayed.
+            None if !self.lvctl.manifest().manifest().tables.is_empty() => return Ok(()),
+            None => {
+                // Record head of the new database, so that tables flushed
+                // later won't be treated as above.
+                let head = ValuePointer::default();
+                vlog.update_head(&head)?;
+                head
+            }
+        };
+
+        let mut max_version = 0;
+        // Entries of the transaction being replayed.
+        let mut txn: Vec<(Entry, ValuePointer)> = vec![];
+        vlog.replay(&head, |entry, vptr| {
+            let version = get_ts(&entry.key);
+            let is_txn = entry.meta & (value::VALUE_TXN | value::VALUE_FIN_TXN) != 0;
+            // A transaction is always written to one value log. Versions of
+            // torn transactions may be reused after restart, but only in
+            // newer value logs.
+            if !is_txn
+                || txn
+                    .first()
+                    .is_some_and(|(e, p)| get_ts(&e.key) != version || p.file_id != vptr.file_id)
+            {
+                // The last transaction is torn.
+                txn.clear();
+            }
+            let is_fin = entry.meta & value::VALUE_FIN_TXN != 0;
+            txn.push((entry, vptr));
+            if is_txn && !is_fin {
+                return Ok(());
+            }
+
+            let mut request = Request {
+                entries: vec![],
+                ptrs: vec![],
+                done: None,
+            };
+            for (entry, vptr) in txn.drain(..) {
+                match self.get(&entry.key)? {
+                    Some(value) if value.version == version => continue,
+                    _ => {}
+                }
+                request.entries.push(entry);
+                request.ptrs.push(vptr);
+            }
+            if request.entries.is_empty() {
+                return Ok(());
+            }
+            max_version = max_version.max(version);
+            self.ensure_room_for_replay()?;
+            self.write_to_lsm(request)
+        })?;
+
+        if max_version >= self.orc.next_ts() {
+            self.orc.init(max_version);
+        }
+        Ok(())
+    }
+
+    /// Make sure mutable memtable has room for replayed entries. Replay runs
+    /// before background workers are started, so memtables are flushed, and
+    /// level 0 is compacted if needed, synchronously.
+    fn ensure_room_for_replay(&self) -> Result<()> {
+        while !self.ensure_room_for_write()? {
+            while self.opts.level_zero_stalls()
+                && self.lvctl.num_level_zero_tables() >= self.opts.num_level_zero_tables_stall
+            {
+                if !self.lvctl.run_compactor_once(0) {
+                    return Err(Error::CompactionError(
+                        "failed to compact level 0 while replaying value log".to_string(),
+                    ));
+                }
+            }
+            self.flush_memtable()?;
+        }
+        Ok(())
+    }
+
+    /// Flush all immutable memtables. Stops on first error, and the failed
+    /// memtable will be retried next time.
+    fn flush_immutables(&self) -> Result<()> {
+        while self.mt.read().unwrap().num_immutable() > 0 {
+            self.flush_memtable()?;
+        }
+        Ok(())
+    }
+}
+
+/// Build a level 0 table from all entries in a memtable.
+fn build_l0_table(skl: &Skiplist<Comparator>, opts: TableOptions) -> Result<TableBuilder> {
+    let mut builder = TableBuilder::new(opts);
+    let mut iter = skl.iter_ref();
+    iter.seek_to_first();
+    while iter.valid() {
+        let mut value = Value::default();
+        value.decode(iter.value());
+        let vlog_len = if value.meta & value::VALUE_POINTER != 0 {
+            let mut vptr = ValuePointer::default();
+            vptr.decode(&value.value)?;
+            vptr.len
+        } else {
+            0
+        };
+        builder.add(iter.key(), value, vlog_len)?;
+        iter.next();
+    }
+    Ok(builder)
+}
+
+/// Background worker flushing immutable memtables. It is woken up by
+/// writers, and also retries periodically in case a flush has failed.
+///
+/// Writers waiting for flush or compaction are woken up once the database
+/// is closed.
+fn flush_worker(core: Arc<Core>, closed: Receiver<()>) {
+    let flush_rx = core.flush_rx.clone();
+    loop {
+        select! {
+            recv(flush_rx) -> _ => {},
+            recv(closed) -> _ => break,
+            default(Duration::from_secs(1)) => {},
+        }
+        // The failed memtable is kept and will be flushed again on next
+        // round. Until then, the error is reported to stalled writers.
+        match core.flush_immutables() {
+            Ok(()) => *core.flush_error.lock().unwrap() = None,
+            Err(Error::DBClosed) => break,
+            Err(e) => {
+                core.metrics.record_flush_error(&e);
+                *core.flush_error.lock().unwrap() = Some(e.to_string());
+                core.notifier.notify();
+            }
+        }
+    }
+    core.notifier.notify();
 }
 
 impl Agate {
+    /// Get the value of `key`, which should contain timestamp.
+    ///
+    /// Returns `Error::KeyNotFound` if there is no version of `key` visible
+    /// at its timestamp. Deleted values are returned as-is with `VALUE_DELETE` set.
     pub fn get(&self, key: &[u8]) -> Result<Value> {
-        self.core.get(key)
+        match self.core.get(key)? {
+            Some(value) => self.core.read_value(value),
+            None => Err(Error::KeyNotFound),
+        }
+    }
+
+    /// Get statistics of all levels in LSM tree, including target sizes
+    /// used by compaction.
+    pub fn levels(&self) -> Vec<LevelInfo> {
+        self.core.lvctl.level_info()
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.core.metrics
     }
 
     pub fn write_to_lsm(&self, request: Request) -> Result<()> {
-        self.core.write_to_lsm(request)
+        self.core.write_requests(vec![request])
     }
 
     pub fn open<P: AsRef<Path>>(mut opts: AgateOptions, path: P) -> Result<Self> {
         opts.fix_options()?;
 
         opts.dir = path.as_ref().to_path_buf();
+        if opts.value_dir.as_os_str().is_empty() {
+            opts.value_dir = opts.dir.clone();
+        }
 
-        if !opts.in_memory && !opts.dir.exists() {
-            fs::create_dir_all(&opts.dir)?;
-            // TODO: create wal path, acquire database path lock
+        if !opts.in_memory {
+            if !opts.dir.exists() {
+                fs::create_dir_all(&opts.dir)?;
+                // TODO: acquire database path lock
+            }
+            if !opts.value_dir.exists() {
+                fs::create_dir_all(&opts.value_dir)?;
+            }
         }
 
-        // TODO: open or create manifest
-        Ok(Agate {
-            core: Arc::new(Core::new(opts)?),
-        })
+        opts.key_registry = Some(Arc::new(KeyRegistry::open(&opts)?));
+        opts.discard_stats = Some(Arc::new(DiscardStats::open(&opts)?));
+        let manifest = ManifestFile::open_or_create_manifest_file(&opts)?;
+        let closer = Arc::new(Closer::new());
+        let core = Arc::new(Core::new(opts, manifest, closer.closed())?);
+        core.replay_value_log()?;
+
+        let flush_core = core.clone();
+        closer.spawn("agate-flush", move |closed| {
+            flush_worker(flush_core, closed)
+        });
+        for id in 0..core.opts.num_compactors {
+            let compact_core = core.clone();
+            closer.spawn(&format!("agate-compactor-{}", id), move |closed| {
+                compact_core.lvctl.run_compactor(id, closed)
+            });
+        }
+
+        Ok(Agate { core, closer })
     }
 }
@@ -1,4 +1,15 @@
 use super::*;
+use crate::cache::{BlockCache, IndexCache};
+use crate::discard::DiscardStats;
+use crate::encryption;
+use crate::key_registry::KeyRegistry;
+use crate::memtable::MEMTABLE_VIEW_MAX;
+use crate::opt::{
+    ChecksumVerificationMode, CompressionType, Options as TableOptions, PrefixExtractor,
+};
+use crate::Error;
+use proto::meta::DataKey;
+use skiplist::MAX_NODE_SIZE;
 
 #[derive(Clone)]
 pub struct AgateOptions {
@@ -21,12 +32,61 @@ pub struct AgateOptions {
 
     pub block_size: usize,
     pub bloom_false_positive: f64,
+    pub checksum_mode: ChecksumVerificationMode,
+    /// Prefixes extracted from keys are added to bloom filters of SSTs, so
+    /// that iterations over a prefix could skip SSTs.
+    pub prefix_extractor: Option<PrefixExtractor>,
+    /// Compression of SST data blocks.
+    pub compression: CompressionType,
+    /// Compression of each level, which overrides `compression` if not
+    /// empty. Levels beyond the list use the last element, e.g.
+    /// `[None, None, Zstd]` compresses level 2 and below with zstd.
+    pub compression_per_level: Vec<CompressionType>,
+    /// Capacity of block cache in bytes. Set to 0 to disable block cache.
+    pub block_cache_size: u64,
+    /// Capacity of index cache in bytes, which holds indices and bloom
+    /// filters of tables. Set to 0 to keep indices of all tables in memory.
+    pub index_cache_size: u64,
+    /// Master key to encrypt data keys with, which should be 16, 24 or 32
+    /// bytes long to use AES-128, AES-192 or AES-256. SSTs, WALs and value
+    /// logs are encrypted with data keys. Set to empty to disable
+    /// encryption.
+    pub encryption_key: Vec<u8>,
+    /// A new data key is generated once the current one is older than this.
+    pub encryption_key_rotation_duration: Duration,
 
     pub num_level_zero_tables: usize,
     pub num_level_zero_tables_stall: usize,
+    /// Number of compaction threads. Set to 0 to disable compaction, in
+    /// which case `num_level_zero_tables` and `num_level_zero_tables_stall`
+    /// are ignored, and writes are never slowed down or stalled by level 0.
+    pub num_compactors: usize,
+    /// Detect conflicts between transactions at commit time. Disable it if
+    /// transactions never conflict, to save memory of tracking conflict keys.
+    pub detect_conflicts: bool,
+    /// Number of versions of each key to keep at or below the discard
+    /// timestamp during compaction.
+    pub num_versions_to_keep: usize,
 
     pub value_log_file_size: u64,
     pub value_log_max_entries: u32,
+    /// Remove SSTs in `dir` which are not recorded in manifest on open,
+    /// instead of failing. SSTs left by a crash during flush or compaction
+    /// are always removed, so `dir` should be checked before enabling this.
+    pub remove_unknown_tables: bool,
+
+    /// Max size of a single write request in bytes, computed from `mem_table_size`.
+    pub(crate) max_batch_size: u64,
+    /// Max number of entries in a single write request, computed from `mem_table_size`.
+    pub(crate) max_batch_count: u64,
+    /// Block cache shared by all tables, created from `block_cache_size`.
+    pub(crate) block_cache: Option<Arc<BlockCache>>,
+    /// Index cache shared by all tables, created from `index_cache_size`.
+    pub(crate) index_cache: Option<Arc<IndexCache>>,
+    /// Data keys of the instance, opened from `encryption_key`.
+    pub(crate) key_registry: Option<Arc<KeyRegistry>>,
+    /// Discarded bytes of value logs, updated by compaction.
+    pub(crate) discard_stats: Option<Arc<DiscardStats>>,
 }
 
 impl Default for AgateOptions {
@@ -48,10 +108,28 @@ impl Default for AgateOptions {
             value_threshold: 1 << 10,
             value_log_file_size: 1 << (30 - 1),
             value_log_max_entries: 1000000,
+            remove_unknown_tables: false,
             block_size: 4 << 10,
             bloom_false_positive: 0.01,
+            checksum_mode: ChecksumVerificationMode::NoVerification,
+            prefix_extractor: None,
+            compression: CompressionType::None,
+            compression_per_level: vec![],
+            block_cache_size: 256 << 20,
+            index_cache_size: 0,
+            encryption_key: vec![],
+            encryption_key_rotation_duration: Duration::from_secs(10 * 24 * 60 * 60),
             num_level_zero_tables: 5,
             num_level_zero_tables_stall: 15,
+            num_compactors: 4,
+            detect_conflicts: true,
+            num_versions_to_keep: 1,
+            max_batch_size: 0,
+            max_batch_count: 0,
+            block_cache: None,
+            index_cache: None,
+            key_registry: None,
+            discard_stats: None,
         }
         // TODO: add other options
     }
@@ -64,15 +142,116 @@ impl AgateOptions {
             self.sync_writes = false;
         }
 
+        // There should be at least one mutable and one immutable memtable,
+        // so that writes can continue while a memtable is being flushed.
+        if self.num_memtables < 2 || self.num_memtables > MEMTABLE_VIEW_MAX {
+            return Err(Error::Config(format!(
+                "num_memtables should be in range [2, {}]",
+                MEMTABLE_VIEW_MAX
+            )));
+        }
+
+        if self.num_level_zero_tables == 0
+            || self.num_level_zero_tables > self.num_level_zero_tables_stall
+        {
+            return Err(Error::Config(
+                "num_level_zero_tables should be in range [1, num_level_zero_tables_stall]"
+                    .to_string(),
+            ));
+        }
+
+        if self.num_versions_to_keep == 0 {
+            return Err(Error::Config(
+                "num_versions_to_keep should be at least 1".to_string(),
+            ));
+        }
+
+        if self.prefix_extractor == Some(PrefixExtractor::FixedLength(0)) {
+            return Err(Error::Config(
+                "prefix length of prefix_extractor should be at least 1".to_string(),
+            ));
+        }
+
+        if !self.encryption_key.is_empty() && !encryption::is_valid_key(&self.encryption_key) {
+            return Err(Error::Config(
+                "encryption_key should be 16, 24 or 32 bytes".to_string(),
+            ));
+        }
+
+        if self.max_levels < 2 {
+            return Err(Error::Config("max_levels should be at least 2".to_string()));
+        }
+
+        self.max_batch_size = (15 * self.mem_table_size) / 100;
+        self.max_batch_count = self.max_batch_size / MAX_NODE_SIZE as u64;
+        self.block_cache = if self.block_cache_size > 0 {
+            Some(Arc::new(BlockCache::new(self.block_cache_size)))
+        } else {
+            None
+        };
+        self.index_cache = if self.index_cache_size > 0 {
+            Some(Arc::new(IndexCache::new(self.index_cache_size)))
+        } else {
+            None
+        };
+
         Ok(())
     }
 
+    /// Compression of tables in `level`.
+    pub fn compression_of_level(&self, level: usize) -> CompressionType {
+        match self.compression_per_level.last() {
+            Some(last) => *self.compression_per_level.get(level).unwrap_or(last),
+            None => self.compression,
+        }
+    }
+
+    /// Get data key of `key_id`. Returns `None` if `key_id` is 0.
+    pub(crate) fn data_key(&self, key_id: u64) -> Result<Option<DataKey>> {
+        match &self.key_registry {
+            Some(registry) => registry.data_key(key_id),
+            None if key_id == 0 => Ok(None),
+            None => Err(Error::InvalidDataKeyId(key_id)),
+        }
+    }
+
+    /// Get data key to encrypt new files with. Returns `None` if encryption
+    /// is disabled.
+    pub(crate) fn latest_data_key(&self) -> Result<Option<DataKey>> {
+        match &self.key_registry {
+            Some(registry) => registry.latest_data_key(),
+            None => Ok(None),
+        }
+    }
+
+    /// Whether flushes and writes wait for level 0 to be compacted. Level 0
+    /// is never compacted without compactors, so waiting would block forever.
+    pub(crate) fn level_zero_stalls(&self) -> bool {
+        self.num_compactors > 0
+    }
+
     pub fn skip_vlog(&self, entry: &Entry) -> bool {
         entry.value.len() < self.value_threshold
     }
 
-    fn arena_size(&self) -> u64 {
-        // TODO: take other options into account
-        self.mem_table_size as u64
+    pub(crate) fn arena_size(&self) -> u64 {
+        // Arena only stores skiplist nodes. Leave enough room for one more
+        // batch after memtable is full.
+        self.mem_table_size + self.max_batch_size + self.max_batch_count * MAX_NODE_SIZE as u64
+    }
+}
+
+/// Build table options from agate options.
+pub fn build_table_options(opts: &AgateOptions) -> TableOptions {
+    TableOptions {
+        table_size: opts.base_table_size,
+        block_size: opts.block_size,
+        bloom_false_positive: opts.bloom_false_positive,
+        checksum_mode: opts.checksum_mode.clone(),
+        prefix_extractor: opts.prefix_extractor,
+        compression: opts.compression,
+        block_cache: opts.block_cache.clone(),
+        index_cache: opts.index_cache.clone(),
+        data_key: None,
     }
 }
@@ -0,0 +1,1173 @@
+use super::*;
+use crate::format::{key_with_ts, user_key};
+use crate::iterator::{Iterator, IteratorOptions};
+use crate::iterator_trait::AgateIterator;
+use crate::ops::transaction::{AGATE_PREFIX, TXN_KEY};
+use crate::opt::CompressionType;
+use crate::value::ValuePointer;
+use crate::value_log::HEAD_FILENAME;
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+fn helper_open(opts: AgateOptions, path: &Path) -> Agate {
+    Agate::open(opts, path).unwrap()
+}
+
+fn test_request(entries: Vec<Entry>) -> Request {
+    Request {
+        entries,
+        ptrs: vec![],
+        done: None,
+    }
+}
+
+fn key(i: usize) -> Bytes {
+    Bytes::from(format!("key{:06}", i))
+}
+
+#[test]
+fn test_write_to_lsm() {
+    let tmp_dir = tempdir().unwrap();
+    let mut opts = AgateOptions::default();
+    opts.value_threshold = 32;
+    let agate = helper_open(opts, tmp_dir.path());
+
+    let small_value = Bytes::from_static(b"small");
+    let big_value = Bytes::from(vec![b'x'; 64]);
+    let entries = vec![
+        Entry::new(key_with_ts(&key(0)[..], 1), small_value.clone()),
+        Entry::new(key_with_ts(&key(1)[..], 1), big_value.clone()),
+    ];
+    let (tx, rx) = crossbeam_channel::bounded(1);
+    let mut request = test_request(entries);
+    request.done = Some(tx);
+    agate.write_to_lsm(request).unwrap();
+    rx.recv().unwrap().unwrap();
+
+    let core = &agate.core;
+    let mt = core.mt.read().unwrap();
+    let skl = &mt.table_mut().skl;
+
+    let mut v = Value::default();
+    v.decode(skl.get(&key_with_ts(&key(0)[..], 1)).unwrap());
+    assert_eq!(v.meta & value::VALUE_POINTER, 0);
+    assert_eq!(v.value, small_value);
+
+    let mut v = Value::default();
+    v.decode(skl.get(&key_with_ts(&key(1)[..], 1)).unwrap());
+    assert_ne!(v.meta & value::VALUE_POINTER, 0);
+    let mut vp = ValuePointer::default();
+    vp.decode(&v.value).unwrap();
+    let mut buf = core.vlog.as_ref().unwrap().read(vp).unwrap();
+    let entry = Wal::decode_entry(&mut buf).unwrap();
+    assert_eq!(entry.value, big_value);
+}
+
+#[test]
+fn test_write_to_lsm_in_memory() {
+    let tmp_dir = tempdir().unwrap();
+    let mut opts = AgateOptions::default();
+    opts.in_memory = true;
+    opts.value_threshold = 32;
+    let agate = helper_open(opts, &tmp_dir.path().join("agate"));
+    assert!(agate.core.vlog.is_none());
+
+    let entries = (0..100)
+        .map(|i| Entry::new(key_with_ts(&key(i)[..], 1), Bytes::from(vec![b'x'; i])))
+        .collect();
+    agate.write_to_lsm(test_request(entries)).unwrap();
+
+    let mt = agate.core.mt.read().unwrap();
+    let skl = &mt.table_mut().skl;
+    for i in 0..100 {
+        let mut v = Value::default();
+        v.decode(skl.get(&key_with_ts(&key(i)[..], 1)).unwrap());
+        assert_eq!(v.meta & value::VALUE_POINTER, 0);
+        assert_eq!(v.value.len(), i);
+    }
+    assert!(!tmp_dir.path().join("agate").exists());
+}
+
+#[test]
+fn test_get() {
+    let tmp_dir = tempdir().unwrap();
+    let mut opts = AgateOptions::default();
+    opts.value_threshold = 32;
+    let agate = helper_open(opts, tmp_dir.path());
+
+    for ts in 1..=3 {
+        let entries = (0..100)
+            .map(|i| {
+                let value = if i % 2 == 0 {
+                    Bytes::from(format!("{}-{}", i, ts))
+                } else {
+                    Bytes::from(format!("{:064}-{}", i, ts))
+                };
+                Entry::new(key_with_ts(&key(i)[..], ts), value)
+            })
+            .collect();
+        agate.write_to_lsm(test_request(entries)).unwrap();
+    }
+
+    for i in 0..100 {
+        for ts in 1..=3 {
+            let value = agate.get(&key_with_ts(&key(i)[..], ts)).unwrap();
+            assert_eq!(value.version, ts);
+            assert_eq!(value.meta & value::VALUE_POINTER, 0);
+            assert!(value.value.ends_with(format!("{}-{}", i, ts).as_bytes()));
+        }
+        // Reading with a larger timestamp returns the latest version.
+        let value = agate.get(&key_with_ts(&key(i)[..], 10)).unwrap();
+        assert_eq!(value.version, 3);
+        // No version is visible at timestamp 0.
+        assert!(matches!(
+            agate.get(&key_with_ts(&key(i)[..], 0)),
+            Err(Error::KeyNotFound)
+        ));
+    }
+
+    assert!(matches!(
+        agate.get(&key_with_ts(&key(100)[..], 10)),
+        Err(Error::KeyNotFound)
+    ));
+}
+
+#[test]
+fn test_flush_memtable() {
+    let tmp_dir = tempdir().unwrap();
+    let mut opts = AgateOptions::default();
+    opts.mem_table_size = 1 << 16;
+    opts.value_threshold = 32;
+    let agate = helper_open(opts, tmp_dir.path());
+
+    for i in 0..100 {
+        let entries = (i * 10..i * 10 + 10)
+            .map(|j| Entry::new(key_with_ts(&key(j)[..], 1), Bytes::from(vec![b'x'; 16])))
+            .collect();
+        agate.write_to_lsm(test_request(entries)).unwrap();
+    }
+
+    let core = &agate.core;
+    for _ in 0..100 {
+        if core.mt.read().unwrap().num_immutable() == 0 {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    assert_eq!(core.mt.read().unwrap().num_immutable(), 0);
+
+    let mut sst_files = 0;
+    let mut mem_files = 0;
+    for entry in fs::read_dir(tmp_dir.path()).unwrap() {
+        let name = entry.unwrap().file_name().into_string().unwrap();
+        if name.ends_with(".sst") {
+            sst_files += 1;
+        } else if name.ends_with(MEMTABLE_FILE_EXT) {
+            mem_files += 1;
+        }
+    }
+    assert!(sst_files > 0);
+    assert_eq!(mem_files, 1);
+
+    for i in 0..1000 {
+        let value = agate.get(&key_with_ts(&key(i)[..], 1)).unwrap();
+        assert_eq!(value.value, Bytes::from(vec![b'x'; 16]));
+    }
+}
+
+#[test]
+fn test_block_cache_metrics() {
+    let tmp_dir = tempdir().unwrap();
+    let agate = helper_open(AgateOptions::default(), tmp_dir.path());
+    let entries = (0..100)
+        .map(|i| Entry::new(key_with_ts(&key(i)[..], 1), Bytes::from(vec![b'x'; 16])))
+        .collect();
+    agate.write_to_lsm(test_request(entries)).unwrap();
+    let core = &agate.core;
+    let memtable = core.new_mem_table().unwrap();
+    core.mt.write().unwrap().use_new_table(memtable);
+    core.flush_immutables().unwrap();
+
+    // Scans could bypass block cache.
+    let mut iter_opts = IteratorOptions::default();
+    iter_opts.fill_cache = false;
+    let mut iter = Iterator::new(core.clone(), 1, &iter_opts, None);
+    iter.rewind();
+    let mut count = 0;
+    while iter.valid() {
+        count += 1;
+        iter.next();
+    }
+    assert_eq!(count, 100);
+    assert_eq!(agate.metrics().block_cache().unwrap().size, 0);
+
+    for _ in 0..2 {
+        for i in 0..100 {
+            agate.get(&key_with_ts(&key(i)[..], 1)).unwrap();
+        }
+    }
+    let stats = agate.metrics().block_cache().unwrap();
+    assert_eq!(stats.capacity, AgateOptions::default().block_cache_size);
+    assert!(stats.size > 0);
+    assert!(stats.misses > 0);
+    assert!(stats.hits >= 100);
+    drop(agate);
+
+    let mut opts = AgateOptions::default();
+    opts.block_cache_size = 0;
+    let agate = helper_open(opts, tmp_dir.path());
+    assert!(agate.metrics().block_cache().is_none());
+    agate.get(&key_with_ts(&key(0)[..], 1)).unwrap();
+}
+
+#[test]
+fn test_index_cache() {
+    let tmp_dir = tempdir().unwrap();
+    let mut opts = AgateOptions::default();
+    opts.index_cache_size = 1 << 20;
+    let agate = helper_open(opts, tmp_dir.path());
+    assert_eq!(agate.metrics().index_cache().unwrap().size, 0);
+    let entries = (0..100)
+        .map(|i| Entry::new(key_with_ts(&key(i)[..], 1), Bytes::from(vec![b'x'; 16])))
+        .collect();
+    agate.write_to_lsm(test_request(entries)).unwrap();
+    let core = &agate.core;
+    let memtable = core.new_mem_table().unwrap();
+    core.mt.write().unwrap().use_new_table(memtable);
+    core.flush_immutables().unwrap();
+
+    for i in 0..100 {
+        agate.get(&key_with_ts(&key(i)[..], 1)).unwrap();
+    }
+    let stats = agate.metrics().index_cache().unwrap();
+    assert_eq!(stats.capacity, 1 << 20);
+    assert!(stats.size > 0);
+    assert!(stats.hits >= 100);
+    drop(agate);
+
+    let agate = helper_open(AgateOptions::default(), tmp_dir.path());
+    assert!(agate.metrics().index_cache().is_none());
+    agate.get(&key_with_ts(&key(0)[..], 1)).unwrap();
+}
+
+#[test]
+fn test_txn_too_big() {
+    let tmp_dir = tempdir().unwrap();
+    let mut opts = AgateOptions::default();
+    opts.mem_table_size = 1 << 16;
+    let agate = helper_open(opts, tmp_dir.path());
+
+    let max_batch_count = agate.core.opts.max_batch_count as usize;
+    let entries = (0..=max_batch_count)
+        .map(|i| Entry::new(key_with_ts(&key(i)[..], 1), Bytes::new()))
+        .collect();
+    assert!(matches!(
+        agate.write_to_lsm(test_request(entries)),
+        Err(Error::TxnTooBig)
+    ));
+}
+
+#[test]
+fn test_recover_mem_tables() {
+    let tmp_dir = tempdir().unwrap();
+    let mut opts = AgateOptions::default();
+    opts.value_threshold = 32;
+
+    let agate = helper_open(opts.clone(), tmp_dir.path());
+    for i in 0..100 {
+        let entries = vec![Entry::new(
+            key_with_ts(&key(i)[..], i as u64 + 1),
+            Bytes::from(format!("{:064}", i)),
+        )];
+        agate.write_to_lsm(test_request(entries)).unwrap();
+    }
+    // Memtable is not full, so nothing is flushed before drop.
+    drop(agate);
+
+    let mem_path = Core::memtable_file_path(tmp_dir.path(), 0);
+    let mut fixed_opts = opts.clone();
+    fixed_opts.fix_options().unwrap();
+    let memtable = Core::open_mem_table(tmp_dir.path(), fixed_opts, 0).unwrap();
+    memtable.update_skip_list().unwrap();
+    assert_eq!(memtable.skl.len(), 100);
+    assert_eq!(memtable.max_version(), 100);
+    drop(memtable);
+
+    // Simulate a torn write of the last entry.
+    let data = fs::read(&mem_path).unwrap();
+    let last_key = key(99);
+    let pos = data
+        .windows(last_key.len())
+        .position(|w| w == &last_key[..])
+        .unwrap();
+    let file = fs::OpenOptions::new().write(true).open(&mem_path).unwrap();
+    file.set_len(pos as u64 + 3).unwrap();
+    drop(file);
+
+    // The last entry is replayed from value log.
+    let agate = helper_open(opts, tmp_dir.path());
+    for i in 0..100 {
+        let value = agate.get(&key_with_ts(&key(i)[..], 1000)).unwrap();
+        assert_eq!(value.version, i as u64 + 1);
+        let value = agate.core.read_value(value).unwrap();
+        assert_eq!(value.value, Bytes::from(format!("{:064}", i)));
+    }
+    // A new memtable file is used for new writes.
+    assert!(Core::memtable_file_path(tmp_dir.path(), 1).exists());
+}
+
+#[test]
+fn test_reopen_with_manifest() {
+    let tmp_dir = tempdir().unwrap();
+    let mut opts = AgateOptions::default();
+    opts.mem_table_size = 1 << 16;
+    opts.value_threshold = 32;
+
+    let agate = helper_open(opts.clone(), tmp_dir.path());
+    for i in 0..100 {
+        let entries = (i * 10..i * 10 + 10)
+            .map(|j| Entry::new(key_with_ts(&key(j)[..], 1), Bytes::from(vec![b'x'; 16])))
+            .collect();
+        agate.write_to_lsm(test_request(entries)).unwrap();
+    }
+    for _ in 0..100 {
+        if agate.core.mt.read().unwrap().num_immutable() == 0 {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    let manifest = agate.core.lvctl.manifest().manifest();
+    assert!(!manifest.tables.is_empty());
+    drop(agate);
+
+    // SST files left by a crash after table IDs in manifest are removed.
+    let table_path = table::new_filename(*manifest.tables.keys().next().unwrap(), tmp_dir.path());
+    let crash_path = table::new_filename(manifest.next_file_id + 5, tmp_dir.path());
+    fs::copy(&table_path, &crash_path).unwrap();
+    drop(helper_open(opts.clone(), tmp_dir.path()));
+    assert!(!crash_path.exists());
+
+    // Other SST files not recorded in manifest are rejected, unless they
+    // are allowed to be removed. Table IDs start from 1.
+    let unknown_path = table::new_filename(0, tmp_dir.path());
+    fs::copy(&table_path, &unknown_path).unwrap();
+    assert!(matches!(
+        Agate::open(opts.clone(), tmp_dir.path()),
+        Err(Error::InvalidManifest(_))
+    ));
+    assert!(unknown_path.exists());
+
+    opts.remove_unknown_tables = true;
+    let agate = helper_open(opts.clone(), tmp_dir.path());
+    assert!(!unknown_path.exists());
+    for i in 0..1000 {
+        let value = agate.get(&key_with_ts(&key(i)[..], 1)).unwrap();
+        assert_eq!(value.value, Bytes::from(vec![b'x'; 16]));
+    }
+    let reopened = agate.core.lvctl.manifest().manifest();
+    assert!(reopened.tables.len() >= manifest.tables.len());
+    drop(agate);
+
+    // SST files recorded in manifest must exist.
+    let missing_id = *reopened.tables.keys().next().unwrap();
+    fs::remove_file(table::new_filename(missing_id, tmp_dir.path())).unwrap();
+    assert!(matches!(
+        Agate::open(opts, tmp_dir.path()),
+        Err(Error::TableNotFound(id)) if id == missing_id
+    ));
+}
+
+#[test]
+fn test_compaction() {
+    let tmp_dir = tempdir().unwrap();
+    let mut opts = AgateOptions::default();
+    opts.mem_table_size = 1 << 15;
+    opts.base_table_size = 1 << 15;
+    opts.base_level_size = 1 << 16;
+    opts.num_level_zero_tables = 2;
+    opts.value_threshold = 32;
+
+    let agate = helper_open(opts.clone(), tmp_dir.path());
+    for round in 1..=3 {
+        for i in 0..100 {
+            let entries = (i * 10..i * 10 + 10)
+                .map(|j| {
+                    Entry::new(
+                        key_with_ts(&key(j)[..], round),
+                        Bytes::from(format!("{:016}", round)),
+                    )
+                })
+                .collect();
+            agate.write_to_lsm(test_request(entries)).unwrap();
+        }
+    }
+
+    let lvctl = &agate.core.lvctl;
+    for _ in 0..100 {
+        if lvctl.pick_compact_levels().is_empty()
+            && agate.core.mt.read().unwrap().num_immutable() == 0
+        {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    assert!(lvctl.pick_compact_levels().is_empty());
+    assert!(lvctl.levels[1..].iter().any(|l| l.read().num_tables() > 0));
+
+    let check = |agate: &Agate| {
+        for i in 0..1000 {
+            for ts in 1..=3 {
+                let value = agate.get(&key_with_ts(&key(i)[..], ts)).unwrap();
+                assert_eq!(value.version, ts);
+                assert_eq!(value.value, Bytes::from(format!("{:016}", ts)));
+            }
+        }
+    };
+    check(&agate);
+
+    // Layout after compaction is persisted in manifest.
+    let manifest = lvctl.manifest().manifest();
+    for (level, handler) in lvctl.levels.iter().enumerate() {
+        for table in handler.read().tables.iter() {
+            assert_eq!(manifest.tables[&table.id()].level as usize, level);
+        }
+    }
+    drop(agate);
+
+    let agate = helper_open(opts, tmp_dir.path());
+    check(&agate);
+}
+
+#[test]
+fn test_compression_per_level() {
+    let tmp_dir = tempdir().unwrap();
+    let mut opts = AgateOptions::default();
+    opts.mem_table_size = 1 << 15;
+    opts.base_table_size = 1 << 15;
+    opts.base_level_size = 1 << 16;
+    opts.num_level_zero_tables = 2;
+    opts.value_threshold = 32;
+    opts.compression_per_level = vec![
+        CompressionType::None,
+        CompressionType::Snappy,
+        CompressionType::Lz4,
+        CompressionType::Zstd,
+    ];
+    assert_eq!(opts.compression_of_level(6), CompressionType::Zstd);
+
+    let agate = helper_open(opts.clone(), tmp_dir.path());
+    for i in 0..300 {
+        let entries = (i * 10..i * 10 + 10)
+            .map(|j| Entry::new(key_with_ts(&key(j)[..], 1), Bytes::from(vec![b'x'; 16])))
+            .collect();
+        agate.write_to_lsm(test_request(entries)).unwrap();
+    }
+    let lvctl = &agate.core.lvctl;
+    for _ in 0..100 {
+        if lvctl.pick_compact_levels().is_empty()
+            && agate.core.mt.read().unwrap().num_immutable() == 0
+        {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+
+    // Tables are compressed as configured for their levels, which is
+    // recorded in manifest.
+    let manifest = lvctl.manifest().manifest();
+    let mut compressed = 0;
+    for (level, handler) in lvctl.levels.iter().enumerate() {
+        for table in handler.read().tables.iter() {
+            let compression = opts.compression_of_level(level);
+            assert_eq!(table.compression(), compression);
+            assert_eq!(manifest.tables[&table.id()].compression, compression as u32);
+            if compression != CompressionType::None {
+                compressed += 1;
+                assert!(u64::from(table.estimated_size()) > table.size());
+            }
+        }
+    }
+    assert!(compressed > 0);
+    drop(agate);
+
+    // Compression of existing tables doesn't depend on options.
+    let agate = helper_open(AgateOptions::default(), tmp_dir.path());
+    for i in 0..3000 {
+        let value = agate.get(&key_with_ts(&key(i)[..], 1)).unwrap();
+        assert_eq!(value.value, Bytes::from(vec![b'x'; 16]));
+    }
+}
+
+fn encryption_test_value(i: usize) -> Bytes {
+    // Values of odd keys are stored in value log.
+    if i.is_multiple_of(2) {
+        Bytes::from(format!("secret{:010}", i))
+    } else {
+        Bytes::from(format!("secret{:058}", i))
+    }
+}
+
+#[test]
+fn test_encryption() {
+    let tmp_dir = tempdir().unwrap();
+    let mut opts = AgateOptions::default();
+    opts.mem_table_size = 1 << 15;
+    opts.value_threshold = 32;
+    opts.encryption_key = b"0123456789abcdef".to_vec();
+
+    let agate = helper_open(opts.clone(), tmp_dir.path());
+    for i in 0..100 {
+        let entries = (i * 10..i * 10 + 10)
+            .map(|j| Entry::new(key_with_ts(&key(j)[..], 1), encryption_test_value(j)))
+            .collect();
+        agate.write_to_lsm(test_request(entries)).unwrap();
+    }
+    for _ in 0..100 {
+        if agate.core.mt.read().unwrap().num_immutable() == 0 {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    let manifest = agate.core.lvctl.manifest().manifest();
+    assert!(!manifest.tables.is_empty());
+    assert!(manifest.tables.values().all(|tm| tm.key_id == 1));
+    drop(agate);
+
+    // Neither SSTs nor WALs nor value logs contain plain data.
+    for file in fs::read_dir(tmp_dir.path()).unwrap() {
+        let data = fs::read(file.unwrap().path()).unwrap();
+        assert!(!data.windows(6).any(|w| w == b"secret"));
+    }
+
+    let agate = helper_open(opts.clone(), tmp_dir.path());
+    for i in 0..1000 {
+        let value = agate.get(&key_with_ts(&key(i)[..], 1)).unwrap();
+        let value = agate.core.read_value(value).unwrap();
+        assert_eq!(value.value, encryption_test_value(i));
+    }
+    drop(agate);
+
+    for key in [b"fedcba9876543210".to_vec(), vec![]] {
+        let mut opts = opts.clone();
+        opts.encryption_key = key;
+        assert!(matches!(
+            Agate::open(opts, tmp_dir.path()),
+            Err(Error::EncryptionKeyMismatch)
+        ));
+    }
+
+    let mut opts = opts;
+    opts.encryption_key = vec![0; 10];
+    assert!(matches!(
+        Agate::open(opts, tmp_dir.path()),
+        Err(Error::Config(_))
+    ));
+}
+
+#[test]
+fn test_write_stall() {
+    let tmp_dir = tempdir().unwrap();
+    let mut opts = AgateOptions::default();
+    opts.mem_table_size = 1 << 15;
+    opts.base_table_size = 1 << 15;
+    opts.base_level_size = 1 << 16;
+    opts.num_memtables = 2;
+    opts.num_level_zero_tables = 1;
+    opts.num_level_zero_tables_stall = 2;
+    opts.num_compactors = 1;
+    opts.value_threshold = 32;
+
+    let agate = helper_open(opts, tmp_dir.path());
+    for i in 0..300 {
+        let entries = (i * 10..i * 10 + 10)
+            .map(|j| Entry::new(key_with_ts(&key(j)[..], 1), Bytes::from(vec![b'x'; 16])))
+            .collect();
+        agate.write_to_lsm(test_request(entries)).unwrap();
+        // Level 0 never exceeds the stall threshold.
+        assert!(agate.core.lvctl.num_level_zero_tables() <= 2);
+    }
+
+    let metrics = agate.metrics();
+    let slowdown = metrics.write_stall(WriteStallReason::Level0SlowDown);
+    assert!(slowdown.count > 0);
+    assert!(slowdown.duration >= Duration::from_millis(slowdown.count));
+
+    for i in 0..3000 {
+        agate.get(&key_with_ts(&key(i)[..], 1)).unwrap();
+    }
+}
+
+#[test]
+fn test_compaction_disabled() {
+    let tmp_dir = tempdir().unwrap();
+    let mut opts = AgateOptions::default();
+    opts.num_level_zero_tables = 1;
+    opts.num_level_zero_tables_stall = 2;
+    opts.num_compactors = 0;
+
+    let agate = helper_open(opts, tmp_dir.path());
+    let core = &agate.core;
+    for i in 0..4 {
+        let entries = vec![Entry::new(
+            key_with_ts(&key(i)[..], 1),
+            Bytes::from("value"),
+        )];
+        agate.write_to_lsm(test_request(entries)).unwrap();
+        let memtable = core.new_mem_table().unwrap();
+        core.mt.write().unwrap().use_new_table(memtable);
+        core.flush_immutables().unwrap();
+    }
+    // Level 0 is never stalled without compaction.
+    assert_eq!(core.lvctl.num_level_zero_tables(), 4);
+    for i in 0..4 {
+        agate.get(&key_with_ts(&key(i)[..], 1)).unwrap();
+    }
+    // Closing doesn't wait for compaction.
+    drop(agate);
+}
+
+#[test]
+fn test_write_after_close() {
+    let tmp_dir = tempdir().unwrap();
+    let agate = helper_open(AgateOptions::default(), tmp_dir.path());
+    let core = agate.core.clone();
+    assert!(!core.is_closed());
+    drop(agate);
+
+    assert!(core.is_closed());
+    let entries = vec![Entry::new(
+        key_with_ts(&key(0)[..], 1),
+        Bytes::from("value"),
+    )];
+    assert!(matches!(
+        core.write_requests(vec![test_request(entries)]),
+        Err(Error::DBClosed)
+    ));
+}
+
+#[test]
+fn test_compaction_discard_versions() {
+    let tmp_dir = tempdir().unwrap();
+    let mut opts = AgateOptions::default();
+    opts.num_level_zero_tables = 1;
+    opts.value_threshold = 32;
+
+    let agate = helper_open(opts, tmp_dir.path());
+    for round in 1..=3 {
+        for i in 0..100 {
+            let mut txn = agate.new_transaction(true);
+            for j in i * 10..i * 10 + 10 {
+                if round == 3 && j < 10 {
+                    txn.delete(key(j)).unwrap();
+                } else {
+                    txn.set(key(j), Bytes::from(format!("{:016}", round)))
+                        .unwrap();
+                }
+            }
+            txn.commit().unwrap();
+        }
+    }
+    // No transaction reads below the latest version now.
+    assert_eq!(agate.new_transaction(false).read_ts, 300);
+    assert_eq!(agate.core.orc.discard_at_or_below(), 300);
+
+    let core = &agate.core;
+    let memtable = core.new_mem_table().unwrap();
+    core.mt.write().unwrap().use_new_table(memtable);
+    core.flush_immutables().unwrap();
+    for _ in 0..100 {
+        if core.lvctl.num_level_zero_tables() == 0 {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    assert_eq!(core.lvctl.num_level_zero_tables(), 0);
+
+    // Only the latest version of each key (including the txn key) is kept,
+    // and deleted keys are dropped as there are no lower levels.
+    let mut count = 0;
+    for handler in core.lvctl.levels.iter() {
+        for table in handler.read().tables.iter() {
+            let mut it = table.new_iterator(0);
+            it.rewind();
+            while it.valid() {
+                assert!(get_ts(it.key()) > 200);
+                count += 1;
+                it.next();
+            }
+        }
+    }
+    assert_eq!(count, 990 + 1);
+}
+
+#[test]
+fn test_ttl() {
+    let tmp_dir = tempdir().unwrap();
+    let mut opts = AgateOptions::default();
+    opts.num_level_zero_tables = 1;
+    opts.value_threshold = 32;
+    let big_value = Bytes::from(vec![b'b'; 64]);
+    let expired = |k: &'static str| {
+        let mut e = Entry::new(Bytes::from(k), Bytes::from(k));
+        e.expires_at = 1;
+        e
+    };
+
+    let agate = helper_open(opts, tmp_dir.path());
+    let mut txn = agate.new_transaction(true);
+    let ttl = Duration::from_secs(3600);
+    txn.set_with_ttl(Bytes::from("a"), Bytes::from("a"), ttl)
+        .unwrap();
+    txn.set_with_ttl(Bytes::from("b"), big_value.clone(), ttl)
+        .unwrap();
+    txn.set_entry(expired("c")).unwrap();
+    txn.set(Bytes::from("d"), Bytes::from("d")).unwrap();
+    txn.set(Bytes::from("e"), Bytes::from("e")).unwrap();
+    assert!(matches!(txn.get(b"c"), Err(Error::KeyNotFound)));
+    txn.commit().unwrap();
+    let mut txn = agate.new_transaction(true);
+    txn.set_entry(expired("e")).unwrap();
+    txn.commit().unwrap();
+
+    let check = |agate: &Agate| {
+        let txn = agate.new_transaction(false);
+        let item = txn.get(b"a").unwrap();
+        assert!(item.expires_at() >= crate::util::unix_time().as_secs() + 3000);
+        assert_eq!(txn.get(b"b").unwrap().value().unwrap(), big_value);
+        assert_eq!(txn.get(b"d").unwrap().expires_at(), 0);
+        assert!(matches!(txn.get(b"c"), Err(Error::KeyNotFound)));
+        // Expiry hides older versions too.
+        assert!(matches!(txn.get(b"e"), Err(Error::KeyNotFound)));
+
+        let mut iter = txn.new_iterator(&IteratorOptions::default());
+        iter.rewind();
+        let mut keys = vec![];
+        while iter.valid() {
+            keys.push(iter.item().key().clone());
+            iter.next();
+        }
+        assert_eq!(keys, vec!["a", "b", "d"]);
+    };
+    check(&agate);
+
+    let core = &agate.core;
+    let memtable = core.new_mem_table().unwrap();
+    core.mt.write().unwrap().use_new_table(memtable);
+    core.flush_immutables().unwrap();
+    for _ in 0..100 {
+        if core.lvctl.num_level_zero_tables() == 0 {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    assert_eq!(core.lvctl.num_level_zero_tables(), 0);
+    check(&agate);
+
+    // All versions of expired keys are dropped by compaction.
+    let mut keys = vec![];
+    for handler in core.lvctl.levels.iter() {
+        for table in handler.read().tables.iter() {
+            let mut it = table.new_iterator(0);
+            it.rewind();
+            while it.valid() {
+                let key = user_key(it.key());
+                if !key.starts_with(AGATE_PREFIX) {
+                    keys.push(Bytes::copy_from_slice(key));
+                }
+                it.next();
+            }
+        }
+    }
+    assert_eq!(keys, vec!["a", "b", "d"]);
+}
+
+#[test]
+fn test_value_log_replay() {
+    let tmp_dir = tempdir().unwrap();
+    let mut opts = AgateOptions::default();
+    opts.value_threshold = 32;
+    opts.value_log_file_size = 1 << 20;
+    // Small values are stored inline, but still replayed with the rest of
+    // their transactions.
+    let value = |i: usize| {
+        if i % 2 == 0 {
+            Bytes::from(format!("{:064}", i))
+        } else {
+            Bytes::from(i.to_string())
+        }
+    };
+    let write = |agate: &Agate, keys: std::ops::Range<usize>| {
+        let mut txn = agate.new_transaction(true);
+        for i in keys {
+            txn.set(key(i), value(i)).unwrap();
+        }
+        txn.commit().unwrap();
+    };
+
+    let agate = helper_open(opts.clone(), tmp_dir.path());
+    write(&agate, 0..10);
+    let core = &agate.core;
+    let memtable = core.new_mem_table().unwrap();
+    core.mt.write().unwrap().use_new_table(memtable);
+    core.flush_immutables().unwrap();
+    // Head is recorded outside of LSM tree.
+    assert!(tmp_dir.path().join(HEAD_FILENAME).exists());
+    assert_eq!(core.lvctl.max_version(), 1);
+    write(&agate, 10..20);
+    write(&agate, 20..30);
+    drop(agate);
+
+    // Lose all memtable WALs, tear the last transaction by removing its
+    // marker, and leave an empty value log.
+    for file in fs::read_dir(tmp_dir.path()).unwrap() {
+        let path = file.unwrap().path();
+        if path.extension() == Some("mem".as_ref()) {
+            fs::remove_file(path).unwrap();
+        }
+    }
+    let vlog_path = tmp_dir.path().join("000001.vlog");
+    let data = fs::read(&vlog_path).unwrap();
+    let pos = data
+        .windows(TXN_KEY.len())
+        .rposition(|w| w == TXN_KEY)
+        .unwrap();
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&vlog_path)
+        .unwrap()
+        .set_len(pos as u64)
+        .unwrap();
+    let empty_vlog_path = tmp_dir.path().join("000005.vlog");
+    fs::File::create(&empty_vlog_path).unwrap();
+
+    let check = |agate: &Agate| {
+        let txn = agate.new_transaction(false);
+        for i in 0..20 {
+            assert_eq!(txn.get(&key(i)).unwrap().value().unwrap(), value(i));
+        }
+        for i in 20..30 {
+            assert!(matches!(txn.get(&key(i)), Err(Error::KeyNotFound)));
+        }
+    };
+    let agate = helper_open(opts.clone(), tmp_dir.path());
+    check(&agate);
+    assert!(!empty_vlog_path.exists());
+    // Torn entries are truncated.
+    let len = fs::metadata(&vlog_path).unwrap().len();
+    assert!(len < pos as u64);
+    // New transactions are after replayed ones.
+    write(&agate, 30..40);
+    assert!(agate.new_transaction(false).read_ts > 2);
+    drop(agate);
+
+    // Replayed entries are persisted.
+    let agate = helper_open(opts, tmp_dir.path());
+    check(&agate);
+    let txn = agate.new_transaction(false);
+    assert_eq!(txn.get(&key(30)).unwrap().value().unwrap(), value(30));
+}
+
+#[test]
+fn test_huge_value() {
+    let tmp_dir = tempdir().unwrap();
+    let mut opts = AgateOptions::default();
+    // Values can't be larger than a value log.
+    opts.value_log_file_size = 5 << 30;
+
+    // The value is mapped from a sparse file, so that it's larger than 4 GiB
+    // without taking as much memory. A few bytes around the u32 boundary
+    // are marked to catch misplaced data.
+    let len = (1 << 32) + 4099;
+    let value_dir = tempdir().unwrap();
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(value_dir.path().join("value"))
+        .unwrap();
+    file.set_len(len as u64).unwrap();
+    let mut mmap = unsafe { memmap2::MmapOptions::new().map_mut(&file).unwrap() };
+    for (i, pos) in [0, (1 << 32) - 1, 1 << 32, len - 1].iter().enumerate() {
+        mmap[*pos] = i as u8 + 1;
+    }
+    let value = Bytes::from_static(&Box::leak(Box::new(mmap))[..]);
+
+    let agate = helper_open(opts, tmp_dir.path());
+    let mut txn = agate.new_transaction(true);
+    txn.set(key(0), value.clone()).unwrap();
+    txn.set(key(1), Bytes::from("small")).unwrap();
+    txn.commit().unwrap();
+
+    let txn = agate.new_transaction(false);
+    let read = txn.get(&key(0)).unwrap().value().unwrap();
+    assert_eq!(read.len(), len);
+    assert!(read == value);
+    assert_eq!(txn.get(&key(1)).unwrap().value().unwrap(), "small");
+}
+
+fn gc_test_value(i: usize, round: usize) -> Bytes {
+    Bytes::from(format!("{:01024}", i * 10 + round))
+}
+
+#[test]
+fn test_value_log_gc() {
+    let tmp_dir = tempdir().unwrap();
+    let mut opts = AgateOptions::default();
+    opts.num_level_zero_tables = 1;
+    opts.value_threshold = 32;
+    opts.value_log_max_entries = 50;
+
+    let agate = helper_open(opts.clone(), tmp_dir.path());
+    // Round 1 writes all keys to the first value log, which is rotated
+    // afterwards. Round 2 overwrites even keys in the second one.
+    for round in 1..=2 {
+        for i in 0..5 {
+            let mut txn = agate.new_transaction(true);
+            for j in i * 10..i * 10 + 10 {
+                if round == 1 || j % 2 == 0 {
+                    txn.set(key(j), gc_test_value(j, round)).unwrap();
+                }
+            }
+            txn.commit().unwrap();
+        }
+    }
+    let vlog_path = tmp_dir.path().join("000001.vlog");
+    assert!(vlog_path.exists());
+    assert!(matches!(agate.run_value_log_gc(0.4), Err(Error::NoRewrite)));
+
+    // Overwritten values are discarded by compaction, once no transaction
+    // reads below the latest version.
+    assert_eq!(agate.new_transaction(false).read_ts, 10);
+    let core = &agate.core;
+    let memtable = core.new_mem_table().unwrap();
+    core.mt.write().unwrap().use_new_table(memtable);
+    core.flush_immutables().unwrap();
+    for _ in 0..100 {
+        if core.lvctl.num_level_zero_tables() == 0 {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    assert_eq!(core.lvctl.num_level_zero_tables(), 0);
+    let stats = core.opts.discard_stats.as_ref().unwrap();
+    assert!(stats.get(1) > 0);
+    assert_eq!(stats.get(2), 0);
+
+    assert!(matches!(
+        agate.run_value_log_gc(1.0),
+        Err(Error::InvalidRequest(_))
+    ));
+    assert!(matches!(agate.run_value_log_gc(0.9), Err(Error::NoRewrite)));
+    agate.run_value_log_gc(0.4).unwrap();
+    assert!(!vlog_path.exists());
+    assert_eq!(stats.get(1), 0);
+    assert!(matches!(agate.run_value_log_gc(0.4), Err(Error::NoRewrite)));
+
+    let check = |agate: &Agate| {
+        let txn = agate.new_transaction(false);
+        for i in 0..50 {
+            let round = if i % 2 == 0 { 2 } else { 1 };
+            let value = txn.get(&key(i)).unwrap().value().unwrap();
+            assert_eq!(value, gc_test_value(i, round));
+        }
+    };
+    check(&agate);
+    drop(agate);
+
+    // Rewritten entries are persisted.
+    let agate = helper_open(opts, tmp_dir.path());
+    check(&agate);
+}
+
+#[test]
+fn test_iterator_bounds() {
+    let tmp_dir = tempdir().unwrap();
+    let agate = helper_open(AgateOptions::default(), tmp_dir.path());
+    let key = |i: usize| Bytes::from(format!("k{:02}", i));
+
+    // Even keys are flushed to level 0, and odd keys stay in memtable.
+    let mut txn = agate.new_transaction(true);
+    for i in (0..30).step_by(2) {
+        txn.set(key(i), key(i)).unwrap();
+    }
+    txn.commit().unwrap();
+    let core = &agate.core;
+    let memtable = core.new_mem_table().unwrap();
+    core.mt.write().unwrap().use_new_table(memtable);
+    core.flush_immutables().unwrap();
+    let mut txn = agate.new_transaction(true);
+    for i in (1..30).step_by(2) {
+        txn.set(key(i), key(i)).unwrap();
+    }
+    txn.commit().unwrap();
+
+    fn keys(iter: &mut Iterator) -> Vec<Bytes> {
+        let mut keys = vec![];
+        while iter.valid() {
+            keys.push(iter.item().key().clone());
+            iter.next();
+        }
+        keys
+    }
+
+    let txn = agate.new_transaction(false);
+    let mut iter_opts = IteratorOptions::default();
+    iter_opts.lower_bound = key(5);
+    iter_opts.upper_bound = key(12);
+    let mut iter = txn.new_iterator(&iter_opts);
+    iter.rewind();
+    assert_eq!(keys(&mut iter), (5..12).map(key).collect::<Vec<_>>());
+    iter.seek(&key(0));
+    assert_eq!(keys(&mut iter), (5..12).map(key).collect::<Vec<_>>());
+    iter.seek(&key(10));
+    assert_eq!(keys(&mut iter), vec![key(10), key(11)]);
+    iter.seek(&key(12));
+    assert!(!iter.valid());
+
+    iter_opts.reverse = true;
+    let mut iter = txn.new_iterator(&iter_opts);
+    iter.rewind();
+    assert_eq!(keys(&mut iter), (5..12).rev().map(key).collect::<Vec<_>>());
+    iter.seek(&key(20));
+    assert_eq!(keys(&mut iter), (5..12).rev().map(key).collect::<Vec<_>>());
+    iter.seek(&key(6));
+    assert_eq!(keys(&mut iter), vec![key(6), key(5)]);
+
+    // Bounds work together with prefix.
+    let mut iter_opts = IteratorOptions::default();
+    iter_opts.prefix = Bytes::from("k1");
+    iter_opts.upper_bound = key(13);
+    let mut iter = txn.new_iterator(&iter_opts);
+    iter.rewind();
+    assert_eq!(keys(&mut iter), (10..13).map(key).collect::<Vec<_>>());
+    iter_opts.reverse = true;
+    iter_opts.upper_bound = Bytes::new();
+    iter_opts.lower_bound = key(17);
+    let mut iter = txn.new_iterator(&iter_opts);
+    iter.rewind();
+    assert_eq!(keys(&mut iter), (17..20).rev().map(key).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_iterator() {
+    let tmp_dir = tempdir().unwrap();
+    let mut opts = AgateOptions::default();
+    opts.value_threshold = 32;
+    let agate = helper_open(opts, tmp_dir.path());
+
+    let big_value = Bytes::from(vec![b'x'; 64]);
+    let mut txn = agate.new_transaction(true);
+    txn.set(Bytes::from("a1"), Bytes::from("1")).unwrap();
+    txn.set(Bytes::from("a2"), big_value.clone()).unwrap();
+    txn.set(Bytes::from("b1"), Bytes::from("1")).unwrap();
+    txn.commit().unwrap();
+
+    // Move older versions to level 0.
+    let core = &agate.core;
+    let memtable = core.new_mem_table().unwrap();
+    core.mt.write().unwrap().use_new_table(memtable);
+    core.flush_immutables().unwrap();
+
+    let old_txn = agate.new_transaction(false);
+    let mut txn = agate.new_transaction(true);
+    txn.set(Bytes::from("a1"), Bytes::from("2")).unwrap();
+    txn.delete(Bytes::from("a2")).unwrap();
+    txn.set(Bytes::from("b2"), Bytes::from("2")).unwrap();
+    txn.commit().unwrap();
+
+    fn collect(iter: &mut Iterator) -> Vec<(Bytes, Bytes)> {
+        let mut items = vec![];
+        while iter.valid() {
+            let item = iter.item();
+            items.push((item.key().clone(), item.value().unwrap()));
+            iter.next();
+        }
+        items
+    }
+    let kv = |k: &'static str, v: &'static str| (Bytes::from(k), Bytes::from(v));
+
+    let txn = agate.new_transaction(false);
+    let mut iter_opts = IteratorOptions::default();
+    let mut iter = txn.new_iterator(&iter_opts);
+    iter.rewind();
+    let expected = vec![kv("a1", "2"), kv("b1", "1"), kv("b2", "2")];
+    assert_eq!(collect(&mut iter), expected);
+    iter.seek(b"a2");
+    assert_eq!(collect(&mut iter), expected[1..]);
+
+    // Old versions are still visible to old transactions, and values in
+    // value log are read on demand.
+    let mut iter = old_txn.new_iterator(&iter_opts);
+    iter.rewind();
+    assert_eq!(
+        collect(&mut iter),
+        vec![
+            kv("a1", "1"),
+            (Bytes::from("a2"), big_value.clone()),
+            kv("b1", "1")
+        ]
+    );
+
+    iter_opts.reverse = true;
+    let mut iter = txn.new_iterator(&iter_opts);
+    iter.rewind();
+    assert_eq!(
+        collect(&mut iter),
+        expected.iter().rev().cloned().collect::<Vec<_>>()
+    );
+    iter.seek(b"a9");
+    assert_eq!(collect(&mut iter), vec![kv("a1", "2")]);
+
+    iter_opts.prefix = Bytes::from("a");
+    let mut iter = txn.new_iterator(&iter_opts);
+    iter.rewind();
+    assert_eq!(collect(&mut iter), vec![kv("a1", "2")]);
+    iter_opts.reverse = false;
+    iter_opts.prefetch_values = true;
+    let mut iter = old_txn.new_iterator(&iter_opts);
+    iter.rewind();
+    assert_eq!(
+        collect(&mut iter),
+        vec![kv("a1", "1"), (Bytes::from("a2"), big_value)]
+    );
+
+    let mut iter_opts = IteratorOptions::default();
+    iter_opts.all_versions = true;
+    let mut iter = txn.new_iterator(&iter_opts);
+    iter.rewind();
+    let mut versions = vec![];
+    while iter.valid() {
+        let item = iter.item();
+        versions.push((item.key().clone(), item.is_deleted_or_expired()));
+        iter.next();
+    }
+    assert_eq!(
+        versions,
+        vec![
+            (Bytes::from("a1"), false),
+            (Bytes::from("a1"), false),
+            (Bytes::from("a2"), true),
+            (Bytes::from("a2"), false),
+            (Bytes::from("b1"), false),
+            (Bytes::from("b2"), false),
+        ]
+    );
+
+    // Iterators of update transactions see pending writes, and keys
+    // iterated are checked for conflicts.
+    let mut txn = agate.new_transaction(true);
+    txn.set(Bytes::from("a0"), Bytes::from("0")).unwrap();
+    txn.delete(Bytes::from("b1")).unwrap();
+    let mut iter = txn.new_iterator(&IteratorOptions::default());
+    iter.rewind();
+    assert_eq!(
+        collect(&mut iter),
+        vec![kv("a0", "0"), kv("a1", "2"), kv("b2", "2")]
+    );
+    drop(iter);
+    let mut other = agate.new_transaction(true);
+    other.set(Bytes::from("b2"), Bytes::from("3")).unwrap();
+    other.commit().unwrap();
+    assert!(matches!(txn.commit(), Err(Error::Conflict)));
+}
@@ -0,0 +1,199 @@
+use crate::util::sync_dir;
+use crate::AgateOptions;
+use crate::Result;
+
+use bytes::{Buf, BufMut};
+use parking_lot::Mutex;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+
+pub const DISCARD_FILENAME: &str = "DISCARD";
+const DISCARD_REWRITE_FILENAME: &str = "DISCARD-REWRITE";
+
+/// Size of a record in DISCARD, which is value log id followed by
+/// discarded bytes.
+const RECORD_SIZE: usize = 4 + 8;
+
+/// `DiscardStats` records how many bytes of each value log have become
+/// obsolete, i.e. the values they hold are dropped by compaction. It's used
+/// to pick value logs for garbage collection.
+///
+/// Stats are persisted to DISCARD in `AgateOptions::value_dir`, which is
+/// rewritten as a whole on each update.
+pub struct DiscardStats {
+    /// `None` in in-memory mode
+    dir: Option<PathBuf>,
+    stats: Mutex<HashMap<u32, u64>>,
+    /// Value logs on disk, which are registered by value log. Compaction may
+    /// still drop pointers into deleted value logs, which are not counted.
+    files: Mutex<HashSet<u32>>,
+}
+
+impl DiscardStats {
+    /// Open or create DISCARD in `opts.value_dir`.
+    pub fn open(opts: &AgateOptions) -> Result<Self> {
+        let mut stats = HashMap::new();
+        let dir = if opts.in_memory {
+            None
+        } else {
+            let path = opts.value_dir.join(DISCARD_FILENAME);
+            if path.exists() {
+                let data = fs::read(&path)?;
+                let mut buf = &data[..];
+                while buf.remaining() >= RECORD_SIZE {
+                    let fid = buf.get_u32();
+                    stats.insert(fid, buf.get_u64());
+                }
+            }
+            Some(opts.value_dir.clone())
+        };
+
+        Ok(Self {
+            dir,
+            stats: Mutex::new(stats),
+            files: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// Set value logs on disk when opening value log, and remove stats of
+    /// other value logs.
+    pub fn set_files(&self, fids: &[u32]) -> Result<()> {
+        let mut stats = self.stats.lock();
+        let mut files = self.files.lock();
+        *files = fids.iter().copied().collect();
+        let len = stats.len();
+        stats.retain(|fid, _| files.contains(fid));
+        if stats.len() == len {
+            return Ok(());
+        }
+        self.persist(&stats)
+    }
+
+    /// Register a newly created value log.
+    pub fn add_file(&self, fid: u32) {
+        self.files.lock().insert(fid);
+    }
+
+    /// Add discarded bytes by value log id. Value logs not on disk are
+    /// skipped.
+    pub fn update(&self, discarded: &HashMap<u32, u64>) -> Result<()> {
+        let mut stats = self.stats.lock();
+        let files = self.files.lock();
+        let mut updated = false;
+        for (fid, bytes) in discarded {
+            if files.contains(fid) {
+                *stats.entry(*fid).or_insert(0) += *bytes;
+                updated = true;
+            }
+        }
+        if !updated {
+            return Ok(());
+        }
+        self.persist(&stats)
+    }
+
+    /// Remove stats of a value log, which is called after it's deleted.
+    pub fn remove(&self, fid: u32) -> Result<()> {
+        let mut stats = self.stats.lock();
+        self.files.lock().remove(&fid);
+        if stats.remove(&fid).is_none() {
+            return Ok(());
+        }
+        self.persist(&stats)
+    }
+
+    /// Get discarded bytes of a value log.
+    pub fn get(&self, fid: u32) -> u64 {
+        self.stats.lock().get(&fid).copied().unwrap_or(0)
+    }
+
+    /// Get the value log with the most discarded bytes among `fids`.
+    pub fn max_discard(&self, fids: &[u32]) -> Option<(u32, u64)> {
+        let stats = self.stats.lock();
+        fids.iter()
+            .filter_map(|fid| stats.get(fid).map(|bytes| (*fid, *bytes)))
+            .filter(|(_, bytes)| *bytes > 0)
+            .max_by_key(|(fid, bytes)| (*bytes, *fid))
+    }
+
+    /// Write stats to a temporary file and rename it to DISCARD, so that
+    /// DISCARD is never partially written.
+    fn persist(&self, stats: &HashMap<u32, u64>) -> Result<()> {
+        let dir = match &self.dir {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+
+        let mut buf = Vec::with_capacity(stats.len() * RECORD_SIZE);
+        for (fid, bytes) in stats {
+            buf.put_u32(*fid);
+            buf.put_u64(*bytes);
+        }
+
+        let rewrite_path = dir.join(DISCARD_REWRITE_FILENAME);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&rewrite_path)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        drop(file);
+
+        fs::rename(&rewrite_path, dir.join(DISCARD_FILENAME))?;
+        sync_dir(dir)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_discard_stats() {
+        let tmp_dir = tempdir().unwrap();
+        let mut opts = AgateOptions::default();
+        opts.value_dir = tmp_dir.path().to_path_buf();
+
+        let stats = DiscardStats::open(&opts).unwrap();
+        assert_eq!(stats.max_discard(&[1, 2, 3]), None);
+        stats.set_files(&[1, 2]).unwrap();
+        stats.add_file(3);
+        stats
+            .update(&[(1, 100), (2, 300)].iter().copied().collect())
+            .unwrap();
+        stats
+            .update(&[(1, 400), (3, 50), (4, 10)].iter().copied().collect())
+            .unwrap();
+        // Value log 4 doesn't exist.
+        assert_eq!(stats.get(4), 0);
+        assert_eq!(stats.get(1), 500);
+        assert_eq!(stats.max_discard(&[1, 2, 3]), Some((1, 500)));
+        assert_eq!(stats.max_discard(&[2, 3, 4]), Some((2, 300)));
+        drop(stats);
+
+        // Stats are persisted.
+        let stats = DiscardStats::open(&opts).unwrap();
+        assert_eq!(stats.get(1), 500);
+        assert_eq!(stats.get(2), 300);
+        assert_eq!(stats.get(3), 50);
+        stats.set_files(&[1, 2, 3]).unwrap();
+        stats.remove(1).unwrap();
+        // Deleted value log is no longer counted.
+        stats.update(&[(1, 100)].iter().copied().collect()).unwrap();
+        drop(stats);
+
+        let stats = DiscardStats::open(&opts).unwrap();
+        assert_eq!(stats.get(1), 0);
+        assert_eq!(stats.max_discard(&[1, 2, 3]), Some((2, 300)));
+        // Stats of value logs not on disk are removed.
+        stats.set_files(&[3]).unwrap();
+        drop(stats);
+        let stats = DiscardStats::open(&opts).unwrap();
+        assert_eq!(stats.get(2), 0);
+        assert_eq!(stats.get(3), 50);
+    }
+}
@@ -0,0 +1,95 @@
+use crate::{Error, Result};
+
+use aes::{Aes128, Aes192, Aes256};
+use ctr::cipher::{KeyIvInit, StreamCipher};
+use rand::RngCore;
+
+/// Length of IV, which equals to AES block size.
+pub const IV_SIZE: usize = 16;
+
+type Aes128Ctr = ctr::Ctr128BE<Aes128>;
+type Aes192Ctr = ctr::Ctr128BE<Aes192>;
+type Aes256Ctr = ctr::Ctr128BE<Aes256>;
+
+/// Check if `key` could be used as an AES key, i.e. it is 16, 24 or 32
+/// bytes long.
+pub fn is_valid_key(key: &[u8]) -> bool {
+    matches!(key.len(), 16 | 24 | 32)
+}
+
+/// Encrypt or decrypt `data` in place with AES in CTR mode. As CTR mode is
+/// symmetric, the same function is used for both directions.
+pub fn xor_block(data: &mut [u8], key: &[u8], iv: &[u8]) -> Result<()> {
+    let invalid = |e| Error::Encryption(format!("{}", e));
+    match key.len() {
+        16 => Aes128Ctr::new_from_slices(key, iv)
+            .map_err(invalid)?
+            .apply_keystream(data),
+        24 => Aes192Ctr::new_from_slices(key, iv)
+            .map_err(invalid)?
+            .apply_keystream(data),
+        32 => Aes256Ctr::new_from_slices(key, iv)
+            .map_err(invalid)?
+            .apply_keystream(data),
+        n => {
+            return Err(Error::Encryption(format!(
+                "invalid key length {}, should be 16, 24 or 32",
+                n
+            )))
+        }
+    }
+    Ok(())
+}
+
+/// Generate `n` random bytes, used as IV or data key.
+pub fn random_bytes(n: usize) -> Vec<u8> {
+    let mut buf = vec![0; n];
+    rand::thread_rng().fill_bytes(&mut buf);
+    buf
+}
+
+/// Encrypt `data` with a random IV, which is appended to the result.
+pub fn encrypt_with_iv(data: &[u8], key: &[u8]) -> Result<Vec<u8>> {
+    let iv = random_bytes(IV_SIZE);
+    let mut buf = Vec::with_capacity(data.len() + IV_SIZE);
+    buf.extend_from_slice(data);
+    xor_block(&mut buf, key, &iv)?;
+    buf.extend_from_slice(&iv);
+    Ok(buf)
+}
+
+/// Decrypt data generated by `encrypt_with_iv`.
+pub fn decrypt_with_iv(data: &[u8], key: &[u8]) -> Result<Vec<u8>> {
+    if data.len() < IV_SIZE {
+        return Err(Error::Encryption(format!(
+            "encrypted data too short: {}",
+            data.len()
+        )));
+    }
+    let (data, iv) = data.split_at(data.len() - IV_SIZE);
+    let mut buf = data.to_vec();
+    xor_block(&mut buf, key, iv)?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encryption() {
+        let data: Vec<u8> = (0..1000).map(|i| (i % 7) as u8).collect();
+        for key_len in [16, 24, 32] {
+            let key = random_bytes(key_len);
+            let encrypted = encrypt_with_iv(&data, &key).unwrap();
+            assert_eq!(encrypted.len(), data.len() + IV_SIZE);
+            assert_ne!(&encrypted[..data.len()], &data[..]);
+            assert_eq!(decrypt_with_iv(&encrypted, &key).unwrap(), data);
+
+            let other_key = random_bytes(key_len);
+            assert_ne!(decrypt_with_iv(&encrypted, &other_key).unwrap(), data);
+        }
+        assert!(encrypt_with_iv(&data, &random_bytes(8)).is_err());
+        assert!(decrypt_with_iv(&data[..IV_SIZE - 1], &random_bytes(16)).is_err());
+    }
+}
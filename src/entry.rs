@@ -1,5 +1,7 @@
+use crate::util::unix_time;
 use crate::value::{ValuePointer, VALUE_DELETE};
 use bytes::Bytes;
+use std::time::Duration;
 
 #[derive(Clone)]
 pub struct Entry {
@@ -42,6 +44,12 @@ impl Entry {
         self.meta |= VALUE_DELETE;
     }
 
+    /// Set the entry to expire after `ttl`. Expiry is in seconds, so `ttl`
+    /// is rounded down to whole seconds since unix epoch.
+    pub fn set_ttl(&mut self, ttl: Duration) {
+        self.expires_at = (unix_time() + ttl).as_secs();
+    }
+
     pub fn estimate_size(&self, threshold: usize) -> usize {
         // The estimated size of an entry will be key length + value length +
         // two bytes of metadata.
@@ -51,7 +59,7 @@ impl Entry {
             self.key.len() + self.value.len() + METADATA_SIZE
         } else {
             // For those values >= threshold, only key will be stored in LSM tree.
-            self.key.len() + ValuePointer::encoded_size() + METADATA_SIZE
+            self.key.len() + ValuePointer::max_encoded_size() + METADATA_SIZE
         }
     }
 
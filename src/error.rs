@@ -9,7 +9,7 @@ use crate::value::ValuePointer;
 pub struct InvalidValuePointerError {
     pub vptr: ValuePointer,
     pub kvlen: usize,
-    pub range: Range<u32>,
+    pub range: Range<u64>,
 }
 
 #[derive(Debug, Error)]
@@ -20,8 +20,26 @@ pub enum Error {
     Io(#[source] Box<io::Error>),
     #[error("Empty key")]
     EmptyKey,
+    #[error("Key not found")]
+    KeyNotFound,
+    #[error("Txn is too big to fit into one request")]
+    TxnTooBig,
+    #[error("Transaction Conflict. Please retry")]
+    Conflict,
+    #[error("No sets or deletes are allowed in a read-only transaction")]
+    ReadOnlyTxn,
+    #[error("This transaction has been discarded. Create a new one")]
+    DiscardedTxn,
     #[error("Too long: {0}")]
     TooLong(String),
+    #[error("Compression error: {0}")]
+    Compression(String),
+    #[error("Encryption error: {0}")]
+    Encryption(String),
+    #[error("Encryption key mismatch")]
+    EncryptionKeyMismatch,
+    #[error("Invalid data key id: {0}")]
+    InvalidDataKeyId(u64),
     #[error("Invalid checksum")]
     InvalidChecksum(String),
     #[error("Invalid filename")]
@@ -39,11 +57,21 @@ pub enum Error {
     #[error("Invalid VP: {0:?}")]
     InvalidValuePointer(Box<InvalidValuePointerError>),
     #[error("Invalid Log Offset: {0} > {1}")]
-    InvalidLogOffset(u32, u32),
+    InvalidLogOffset(u64, u64),
     #[error("VLog Not Found: id={0}")]
     VlogNotFound(u32),
+    #[error("Invalid manifest: {0}")]
+    InvalidManifest(String),
+    #[error("Table file not found: id={0}")]
+    TableNotFound(u64),
     #[error("Error when compaction: {0}")]
     CompactionError(String),
+    #[error("Error when flushing memtable: {0}")]
+    FlushError(String),
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+    #[error("Value log GC attempt didn't result in any cleanup")]
+    NoRewrite,
 }
 
 impl From<io::Error> for Error {
@@ -1,26 +1,560 @@
-use crate::Table;
-use bytes::Bytes;
+use crate::db::Core;
+use crate::entry::Entry;
+use crate::format::{get_ts, key_with_ts, user_key};
+use crate::iterator_trait::AgateIterator;
+use crate::ops::transaction::{Transaction, AGATE_PREFIX};
+use crate::table::{MergeIterator, TableIterators};
+use crate::util::{self, is_deleted_or_expired};
+use crate::value::Value;
+use crate::{Result, Table};
+use bytes::{Bytes, BytesMut};
+use std::cmp::Ordering;
+use std::sync::Arc;
 
-#[derive(Default, Clone)]
+/// `Item` is a key-value pair returned by transactions and iterators.
+///
+/// Value may be stored in value log, and is only read when `value` is called.
+#[derive(Clone)]
+pub struct Item {
+    key: Bytes,
+    version: u64,
+    meta: u8,
+    user_meta: u8,
+    expires_at: u64,
+    value: Bytes,
+    core: Arc<Core>,
+}
+
+impl Item {
+    /// Create an item of user key `key` from `value` read from LSM tree.
+    pub(crate) fn new(key: Bytes, value: Value, core: Arc<Core>) -> Self {
+        Self {
+            key,
+            version: value.version,
+            meta: value.meta,
+            user_meta: value.user_meta,
+            expires_at: value.expires_at,
+            value: value.value,
+            core,
+        }
+    }
+
+    /// Create an item from an entry not written yet.
+    pub(crate) fn from_entry(entry: &Entry, version: u64, core: Arc<Core>) -> Self {
+        Self {
+            key: entry.key.clone(),
+            version,
+            // Pending entries always hold actual value.
+            meta: entry.meta & !crate::value::VALUE_POINTER,
+            user_meta: entry.user_meta,
+            expires_at: entry.expires_at,
+            value: entry.value.clone(),
+            core,
+        }
+    }
+
+    /// User key of the item.
+    pub fn key(&self) -> &Bytes {
+        &self.key
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn user_meta(&self) -> u8 {
+        self.user_meta
+    }
+
+    pub fn expires_at(&self) -> u64 {
+        self.expires_at
+    }
+
+    /// Returns `true` if this version is deleted or expired. Such items are
+    /// only emitted by iterators with `all_versions` set.
+    pub fn is_deleted_or_expired(&self) -> bool {
+        is_deleted_or_expired(self.meta, self.expires_at)
+    }
+
+    /// Value of the item, read from value log if necessary.
+    pub fn value(&self) -> Result<Bytes> {
+        let value = Value {
+            meta: self.meta,
+            user_meta: self.user_meta,
+            expires_at: self.expires_at,
+            value: self.value.clone(),
+            version: self.version,
+        };
+        Ok(self.core.read_value(value)?.value)
+    }
+}
+
+#[derive(Clone)]
 pub struct IteratorOptions {
     pub prefetch_size: usize,
+    /// Read values from value log while iterating, instead of on demand.
     pub prefetch_values: bool,
+    /// Iterate keys in descending order.
     pub reverse: bool,
+    /// Emit all versions visible at read timestamp, including deleted ones.
     pub all_versions: bool,
+    /// Emit internal keys of agatedb.
     pub internal_access: bool,
     prefix_is_key: bool,
+    /// Only iterate keys starting with `prefix`.
     pub prefix: Bytes,
+    /// Only iterate keys greater than or equal to `lower_bound`. Empty if
+    /// unbounded.
+    pub lower_bound: Bytes,
+    /// Only iterate keys less than `upper_bound`. Empty if unbounded.
+    pub upper_bound: Bytes,
+    /// Add blocks read to block cache. Disable it for large scans, so that
+    /// hot blocks won't be evicted.
+    pub fill_cache: bool,
+}
+
+impl Default for IteratorOptions {
+    fn default() -> Self {
+        Self {
+            prefetch_size: 0,
+            prefetch_values: false,
+            reverse: false,
+            all_versions: false,
+            internal_access: false,
+            prefix_is_key: false,
+            prefix: Bytes::new(),
+            lower_bound: Bytes::new(),
+            upper_bound: Bytes::new(),
+            fill_cache: true,
+        }
+    }
 }
 
 impl IteratorOptions {
+    /// Compare `key` (with timestamp) truncated to length of prefix with
+    /// prefix.
+    fn compare_to_prefix(&self, key: &[u8]) -> Ordering {
+        let key = user_key(key);
+        let len = self.prefix.len().min(key.len());
+        key[..len].cmp(&self.prefix[..])
+    }
+
     /// Check if a table should be included in iterator
-    pub fn pick_table(&self, _table: &Table) -> bool {
-        true
-        // TODO: implement table selection logic
+    pub fn pick_table(&self, table: &Table) -> bool {
+        if !table.overlaps_with_range(&self.lower_bound, &self.upper_bound) {
+            return false;
+        }
+        if self.prefix.is_empty() {
+            return true;
+        }
+        if self.compare_to_prefix(table.smallest()) == Ordering::Greater
+            || self.compare_to_prefix(table.biggest()) == Ordering::Less
+        {
+            return false;
+        }
+        self.may_contain_prefix(table)
+    }
+
+    /// Check bloom filter of `table` for keys with prefix.
+    ///
+    /// Tables of which index can't be read are picked, so that the error is
+    /// reported when iterating them.
+    fn may_contain_prefix(&self, table: &Table) -> bool {
+        let does_not_have = |hash| table.does_not_have(hash).unwrap_or(false);
+        // Prefix is a full key, so bloom filter could be used.
+        if self.prefix_is_key && does_not_have(farmhash::fingerprint32(&self.prefix)) {
+            return false;
+        }
+        // All keys with prefix share the same extracted prefix, which has
+        // been added to bloom filter.
+        match table
+            .prefix_extractor()
+            .unwrap_or(None)
+            .and_then(|e| e.extract(&self.prefix))
+        {
+            Some(prefix) => !does_not_have(farmhash::fingerprint32(prefix)),
+            None => true,
+        }
+    }
+
+    /// Remove unnecessary tables. Tables should be sorted by key range.
+    pub fn pick_tables(&self, tables: &mut Vec<Table>) {
+        if !self.lower_bound.is_empty() {
+            let start = util::search(tables.len(), |i| {
+                user_key(tables[i].biggest()) >= &self.lower_bound[..]
+            });
+            tables.drain(..start);
+        }
+        if !self.upper_bound.is_empty() {
+            let end = util::search(tables.len(), |i| {
+                user_key(tables[i].smallest()) >= &self.upper_bound[..]
+            });
+            tables.truncate(end);
+        }
+        if self.prefix.is_empty() {
+            return;
+        }
+        let start = util::search(tables.len(), |i| {
+            self.compare_to_prefix(tables[i].biggest()) != Ordering::Less
+        });
+        tables.drain(..start);
+        let end = util::search(tables.len(), |i| {
+            self.compare_to_prefix(tables[i].smallest()) == Ordering::Greater
+        });
+        tables.truncate(end);
+        tables.retain(|t| self.may_contain_prefix(t));
     }
+}
+
+/// Returns the smallest key that is greater than all keys starting with
+/// `prefix`, or `None` if there is no such key.
+fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut key = prefix.to_vec();
+    while let Some(last) = key.pop() {
+        if last < u8::MAX {
+            key.push(last + 1);
+            return Some(key);
+        }
+    }
+    None
+}
+
+/// `Iterator` iterates on keys visible at `read_ts`, merging all memtables
+/// and levels. Only the latest version of each key is emitted, and deleted
+/// and expired keys are skipped, unless `all_versions` is set.
+pub struct Iterator<'a> {
+    table_iter: Box<TableIterators>,
+    read_ts: u64,
+    opts: IteratorOptions,
+    core: Arc<Core>,
+    /// Transaction of the iterator, which tracks keys read for conflict
+    /// detection.
+    txn: Option<&'a Transaction>,
+    item: Option<Item>,
+    /// User key of the last emitted or skipped entry, whose older versions
+    /// should be skipped.
+    last_key: BytesMut,
+}
+
+impl<'a> Iterator<'a> {
+    pub(crate) fn new(
+        core: Arc<Core>,
+        read_ts: u64,
+        opts: &IteratorOptions,
+        txn: Option<&'a Transaction>,
+    ) -> Self {
+        let mut iters = vec![];
+        if let Some(pending) = txn.and_then(|txn| txn.new_pending_writes_iterator(opts.reverse)) {
+            // Pending writes are newer than anything else.
+            iters.push(TableIterators::from(pending));
+        }
+        iters.append(&mut core.new_table_iterators(opts));
+        Self {
+            table_iter: MergeIterator::from_iterators(
+                iters.into_iter().map(Box::new).collect(),
+                opts.reverse,
+            ),
+            read_ts,
+            opts: opts.clone(),
+            core,
+            txn,
+            item: None,
+            last_key: BytesMut::new(),
+        }
+    }
+
+    /// Seek to the first key, or the first key with `prefix` if set. In
+    /// reverse mode, seek to the last key instead. Keys are limited by
+    /// `lower_bound` and `upper_bound`.
+    pub fn rewind(&mut self) {
+        if !self.opts.reverse {
+            let start = self.opts.prefix.clone().max(self.opts.lower_bound.clone());
+            if start.is_empty() {
+                self.table_iter.rewind();
+                self.last_key.clear();
+                self.parse_item();
+            } else {
+                self.seek(&start);
+            }
+            return;
+        }
+
+        let successor = prefix_successor(&self.opts.prefix).map(Bytes::from);
+        let upper = Some(self.opts.upper_bound.clone()).filter(|b| !b.is_empty());
+        let end = match (successor, upper) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        if let Some(key) = end {
+            // All versions of keys in range are smaller than the newest
+            // version of the end key.
+            self.table_iter.seek(&key_with_ts(&key[..], u64::MAX));
+        } else {
+            self.table_iter.rewind();
+        }
+        self.last_key.clear();
+        self.parse_item();
+    }
+
+    /// Seek to the first key greater than or equal to `key`, or the last key
+    /// less than or equal to `key` in reverse mode.
+    pub fn seek(&mut self, key: &[u8]) {
+        let seek_key = if !self.opts.reverse {
+            let key = key
+                .max(&self.opts.prefix[..])
+                .max(&self.opts.lower_bound[..]);
+            key_with_ts(key, self.read_ts)
+        } else if !self.opts.upper_bound.is_empty() && key >= &self.opts.upper_bound[..] {
+            // Keys at or beyond upper bound are skipped by `should_skip`.
+            key_with_ts(&self.opts.upper_bound[..], u64::MAX)
+        } else {
+            // Oldest version of `key`, so all visible versions are included.
+            key_with_ts(key, 0)
+        };
+        self.table_iter.seek(&seek_key);
+        self.last_key.clear();
+        self.parse_item();
+    }
+
+    pub fn next(&mut self) {
+        if self.item.is_none() {
+            return;
+        }
+        // In reverse mode, all versions of current key have been consumed.
+        if !self.opts.reverse || self.opts.all_versions {
+            self.table_iter.next();
+        }
+        self.parse_item();
+    }
+
+    /// Returns `false` when iteration is done.
+    pub fn valid(&self) -> bool {
+        self.item.is_some()
+    }
+
+    /// Returns `true` if iterator is valid and current key starts with
+    /// `prefix`.
+    pub fn valid_for_prefix(&self, prefix: &[u8]) -> bool {
+        matches!(&self.item, Some(item) if item.key().starts_with(prefix))
+    }
+
+    /// Current item. Panics if iterator is invalid.
+    pub fn item(&self) -> &Item {
+        let item = self.item.as_ref().unwrap();
+        if let Some(txn) = self.txn {
+            txn.add_read_key(item.key());
+        }
+        item
+    }
+
+    /// Returns `true` if current entry of `table_iter` should never be
+    /// emitted. Returns `None` if iteration is out of prefix range or
+    /// bounds.
+    fn should_skip(&self) -> Option<bool> {
+        let key = self.table_iter.key();
+        let user_key = user_key(key);
+        let below_lower =
+            !self.opts.lower_bound.is_empty() && user_key < &self.opts.lower_bound[..];
+        let beyond_upper =
+            !self.opts.upper_bound.is_empty() && user_key >= &self.opts.upper_bound[..];
+        if below_lower || beyond_upper {
+            // Keys out of bounds which are not reached yet in iteration order
+            // are skipped.
+            return if self.opts.reverse == beyond_upper {
+                Some(true)
+            } else {
+                None
+            };
+        }
+        if !user_key.starts_with(&self.opts.prefix) {
+            if !self.opts.reverse || user_key < &self.opts.prefix[..] {
+                return None;
+            }
+            // Keys bigger than prefix range in reverse mode.
+            return Some(true);
+        }
+        Some(
+            get_ts(key) > self.read_ts
+                || (!self.opts.internal_access && user_key.starts_with(AGATE_PREFIX)),
+        )
+    }
+
+    /// Advance `table_iter` to the next entry to emit, and set current item.
+    fn parse_item(&mut self) {
+        self.item = None;
+        while self.table_iter.valid() {
+            match self.should_skip() {
+                None => return,
+                Some(true) => {
+                    self.table_iter.next();
+                    continue;
+                }
+                Some(false) => {}
+            }
+
+            let key = self.table_iter.key();
+            let user_key = user_key(key);
+            if self.opts.all_versions {
+                let mut value = self.table_iter.value();
+                value.version = get_ts(key);
+                self.set_item(Bytes::copy_from_slice(user_key), value);
+                return;
+            }
+
+            let (user_key, value) = if !self.opts.reverse {
+                if !self.last_key.is_empty() && user_key == &self.last_key[..] {
+                    self.table_iter.next();
+                    continue;
+                }
+                // This is the latest visible version of the key.
+                self.last_key.clear();
+                self.last_key.extend_from_slice(user_key);
+                let mut value = self.table_iter.value();
+                value.version = get_ts(key);
+                (Bytes::copy_from_slice(user_key), value)
+            } else {
+                self.latest_version_reverse()
+            };
+            if is_deleted_or_expired(value.meta, value.expires_at) {
+                if !self.opts.reverse {
+                    self.table_iter.next();
+                }
+                continue;
+            }
+            self.set_item(user_key, value);
+            return;
+        }
+    }
+
+    /// In reverse mode, versions of a key are emitted from the oldest to the
+    /// newest. Consume all versions of current key, and returns the latest
+    /// visible one.
+    fn latest_version_reverse(&mut self) -> (Bytes, Value) {
+        let key = self.table_iter.key();
+        let cur_key = Bytes::copy_from_slice(user_key(key));
+        let mut value = self.table_iter.value();
+        value.version = get_ts(key);
+        self.table_iter.next();
+        while self.table_iter.valid() {
+            let key = self.table_iter.key();
+            if user_key(key) != cur_key {
+                break;
+            }
+            let version = get_ts(key);
+            if version <= self.read_ts {
+                value = self.table_iter.value();
+                value.version = version;
+            }
+            self.table_iter.next();
+        }
+        (cur_key, value)
+    }
+
+    fn set_item(&mut self, key: Bytes, mut value: Value) {
+        if self.opts.prefetch_values {
+            // Errors are returned when value is read again.
+            if let Ok(v) = self.core.read_value(value.clone()) {
+                value = v;
+            }
+        }
+        self.item = Some(Item::new(key, value, self.core.clone()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::table::tests::{build_table_data, get_test_table_options};
+
+    fn build_table(id: u64, keys: &[&'static str]) -> Table {
+        let kvs = keys
+            .iter()
+            .map(|k| (Bytes::from(*k), Bytes::from(*k)))
+            .collect();
+        let opts = get_test_table_options();
+        Table::open_in_memory(build_table_data(kvs, opts.clone()), id, opts).unwrap()
+    }
+
+    #[test]
+    fn test_pick_tables_with_prefix_bloom() {
+        let mut opts = get_test_table_options();
+        opts.prefix_extractor = Some(crate::opt::PrefixExtractor::FixedLength(2));
+        let build_table = |id, keys: &[&'static str]| {
+            let kvs = keys
+                .iter()
+                .map(|k| (Bytes::from(*k), Bytes::from(*k)))
+                .collect();
+            Table::open_in_memory(build_table_data(kvs, opts.clone()), id, opts.clone()).unwrap()
+        };
+        let tables = vec![
+            build_table(1, &["a1x", "a3x", "c1x"]),
+            build_table(2, &["a2x", "c2x"]),
+        ];
+
+        let mut iter_opts = IteratorOptions::default();
+        iter_opts.prefix = Bytes::from("a1");
+        assert!(iter_opts.pick_table(&tables[0]));
+        assert!(!iter_opts.pick_table(&tables[1]));
+        let mut picked = tables.clone();
+        iter_opts.pick_tables(&mut picked);
+        assert_eq!(picked.iter().map(|t| t.id()).collect::<Vec<_>>(), vec![1]);
+
+        // Longer prefixes share the extracted prefix.
+        iter_opts.prefix = Bytes::from("a2x");
+        assert!(!iter_opts.pick_table(&tables[0]));
+        assert!(iter_opts.pick_table(&tables[1]));
+
+        // Prefixes shorter than extracted ones can't use bloom filter.
+        iter_opts.prefix = Bytes::from("a");
+        assert!(iter_opts.pick_table(&tables[0]));
+        assert!(iter_opts.pick_table(&tables[1]));
+    }
+
+    #[test]
+    fn test_pick_tables() {
+        let tables = vec![
+            build_table(1, &["a1", "a3"]),
+            build_table(2, &["b1", "b5"]),
+            build_table(3, &["b7", "c1"]),
+            build_table(4, &["d1", "d2"]),
+        ];
+        let picked = |opts: &IteratorOptions| {
+            let mut picked = tables.clone();
+            opts.pick_tables(&mut picked);
+            picked.iter().map(|t| t.id()).collect::<Vec<_>>()
+        };
+
+        let mut opts = IteratorOptions::default();
+        assert_eq!(picked(&opts), vec![1, 2, 3, 4]);
+
+        opts.prefix = Bytes::from("b");
+        assert_eq!(picked(&opts), vec![2, 3]);
+        assert!(!opts.pick_table(&tables[0]));
+        assert!(opts.pick_table(&tables[2]));
+
+        // Prefix falls between two tables.
+        opts.prefix = Bytes::from("b6");
+        assert!(picked(&opts).is_empty());
+
+        opts.prefix = Bytes::from("c");
+        assert_eq!(picked(&opts), vec![3]);
+        opts.prefix = Bytes::from("e");
+        assert!(picked(&opts).is_empty());
 
-    /// Remove unnecessary tables
-    pub fn pick_tables(&self, _tables: &mut Vec<Table>) {
-        // TODO: implement table selection logic
+        opts.prefix = Bytes::new();
+        opts.lower_bound = Bytes::from("b2");
+        assert_eq!(picked(&opts), vec![2, 3, 4]);
+        opts.upper_bound = Bytes::from("b7");
+        assert_eq!(picked(&opts), vec![2]);
+        assert!(!opts.pick_table(&tables[2]));
+        opts.upper_bound = Bytes::from("b8");
+        assert_eq!(picked(&opts), vec![2, 3]);
+        // Bounds work together with prefix.
+        opts.prefix = Bytes::from("a");
+        assert!(picked(&opts).is_empty());
+        opts.prefix = Bytes::from("c");
+        opts.lower_bound = Bytes::new();
+        opts.upper_bound = Bytes::new();
+        assert_eq!(picked(&opts), vec![3]);
     }
 }
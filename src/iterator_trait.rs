@@ -4,11 +4,20 @@ use enum_dispatch::enum_dispatch;
 
 /// `AgateIterator` defines the interface of all iterators,
 /// including `TableIterator`, `MergeIterator` and `ConcatIterator`.
+///
+/// Directions are relative to the iterator. For a reversed iterator, `next`
+/// moves to smaller keys, and `prev` moves to bigger keys.
 #[enum_dispatch]
 pub trait AgateIterator {
     fn next(&mut self);
     fn rewind(&mut self);
     fn seek(&mut self, key: &Bytes);
+    /// Move to the previous entry, the opposite direction of `next`.
+    fn prev(&mut self);
+    /// Move to the last entry, the opposite of `rewind`.
+    fn seek_to_last(&mut self);
+    /// Seek to the last entry before or at `key`, the opposite of `seek`.
+    fn seek_for_prev(&mut self, key: &Bytes);
     fn key(&self) -> &[u8];
     fn value(&self) -> Value;
     fn valid(&self) -> bool;
@@ -0,0 +1,319 @@
+use crate::encryption::{self, IV_SIZE};
+use crate::util::sync_dir;
+use crate::AgateOptions;
+use crate::{Error, Result};
+
+use bytes::{Buf, BufMut};
+use crc::crc32;
+use parking_lot::RwLock;
+use prost::Message;
+use proto::meta::DataKey;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const KEY_REGISTRY_FILENAME: &str = "KEYREGISTRY";
+const KEY_REGISTRY_REWRITE_FILENAME: &str = "KEYREGISTRY-REWRITE";
+
+/// Text stored at the beginning of key registry, encrypted with master key.
+/// It's used to check whether the given master key is correct.
+const SANITY_TEXT: &[u8] = b"Hello Agate";
+
+struct Core {
+    /// Decrypted data keys by key id.
+    data_keys: HashMap<u64, DataKey>,
+    /// Id of the latest data key, 0 if no data key is generated yet.
+    next_key_id: u64,
+    /// Creation time of the latest data key, in seconds since UNIX epoch.
+    last_created: i64,
+    /// `None` in in-memory mode
+    file: Option<File>,
+}
+
+/// `KeyRegistry` manages data keys, which are used to encrypt SSTs, WALs and
+/// value logs.
+///
+/// Data keys are encrypted with master key (`AgateOptions::encryption_key`)
+/// and appended to KEYREGISTRY, each framed by its length and CRC32C
+/// checksum. A new data key is generated once the latest one is older
+/// than `AgateOptions::encryption_key_rotation_duration`, while old keys are
+/// kept to decrypt existing files.
+pub struct KeyRegistry {
+    encryption_key: Vec<u8>,
+    rotation_duration: Duration,
+    core: RwLock<Core>,
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
+
+impl KeyRegistry {
+    /// Open or create KEYREGISTRY in `opts.dir`. Returns
+    /// `Error::EncryptionKeyMismatch` if KEYREGISTRY is created with a
+    /// different master key.
+    pub fn open(opts: &AgateOptions) -> Result<Self> {
+        let mut core = Core {
+            data_keys: HashMap::new(),
+            next_key_id: 0,
+            last_created: 0,
+            file: None,
+        };
+
+        if !opts.in_memory {
+            let path = opts.dir.join(KEY_REGISTRY_FILENAME);
+            let mut file = if path.exists() {
+                let mut file = OpenOptions::new().read(true).write(true).open(&path)?;
+                let trunc_offset = replay_key_registry(&mut file, &opts.encryption_key, &mut core)?;
+                // Truncate file so that we won't append after a corrupted key.
+                file.set_len(trunc_offset)?;
+                file
+            } else {
+                help_rewrite(&opts.dir, &opts.encryption_key)?
+            };
+            file.seek(SeekFrom::End(0))?;
+            core.file = Some(file);
+        }
+
+        Ok(Self {
+            encryption_key: opts.encryption_key.clone(),
+            rotation_duration: opts.encryption_key_rotation_duration,
+            core: RwLock::new(core),
+        })
+    }
+
+    /// Get data key of `key_id`. Returns `None` if `key_id` is 0, which
+    /// means data is not encrypted.
+    pub fn data_key(&self, key_id: u64) -> Result<Option<DataKey>> {
+        if key_id == 0 {
+            return Ok(None);
+        }
+        match self.core.read().data_keys.get(&key_id) {
+            Some(key) => Ok(Some(key.clone())),
+            None => Err(Error::InvalidDataKeyId(key_id)),
+        }
+    }
+
+    /// Get the data key to encrypt new files with, which is rotated once
+    /// it's older than rotation duration. Returns `None` if encryption is
+    /// disabled.
+    pub fn latest_data_key(&self) -> Result<Option<DataKey>> {
+        if self.encryption_key.is_empty() {
+            return Ok(None);
+        }
+
+        let valid = |core: &Core| {
+            core.next_key_id > 0
+                && now() < core.last_created + self.rotation_duration.as_secs() as i64
+        };
+
+        let core = self.core.read();
+        if valid(&core) {
+            return Ok(Some(core.data_keys[&core.next_key_id].clone()));
+        }
+        drop(core);
+
+        let mut core = self.core.write();
+        // Key may have been rotated by others.
+        if valid(&core) {
+            return Ok(Some(core.data_keys[&core.next_key_id].clone()));
+        }
+        let key = DataKey {
+            key_id: core.next_key_id + 1,
+            data: encryption::random_bytes(self.encryption_key.len()),
+            iv: encryption::random_bytes(IV_SIZE),
+            created_at: now(),
+        };
+        if let Some(file) = core.file.as_mut() {
+            let mut buf = vec![];
+            encode_data_key(&key, &self.encryption_key, &mut buf)?;
+            file.write_all(&buf)?;
+            file.sync_all()?;
+        }
+        core.next_key_id = key.key_id;
+        core.last_created = key.created_at;
+        core.data_keys.insert(key.key_id, key.clone());
+        Ok(Some(key))
+    }
+}
+
+/// Encode data key as length, CRC32C checksum and protobuf data, of which
+/// key data is encrypted with master key.
+fn encode_data_key(key: &DataKey, encryption_key: &[u8], buf: &mut Vec<u8>) -> Result<()> {
+    let mut key = key.clone();
+    if !encryption_key.is_empty() {
+        encryption::xor_block(&mut key.data, encryption_key, &key.iv)?;
+    }
+    let mut data = vec![];
+    key.encode(&mut data).unwrap();
+    buf.put_u32(data.len() as u32);
+    buf.put_u32(crc32::checksum_castagnoli(&data));
+    buf.extend_from_slice(&data);
+    Ok(())
+}
+
+/// Write a new KEYREGISTRY without any data key.
+fn help_rewrite(dir: impl AsRef<Path>, encryption_key: &[u8]) -> Result<File> {
+    let rewrite_path = dir.as_ref().join(KEY_REGISTRY_REWRITE_FILENAME);
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(&rewrite_path)?;
+
+    let iv = encryption::random_bytes(IV_SIZE);
+    let mut sanity = SANITY_TEXT.to_vec();
+    if !encryption_key.is_empty() {
+        encryption::xor_block(&mut sanity, encryption_key, &iv)?;
+    }
+    file.write_all(&iv)?;
+    file.write_all(&sanity)?;
+    file.sync_all()?;
+    drop(file);
+
+    let path = dir.as_ref().join(KEY_REGISTRY_FILENAME);
+    fs::rename(&rewrite_path, &path)?;
+    let file = OpenOptions::new().read(true).write(true).open(&path)?;
+    sync_dir(&dir)?;
+    Ok(file)
+}
+
+/// Read exactly `buf.len()` bytes. Returns `false` if file ends early.
+fn read_full(file: &mut File, buf: &mut [u8]) -> Result<bool> {
+    match file.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Verify master key and read all data keys into `core`.
+///
+/// Returns the offset of the end of last valid data key.
+fn replay_key_registry(file: &mut File, encryption_key: &[u8], core: &mut Core) -> Result<u64> {
+    file.seek(SeekFrom::Start(0))?;
+
+    let mut iv = [0; IV_SIZE];
+    let mut sanity = vec![0; SANITY_TEXT.len()];
+    if !read_full(file, &mut iv)? || !read_full(file, &mut sanity)? {
+        return Err(Error::Encryption("bad key registry".to_string()));
+    }
+    if !encryption_key.is_empty() {
+        encryption::xor_block(&mut sanity, encryption_key, &iv)?;
+    }
+    if sanity != SANITY_TEXT {
+        return Err(Error::EncryptionKeyMismatch);
+    }
+
+    let mut offset = (IV_SIZE + SANITY_TEXT.len()) as u64;
+    loop {
+        let mut header = [0; 8];
+        if !read_full(file, &mut header)? {
+            break;
+        }
+        let mut header = &header[..];
+        let length = header.get_u32();
+        let checksum = header.get_u32();
+
+        let mut data = vec![0; length as usize];
+        if !read_full(file, &mut data)? {
+            break;
+        }
+        if crc32::checksum_castagnoli(&data) != checksum {
+            return Err(Error::Encryption("bad checksum of data key".to_string()));
+        }
+
+        let mut key = DataKey::decode(&data[..])?;
+        if !encryption_key.is_empty() {
+            encryption::xor_block(&mut key.data, encryption_key, &key.iv)?;
+        }
+        if key.key_id > core.next_key_id {
+            core.next_key_id = key.key_id;
+            core.last_created = key.created_at;
+        }
+        core.data_keys.insert(key.key_id, key);
+        offset += 8 + length as u64;
+    }
+
+    Ok(offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn test_options(dir: &Path, encryption_key: &[u8]) -> AgateOptions {
+        let mut opts = AgateOptions::default();
+        opts.dir = dir.to_path_buf();
+        opts.encryption_key = encryption_key.to_vec();
+        opts
+    }
+
+    #[test]
+    fn test_key_registry() {
+        let tmp_dir = tempdir().unwrap();
+        let master_key = encryption::random_bytes(32);
+        let opts = test_options(tmp_dir.path(), &master_key);
+
+        let registry = KeyRegistry::open(&opts).unwrap();
+        let key = registry.latest_data_key().unwrap().unwrap();
+        assert_eq!(key.key_id, 1);
+        assert_eq!(key.data.len(), 32);
+        // Key is not rotated until it's expired.
+        assert_eq!(registry.latest_data_key().unwrap().unwrap(), key);
+        assert_eq!(registry.data_key(1).unwrap().unwrap(), key);
+        assert!(registry.data_key(0).unwrap().is_none());
+        assert!(matches!(
+            registry.data_key(2),
+            Err(Error::InvalidDataKeyId(2))
+        ));
+        drop(registry);
+
+        // Data keys are persisted, and rotated once expired.
+        let mut opts = opts;
+        opts.encryption_key_rotation_duration = Duration::from_secs(0);
+        let registry = KeyRegistry::open(&opts).unwrap();
+        assert_eq!(registry.data_key(1).unwrap().unwrap(), key);
+        let new_key = registry.latest_data_key().unwrap().unwrap();
+        assert_eq!(new_key.key_id, 2);
+        assert_ne!(new_key.data, key.data);
+        drop(registry);
+
+        let registry = KeyRegistry::open(&opts).unwrap();
+        assert_eq!(registry.data_key(1).unwrap().unwrap(), key);
+        assert_eq!(registry.data_key(2).unwrap().unwrap(), new_key);
+        assert_eq!(registry.latest_data_key().unwrap().unwrap().key_id, 3);
+    }
+
+    #[test]
+    fn test_key_registry_mismatch() {
+        let tmp_dir = tempdir().unwrap();
+        let master_key = encryption::random_bytes(16);
+        let registry = KeyRegistry::open(&test_options(tmp_dir.path(), &master_key)).unwrap();
+        registry.latest_data_key().unwrap();
+        drop(registry);
+
+        for key in [encryption::random_bytes(16), vec![]] {
+            assert!(matches!(
+                KeyRegistry::open(&test_options(tmp_dir.path(), &key)),
+                Err(Error::EncryptionKeyMismatch)
+            ));
+        }
+
+        // Registry created without master key can't be opened with one.
+        let tmp_dir = tempdir().unwrap();
+        let registry = KeyRegistry::open(&test_options(tmp_dir.path(), &[])).unwrap();
+        assert!(registry.latest_data_key().unwrap().is_none());
+        drop(registry);
+        assert!(matches!(
+            KeyRegistry::open(&test_options(tmp_dir.path(), &master_key)),
+            Err(Error::EncryptionKeyMismatch)
+        ));
+    }
+}
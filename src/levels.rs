@@ -1,5 +1,856 @@
 mod compaction;
 mod handler;
 
-use compaction::KeyRange;
+use compaction::{
+    get_key_range, get_key_range_single, CompactDef, CompactStatus, CompactionPriority, KeyRange,
+    LevelCompactStatus, Targets,
+};
 use handler::LevelHandler;
+
+use crate::closer::Notifier;
+use crate::db::build_table_options;
+use crate::format::{get_ts, user_key};
+use crate::iterator::IteratorOptions;
+use crate::iterator_trait::AgateIterator;
+use crate::manifest::{new_create_change, new_delete_change, Manifest, ManifestFile};
+use crate::metrics::Metrics;
+use crate::ops::oracle::Oracle;
+use crate::opt::CompressionType;
+use crate::table::{self, ConcatIterator, MergeIterator, Table, TableIterators, ITERATOR_NOCACHE};
+use crate::util::is_deleted_or_expired;
+use crate::util::{KeyComparator, COMPARATOR};
+use crate::value::{
+    Value, ValuePointer, VALUE_DISCARD_EARLIER_VERSIONS, VALUE_MERGE_ENTRY, VALUE_POINTER,
+};
+use crate::{AgateOptions, Error, Result, TableBuilder};
+
+use bytes::{Bytes, BytesMut};
+use crossbeam_channel::{select, Receiver};
+use parking_lot::{Mutex, RwLock};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Statistics of a level in LSM tree.
+#[derive(Debug, Clone)]
+pub struct LevelInfo {
+    pub level: usize,
+    pub num_tables: usize,
+    /// Total size of tables in this level.
+    pub size: u64,
+    /// Target size of this level. Level 0 has no target size.
+    pub target_size: u64,
+    /// Target size of tables compacted into this level.
+    pub target_file_size: u64,
+    /// Whether level 0 is compacted into this level.
+    pub is_base_level: bool,
+    /// Compaction score. Level needs compaction if score is at least 1.
+    pub score: f64,
+    /// Compaction score adjusted by score of the next level.
+    pub adjusted: f64,
+}
+
+/// `LevelsController` manages all levels of the LSM tree.
+pub struct LevelsController {
+    pub(crate) levels: Vec<Arc<RwLock<LevelHandler>>>,
+    next_file_id: AtomicU64,
+    /// `cpt_status` tracks key ranges and tables being compacted, so that
+    /// compactors won't work on overlapping tables.
+    cpt_status: RwLock<CompactStatus>,
+    manifest: Arc<ManifestFile>,
+    /// Oracle of the database, which tells versions could be discarded.
+    orc: Arc<Oracle>,
+    /// Metrics of the database, which record compaction errors.
+    metrics: Arc<Metrics>,
+    /// Wakes up writers waiting for level 0 to be compacted.
+    notifier: Arc<Notifier>,
+    /// Error of the last failed compaction, which is returned to writers
+    /// waiting for level 0. Cleared once a compaction succeeds.
+    compaction_error: Mutex<Option<String>>,
+    opts: AgateOptions,
+}
+
+impl LevelsController {
+    /// Open all tables recorded in manifest and put them into levels.
+    pub fn new(
+        opts: AgateOptions,
+        manifest: Arc<ManifestFile>,
+        orc: Arc<Oracle>,
+        metrics: Arc<Metrics>,
+        notifier: Arc<Notifier>,
+    ) -> Result<Self> {
+        let mut handlers: Vec<LevelHandler> = (0..opts.max_levels)
+            .map(|level| LevelHandler::new(opts.clone(), level))
+            .collect();
+        let mut next_file_id = 1;
+
+        if !opts.in_memory {
+            let manifest_data = manifest.manifest();
+            revert_to_manifest(&opts.dir, &manifest_data, opts.remove_unknown_tables)?;
+
+            let table_opts = build_table_options(&opts);
+            for (id, tm) in manifest_data.tables.iter() {
+                let level = tm.level as usize;
+                if level >= handlers.len() {
+                    return Err(Error::InvalidManifest(format!(
+                        "table {} is at level {}, but max level is {}",
+                        id,
+                        level,
+                        handlers.len() - 1
+                    )));
+                }
+                // Tables are compressed and encrypted as recorded in
+                // manifest, which may differ from current options.
+                let mut table_opts = table_opts.clone();
+                table_opts.compression =
+                    CompressionType::from_u32(tm.compression).ok_or_else(|| {
+                        Error::InvalidManifest(format!(
+                            "table {} has unknown compression {}",
+                            id, tm.compression
+                        ))
+                    })?;
+                table_opts.data_key = opts.data_key(tm.key_id)?;
+                let table = Table::open(&table::new_filename(*id, &opts.dir), table_opts)?;
+                // Tables are pushed to handlers immediately, so that they will
+                // be retained on disk if any error occurs.
+                handlers[level].tables.push(table);
+            }
+            // IDs of deleted tables are not reused.
+            next_file_id = next_file_id.max(manifest_data.next_file_id);
+        }
+
+        let levels = handlers
+            .into_iter()
+            .map(|mut handler| {
+                let tables = std::mem::take(&mut handler.tables);
+                handler.init_tables(tables);
+                Arc::new(RwLock::new(handler))
+            })
+            .collect();
+
+        let cpt_status = CompactStatus {
+            levels: (0..opts.max_levels)
+                .map(|_| LevelCompactStatus::default())
+                .collect(),
+            tables: HashSet::new(),
+        };
+
+        Ok(Self {
+            levels,
+            next_file_id: AtomicU64::new(next_file_id),
+            cpt_status: RwLock::new(cpt_status),
+            manifest,
+            orc,
+            metrics,
+            notifier,
+            compaction_error: Mutex::new(None),
+            opts,
+        })
+    }
+
+    pub fn manifest(&self) -> &ManifestFile {
+        &self.manifest
+    }
+
+    /// Get error of the last failed compaction, if no compaction has
+    /// succeeded since then.
+    pub(crate) fn compaction_error(&self) -> Option<String> {
+        self.compaction_error.lock().clone()
+    }
+
+    pub fn num_level_zero_tables(&self) -> usize {
+        self.levels[0].read().num_tables()
+    }
+
+    /// Max version of all tables in LSM tree.
+    pub fn max_version(&self) -> u64 {
+        let mut max_version = 0;
+        for level in &self.levels {
+            for table in &level.read().tables {
+                max_version = max_version.max(table.max_version());
+            }
+        }
+        max_version
+    }
+
+    /// Append iterators of all levels to `iters`, from level 0 to the last
+    /// level.
+    pub(crate) fn append_iterators(&self, iters: &mut Vec<TableIterators>, opts: &IteratorOptions) {
+        for level in &self.levels {
+            level.read().append_iterators(iters, opts);
+        }
+    }
+
+    /// Allocate an id for a new SST.
+    pub fn reserve_file_id(&self) -> u64 {
+        self.next_file_id.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// Add a table flushed from memtable to level 0.
+    ///
+    /// The table is recorded in manifest first. If there are too many tables
+    /// in level 0, this function will block until compaction makes room for it,
+    /// or returns `Error::DBClosed` once `closed` is ready.
+    pub fn add_l0_table(&self, table: Table, closed: &Receiver<()>) -> Result<()> {
+        self.manifest.add_changes(vec![new_create_change(
+            table.id(),
+            0,
+            table.key_id(),
+            table.compression() as u32,
+        )])?;
+        while !self.levels[0].write().try_add_l0_table(table.clone()) {
+            select! {
+                recv(closed) -> _ => return Err(Error::DBClosed),
+                default(Duration::from_millis(10)) => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Searches for `key` in all levels of the LSM tree.
+    ///
+    /// `max_value` is the value with the highest version found in memtables.
+    /// Returns the value with the highest version that is less than or equal
+    /// to the version of `key`.
+    pub fn get(&self, key: &Bytes, mut max_value: Option<Value>) -> Result<Option<Value>> {
+        let version = get_ts(key);
+
+        for handler in self.levels.iter() {
+            let value = match handler.read().get(key)? {
+                Some(value) => value,
+                None => continue,
+            };
+            if value.version == version {
+                return Ok(Some(value));
+            }
+            if max_value.as_ref().map(|v| v.version) < Some(value.version) {
+                max_value = Some(value);
+            }
+        }
+
+        Ok(max_value)
+    }
+
+    /// Compute target size of each level from the size of the last level.
+    ///
+    /// Target size of the last level is its actual size, and target size of
+    /// each upper level is `level_size_multiplier` times smaller, but no
+    /// smaller than `base_level_size`. The base level, which level 0 is
+    /// compacted into, is the highest level whose target size fits in
+    /// `base_level_size`. So a small database compacts level 0 directly into
+    /// the lower levels, skipping the empty levels in between.
+    pub(crate) fn level_targets(&self) -> Targets {
+        let num_levels = self.levels.len();
+        let adjust = |size: u64| size.max(self.opts.base_level_size);
+
+        let mut targets = Targets::new();
+        targets.target_size = vec![0; num_levels];
+        targets.file_size = vec![0; num_levels];
+
+        let mut db_size = self.levels[num_levels - 1].read().total_size;
+        for level in (1..num_levels).rev() {
+            let target = adjust(db_size);
+            targets.target_size[level] = target;
+            if targets.base_level == 0 && target <= self.opts.base_level_size {
+                targets.base_level = level;
+            }
+            db_size /= self.opts.level_size_multiplier as u64;
+        }
+
+        let mut table_size = self.opts.base_table_size;
+        for level in 0..num_levels {
+            if level == 0 {
+                // Level 0 tables are flushed from memtables.
+                targets.file_size[level] = self.opts.mem_table_size;
+            } else if level <= targets.base_level {
+                targets.file_size[level] = table_size;
+            } else {
+                table_size *= self.opts.table_size_multiplier as u64;
+                targets.file_size[level] = table_size;
+            }
+        }
+
+        // Bring the base level down to the last empty level.
+        for level in targets.base_level + 1..num_levels - 1 {
+            if self.levels[level].read().total_size > 0 {
+                break;
+            }
+            targets.base_level = level;
+        }
+
+        // If the base level is empty and the next level is below its target,
+        // pick the next level as base level.
+        let base = targets.base_level;
+        if base < num_levels - 1
+            && self.levels[base].read().total_size == 0
+            && self.levels[base + 1].read().total_size < targets.target_size[base + 1]
+        {
+            targets.base_level += 1;
+        }
+
+        targets
+    }
+
+    /// Compute compaction priorities of all levels except the last one.
+    fn compute_priorities(&self, targets: &Targets) -> Vec<CompactionPriority> {
+        // Size of tables being compacted shouldn't be counted.
+        let del_sizes: Vec<u64> = self
+            .cpt_status
+            .read()
+            .levels
+            .iter()
+            .map(|l| l.del_size)
+            .collect();
+        // The last level can't be compacted to a lower level.
+        let num_levels = self.levels.len() - 1;
+
+        let mut prios: Vec<CompactionPriority> = (0..num_levels)
+            .map(|level| {
+                let score = if level == 0 {
+                    self.levels[0].read().num_tables() as f64
+                        / self.opts.num_level_zero_tables as f64
+                } else {
+                    let size = self.levels[level]
+                        .read()
+                        .total_size
+                        .saturating_sub(del_sizes[level]);
+                    size as f64 / targets.target_size[level] as f64
+                };
+                CompactionPriority {
+                    level,
+                    score,
+                    adjusted: score,
+                    drop_prefixes: vec![],
+                    targets: targets.clone(),
+                }
+            })
+            .collect();
+
+        // If the next level is also over its target, compacting into it will
+        // make things worse. Divide the score by the score of next level, so
+        // that the next level will be compacted first.
+        let mut prev_level = 0;
+        for level in targets.base_level..num_levels {
+            if prios[prev_level].adjusted >= 1.0 {
+                const MIN_SCORE: f64 = 0.01;
+                if prios[level].score >= MIN_SCORE {
+                    prios[prev_level].adjusted /= prios[level].adjusted;
+                } else {
+                    prios[prev_level].adjusted /= MIN_SCORE;
+                }
+            }
+            prev_level = level;
+        }
+
+        prios
+    }
+
+    /// Return levels which need compaction, sorted by adjusted score in
+    /// descending order.
+    pub(crate) fn pick_compact_levels(&self) -> Vec<CompactionPriority> {
+        let targets = self.level_targets();
+        let mut prios: Vec<CompactionPriority> = self
+            .compute_priorities(&targets)
+            .into_iter()
+            .filter(|p| p.score >= 1.0)
+            .collect();
+        prios.sort_by(|x, y| y.adjusted.partial_cmp(&x.adjusted).unwrap());
+        prios
+    }
+
+    /// Get statistics of all levels.
+    pub fn level_info(&self) -> Vec<LevelInfo> {
+        let targets = self.level_targets();
+        let mut result: Vec<LevelInfo> = self
+            .levels
+            .iter()
+            .enumerate()
+            .map(|(level, handler)| {
+                let handler = handler.read();
+                LevelInfo {
+                    level,
+                    num_tables: handler.num_tables(),
+                    size: handler.total_size,
+                    target_size: targets.target_size[level],
+                    target_file_size: targets.file_size[level],
+                    is_base_level: targets.base_level == level,
+                    score: 0.0,
+                    adjusted: 0.0,
+                }
+            })
+            .collect();
+        for prio in self.compute_priorities(&targets) {
+            result[prio.level].score = prio.score;
+            result[prio.level].adjusted = prio.adjusted;
+        }
+        result
+    }
+
+    /// Run compactor until `closed` is ready.
+    pub(crate) fn run_compactor(&self, id: usize, closed: Receiver<()>) {
+        loop {
+            select! {
+                recv(closed) -> _ => return,
+                default(Duration::from_millis(50)) => {}
+            }
+            self.run_compactor_once(id);
+        }
+    }
+
+    /// Pick levels and try to compact one of them. Returns `true` if a
+    /// compaction has been done.
+    pub(crate) fn run_compactor_once(&self, id: usize) -> bool {
+        let mut prios = self.pick_compact_levels();
+        if id == 0 {
+            // Compactor 0 always works on level 0 first, so that flushes
+            // won't be blocked for a long time.
+            if let Some(pos) = prios.iter().position(|p| p.level == 0) {
+                let prio = prios.remove(pos);
+                prios.insert(0, prio);
+            }
+        }
+
+        for prio in prios {
+            if id == 0 && prio.level == 0 {
+                // Compactor 0 compacts level 0 irrespective of its adjusted score.
+            } else if prio.adjusted < 1.0 {
+                break;
+            }
+            match self.do_compact(id, prio) {
+                Ok(true) => {
+                    *self.compaction_error.lock() = None;
+                    self.notifier.notify();
+                    return true;
+                }
+                // Tables may be being compacted by other compactors. Just try
+                // next level.
+                Ok(false) => {}
+                Err(e) => {
+                    self.metrics.record_compaction_error(&e);
+                    *self.compaction_error.lock() = Some(e.to_string());
+                    self.notifier.notify();
+                }
+            }
+        }
+        false
+    }
+
+    /// Pick tables from level `prio.level` and compact them to the next level.
+    /// Returns `false` if no tables could be picked.
+    fn do_compact(&self, id: usize, prio: CompactionPriority) -> Result<bool> {
+        let this_level_id = prio.level;
+        assert!(this_level_id + 1 < self.levels.len());
+        let targets = prio.targets.clone();
+        let next_level_id = if this_level_id == 0 {
+            targets.base_level
+        } else {
+            this_level_id + 1
+        };
+
+        let mut cd = CompactDef::new(
+            id,
+            self.levels[this_level_id].clone(),
+            this_level_id,
+            self.levels[next_level_id].clone(),
+            next_level_id,
+            prio,
+            targets,
+        );
+
+        let filled = if this_level_id == 0 {
+            self.fill_tables_l0(&mut cd)
+        } else {
+            self.fill_tables(&mut cd)
+        };
+        if !filled {
+            return Ok(false);
+        }
+
+        let result = self.run_compact_def(&cd);
+        self.cpt_status.write().delete(&cd);
+        result.map(|_| true)
+    }
+
+    /// Pick overlapping tables from level 0, starting from the oldest one,
+    /// together with tables overlapping with them in base level.
+    fn fill_tables_l0(&self, cd: &mut CompactDef) -> bool {
+        let this_level = cd.this_level.read();
+        let next_level = cd.next_level.read();
+
+        if this_level.tables.is_empty() {
+            return false;
+        }
+
+        let mut top = vec![this_level.tables[0].clone()];
+        let mut kr = get_key_range_single(&top[0]);
+        for table in this_level.tables[1..].iter() {
+            let dkr = get_key_range_single(table);
+            if !kr.overlaps_with(&dkr) {
+                break;
+            }
+            top.push(table.clone());
+            kr = kr.extend(&dkr);
+        }
+
+        cd.this_range = kr;
+        cd.this_size = top.iter().map(|t| t.size()).sum();
+        cd.top = top;
+
+        let (left, right) = next_level.overlapping_tables(&cd.this_range);
+        cd.bot = next_level.tables[left..right].to_vec();
+        cd.next_range = match get_key_range(&cd.bot) {
+            Some(kr) => kr,
+            None => cd.this_range.clone(),
+        };
+
+        self.cpt_status.write().compare_and_add(cd).is_ok()
+    }
+
+    /// Pick a table which is not being compacted from level `cd.this_level_id`,
+    /// together with tables overlapping with it in the next level.
+    fn fill_tables(&self, cd: &mut CompactDef) -> bool {
+        let this_level = cd.this_level.read();
+        let next_level = cd.next_level.read();
+        let mut cpt_status = self.cpt_status.write();
+
+        // Compact older tables first, as they are more likely to contain
+        // stale versions.
+        let mut tables = this_level.tables.clone();
+        tables.sort_by_key(|t| t.max_version());
+
+        for table in tables {
+            cd.this_size = table.size();
+            cd.this_range = get_key_range_single(&table);
+            if cpt_status.overlaps_with(cd.this_level_id, &cd.this_range) {
+                continue;
+            }
+            cd.top = vec![table];
+
+            let (left, right) = next_level.overlapping_tables(&cd.this_range);
+            cd.bot = next_level.tables[left..right].to_vec();
+            cd.next_range = match get_key_range(&cd.bot) {
+                Some(kr) => kr,
+                None => cd.this_range.clone(),
+            };
+            if !cd.bot.is_empty() && cpt_status.overlaps_with(cd.next_level_id, &cd.next_range) {
+                continue;
+            }
+            if cpt_status.compare_and_add(cd).is_err() {
+                continue;
+            }
+            return true;
+        }
+        false
+    }
+
+    /// Merge tables in `cd`, and install the new tables to the next level.
+    fn run_compact_def(&self, cd: &CompactDef) -> Result<()> {
+        let (new_tables, discarded) = self.compact_build_tables(cd)?;
+
+        let mut changes = vec![];
+        for table in new_tables.iter() {
+            changes.push(new_create_change(
+                table.id(),
+                cd.next_level_id,
+                table.key_id(),
+                table.compression() as u32,
+            ));
+        }
+        for table in cd.top.iter().chain(cd.bot.iter()) {
+            changes.push(new_delete_change(table.id()));
+        }
+        self.manifest.add_changes(changes)?;
+
+        // Add new tables to the next level before removing old tables from
+        // this level, so that readers can always find the keys.
+        cd.next_level.write().replace_tables(&cd.bot, &new_tables)?;
+        cd.this_level.write().delete_tables(&cd.top)?;
+
+        // Only count discarded values once the compaction is committed.
+        if let Some(stats) = &self.opts.discard_stats {
+            stats.update(&discarded)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns `true` if key range of `tables` overlaps with any level at
+    /// or below `level`.
+    fn check_overlap(&self, tables: &[Table], level: usize) -> bool {
+        let kr = match get_key_range(tables) {
+            Some(kr) => kr,
+            None => return false,
+        };
+        self.levels[level.min(self.levels.len())..]
+            .iter()
+            .any(|handler| {
+                let (left, right) = handler.read().overlapping_tables(&kr);
+                right > left
+            })
+    }
+
+    /// Merge all tables in `cd` and split them into new tables of
+    /// `file_size` of the next level.
+    ///
+    /// Versions at or below the discard timestamp of oracle are invisible to
+    /// all transactions except the latest `num_versions_to_keep` ones, so
+    /// older versions are dropped. Value log space of dropped values is
+    /// returned along with the new tables.
+    fn compact_build_tables(&self, cd: &CompactDef) -> Result<(Vec<Table>, HashMap<u32, u64>)> {
+        let discard_ts = self.orc.discard_at_or_below();
+        // Deleted keys could only be dropped if no older versions exist in
+        // lower levels.
+        let has_overlap = self.check_overlap(&cd.all_tables(), cd.next_level_id + 1);
+
+        // Tables compacted are going to be removed, so their blocks are not
+        // cached.
+        let mut iters: Vec<Box<TableIterators>> = vec![];
+        if cd.this_level_id == 0 {
+            // Newer tables should come first.
+            for table in cd.top.iter().rev() {
+                iters.push(Box::new(TableIterators::from(
+                    table.new_iterator(ITERATOR_NOCACHE),
+                )));
+            }
+        } else {
+            iters.push(Box::new(TableIterators::from(ConcatIterator::from_tables(
+                cd.top.clone(),
+                ITERATOR_NOCACHE,
+            ))));
+        }
+        if !cd.bot.is_empty() {
+            iters.push(Box::new(TableIterators::from(ConcatIterator::from_tables(
+                cd.bot.clone(),
+                ITERATOR_NOCACHE,
+            ))));
+        }
+        let mut it = MergeIterator::from_iterators(iters, false);
+        it.rewind();
+
+        let mut table_opts = build_table_options(&self.opts);
+        table_opts.table_size = cd.targets.file_size[cd.next_level_id];
+        table_opts.compression = self.opts.compression_of_level(cd.next_level_id);
+        table_opts.data_key = self.opts.latest_data_key()?;
+
+        let mut new_tables = vec![];
+        // All remaining versions of `skip_key` should be dropped.
+        let mut skip_key = BytesMut::new();
+        // Bytes of values dropped by this compaction, by value log id.
+        let mut discarded = HashMap::new();
+        while it.valid() {
+            let mut builder = TableBuilder::new(table_opts.clone());
+            let mut last_key = BytesMut::new();
+            let mut num_versions = 0;
+            let mut first_key_has_discard_set = false;
+
+            while it.valid() {
+                let key = it.key();
+                if !skip_key.is_empty() {
+                    if user_key(key) == user_key(&skip_key) {
+                        update_discard_stats(&mut discarded, &it.value())?;
+                        it.next();
+                        continue;
+                    }
+                    skip_key.clear();
+                }
+
+                let value = it.value();
+                // Versions of the same key should be kept in the same table.
+                if last_key.is_empty() || user_key(key) != user_key(&last_key) {
+                    if !last_key.is_empty() && builder.reach_capacity(table_opts.table_size) {
+                        break;
+                    }
+                    last_key.clear();
+                    last_key.extend_from_slice(key);
+                    num_versions = 0;
+                    first_key_has_discard_set = value.meta & VALUE_DISCARD_EARLIER_VERSIONS != 0;
+                }
+
+                if get_ts(key) <= discard_ts && value.meta & VALUE_MERGE_ENTRY == 0 {
+                    num_versions += 1;
+                    let last_valid_version = value.meta & VALUE_DISCARD_EARLIER_VERSIONS != 0
+                        || num_versions == self.opts.num_versions_to_keep;
+                    let is_expired = is_deleted_or_expired(value.meta, value.expires_at);
+                    if is_expired || last_valid_version {
+                        // Older versions of this key are invisible.
+                        skip_key.clear();
+                        skip_key.extend_from_slice(key);
+                        let keep = first_key_has_discard_set
+                            || (last_valid_version && !is_expired)
+                            || has_overlap;
+                        if !keep {
+                            update_discard_stats(&mut discarded, &value)?;
+                            it.next();
+                            continue;
+                        }
+                    }
+                }
+
+                let vlog_len = if value.meta & VALUE_POINTER != 0 {
+                    let mut vptr = ValuePointer::default();
+                    vptr.decode(&value.value)?;
+                    vptr.len
+                } else {
+                    0
+                };
+                builder.add(&Bytes::copy_from_slice(key), value, vlog_len)?;
+                it.next();
+            }
+
+            if builder.is_empty() {
+                continue;
+            }
+            let file_id = self.reserve_file_id();
+            let data = builder.finish()?;
+            let table = if self.opts.in_memory {
+                Table::open_in_memory(data, file_id, table_opts.clone())?
+            } else {
+                Table::create(
+                    &table::new_filename(file_id, &self.opts.dir),
+                    data,
+                    table_opts.clone(),
+                )?
+            };
+            new_tables.push(table);
+        }
+
+        new_tables.sort_by(|x, y| COMPARATOR.compare_key(x.smallest(), y.smallest()));
+        Ok((new_tables, discarded))
+    }
+}
+
+/// Record the value log space of a dropped `value` as discarded.
+fn update_discard_stats(discarded: &mut HashMap<u32, u64>, value: &Value) -> Result<()> {
+    if value.meta & VALUE_POINTER != 0 {
+        let mut vptr = ValuePointer::default();
+        vptr.decode(&value.value)?;
+        *discarded.entry(vptr.file_id).or_insert(0) += vptr.len;
+    }
+    Ok(())
+}
+
+/// Check that all tables in manifest exist on disk, and SST files on disk
+/// are all recorded in manifest.
+///
+/// SST files left by a crash, i.e. created but not yet recorded, or deleted
+/// but not yet removed, are removed. Other unknown SST files are removed
+/// only if `remove_unknown` is set.
+fn revert_to_manifest(dir: &Path, manifest: &Manifest, remove_unknown: bool) -> Result<()> {
+    let mut ids = HashSet::new();
+    for file in fs::read_dir(dir)? {
+        let filename = file?.file_name();
+        let filename = filename.to_string_lossy();
+        if filename.ends_with(".sst") {
+            ids.insert(table::parse_file_id(&filename)?);
+        }
+    }
+
+    for id in manifest.tables.keys() {
+        if !ids.contains(id) {
+            return Err(Error::TableNotFound(*id));
+        }
+    }
+
+    for id in ids {
+        if manifest.tables.contains_key(&id) {
+            continue;
+        }
+        let left_by_crash = id >= manifest.next_file_id || manifest.deleted.contains(&id);
+        if !left_by_crash && !remove_unknown {
+            return Err(Error::InvalidManifest(format!(
+                "table {} is not recorded in manifest",
+                id
+            )));
+        }
+        fs::remove_file(table::new_filename(id, dir))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_levels_controller() -> LevelsController {
+        let mut opts = AgateOptions::default();
+        opts.in_memory = true;
+        let manifest = ManifestFile::open_or_create_manifest_file(&opts).unwrap();
+        let orc = Arc::new(Oracle::new(&opts));
+        let metrics = Arc::new(Metrics::default());
+        let notifier = Arc::new(Notifier::default());
+        LevelsController::new(opts, Arc::new(manifest), orc, metrics, notifier).unwrap()
+    }
+
+    #[test]
+    fn test_level_targets() {
+        let lvctl = new_levels_controller();
+        let base_level_size = lvctl.opts.base_level_size;
+        let base_table_size = lvctl.opts.base_table_size;
+
+        // Empty database compacts level 0 into the last level.
+        let targets = lvctl.level_targets();
+        assert_eq!(targets.base_level, 6);
+        assert!(targets.target_size[1..]
+            .iter()
+            .all(|s| *s == base_level_size));
+        assert!(targets.file_size[1..].iter().all(|s| *s == base_table_size));
+
+        lvctl.levels[6].write().total_size = base_level_size * 100;
+        let targets = lvctl.level_targets();
+        assert_eq!(
+            targets.target_size[4..],
+            [base_level_size, base_level_size * 10, base_level_size * 100]
+        );
+        // Level 5 is empty, so it is used as base level.
+        assert_eq!(targets.base_level, 5);
+
+        // Level 5 is below its target, so level 0 is still compacted into it.
+        lvctl.levels[5].write().total_size = base_level_size;
+        assert_eq!(lvctl.level_targets().base_level, 5);
+
+        lvctl.levels[5].write().total_size = base_level_size * 20;
+        let targets = lvctl.level_targets();
+        assert_eq!(targets.base_level, 4);
+        assert_eq!(
+            targets.file_size[4..],
+            [base_table_size, base_table_size * 2, base_table_size * 4]
+        );
+
+        let info = lvctl.level_info();
+        assert!(info[4].is_base_level);
+        assert_eq!(info[5].size, base_level_size * 20);
+        assert_eq!(info[5].target_size, base_level_size * 10);
+        assert!((info[5].score - 2.0).abs() < 1e-9);
+        assert_eq!(lvctl.pick_compact_levels()[0].level, 5);
+    }
+
+    #[test]
+    fn test_add_l0_table_closed() {
+        let lvctl = new_levels_controller();
+        let new_table = || {
+            let table_opts = build_table_options(&lvctl.opts);
+            let mut builder = TableBuilder::new(table_opts.clone());
+            let key = crate::format::key_with_ts(&b"key"[..], 1);
+            builder
+                .add(&key, Value::new(Bytes::from("value")), 0)
+                .unwrap();
+            let data = builder.finish().unwrap();
+            Table::open_in_memory(data, lvctl.reserve_file_id(), table_opts).unwrap()
+        };
+
+        let (tx, closed) = crossbeam_channel::bounded(0);
+        for _ in 0..lvctl.opts.num_level_zero_tables_stall {
+            lvctl.add_l0_table(new_table(), &closed).unwrap();
+        }
+        drop(tx);
+        // Level 0 is full and no compactor is running.
+        assert!(matches!(
+            lvctl.add_l0_table(new_table(), &closed),
+            Err(Error::DBClosed)
+        ));
+    }
+}
@@ -2,11 +2,16 @@
 #![allow(unused_variables)]
 
 use super::KeyRange;
+use crate::format::{get_ts, user_key};
+use crate::iterator::IteratorOptions;
+use crate::table::{ConcatIterator, TableIterators, ITERATOR_NOCACHE, ITERATOR_REVERSED};
+use crate::util::{self, KeyComparator, COMPARATOR};
 use crate::value::Value;
 use crate::Result;
-use crate::{iterator::IteratorOptions, table::TableIterators};
-use crate::{AgateOptions, Table};
+use crate::{AgateIterator, AgateOptions, Table};
 use bytes::Bytes;
+use std::cmp::Ordering;
+use std::collections::HashSet;
 
 pub struct LevelHandler {
     opts: AgateOptions,
@@ -35,39 +40,272 @@ impl LevelHandler {
         }
     }
 
+    /// Add a newly flushed table to level 0. Returns `false` if there are
+    /// too many tables in level 0, and caller should retry later.
     pub fn try_add_l0_table(&mut self, table: Table) -> bool {
-        unimplemented!()
+        assert_eq!(self.level, 0);
+        if self.opts.level_zero_stalls()
+            && self.tables.len() >= self.opts.num_level_zero_tables_stall
+        {
+            return false;
+        }
+        self.total_size += table.size();
+        self.tables.push(table);
+        true
     }
 
     pub fn num_tables(&self) -> usize {
         self.tables.len()
     }
 
+    /// Returns tables that may contain `key`.
+    ///
+    /// For level 0, tables may overlap with each other, so all tables are
+    /// returned, newest first. For other levels, at most one table is returned.
     pub fn get_table_for_key(&self, key: &Bytes) -> Vec<Table> {
-        unimplemented!()
+        if self.level == 0 {
+            return self.tables.iter().rev().cloned().collect();
+        }
+
+        let idx = util::search(self.tables.len(), |idx| {
+            COMPARATOR.compare_key(self.tables[idx].biggest(), key) != Ordering::Less
+        });
+        if idx >= self.tables.len() {
+            return vec![];
+        }
+        let table = &self.tables[idx];
+        // Versions of the same key may have a larger timestamp than the smallest
+        // key in table, so only user keys are compared here.
+        if user_key(table.smallest()) > user_key(key) {
+            return vec![];
+        }
+        vec![table.clone()]
     }
 
-    pub fn get(&self, key: &Bytes) -> Result<Value> {
-        unimplemented!()
+    /// Returns the value with the highest version that is less than or equal
+    /// to the version of `key`, or `None` if key is not found in this level.
+    pub fn get(&self, key: &Bytes) -> Result<Option<Value>> {
+        let tables = self.get_table_for_key(key);
+        let hash = farmhash::fingerprint32(user_key(key));
+        let mut max_value: Option<Value> = None;
+
+        for table in tables {
+            if table.does_not_have(hash)? {
+                continue;
+            }
+
+            let mut it = table.new_iterator(0);
+            it.seek(key);
+            if !it.valid() || !util::same_key(key, it.key()) {
+                continue;
+            }
+
+            let version = get_ts(it.key());
+            if max_value.as_ref().map(|v| v.version) < Some(version) {
+                let mut value = it.value();
+                value.version = version;
+                max_value = Some(value);
+            }
+        }
+
+        Ok(max_value)
     }
 
+    /// Returns the half-open interval of tables overlapping with `kr`.
+    /// Tables should be sorted by key range, so this is not used for level 0.
     pub fn overlapping_tables(&self, kr: &KeyRange) -> (usize, usize) {
-        unimplemented!()
+        let (left, right) = match kr {
+            KeyRange::Empty => return (0, 0),
+            KeyRange::Inf => return (0, self.tables.len()),
+            KeyRange::Range { left, right } => (left, right),
+        };
+        let left_idx = util::search(self.tables.len(), |i| {
+            COMPARATOR.compare_key(left, self.tables[i].biggest()) != Ordering::Greater
+        });
+        let right_idx = util::search(self.tables.len(), |i| {
+            COMPARATOR.compare_key(right, self.tables[i].smallest()) == Ordering::Less
+        });
+        (left_idx, right_idx)
     }
 
+    /// Remove tables in `to_del` and add tables in `to_add`. This is used
+    /// when compacting tables into this level, so tables are sorted by key
+    /// range afterwards.
     pub fn replace_tables(&mut self, to_del: &[Table], to_add: &[Table]) -> Result<()> {
-        unimplemented!()
+        let to_del: HashSet<u64> = to_del.iter().map(|t| t.id()).collect();
+        let mut tables = Vec::with_capacity(self.tables.len() + to_add.len());
+        for table in self.tables.drain(..) {
+            if to_del.contains(&table.id()) {
+                self.total_size -= table.size();
+            } else {
+                tables.push(table);
+            }
+        }
+        for table in to_add {
+            self.total_size += table.size();
+            tables.push(table.clone());
+        }
+        tables.sort_by(|x, y| COMPARATOR.compare_key(x.smallest(), y.smallest()));
+        self.tables = tables;
+        Ok(())
     }
 
+    /// Remove tables in `to_del`. Order of remaining tables is kept.
     pub fn delete_tables(&mut self, to_del: &[Table]) -> Result<()> {
-        unimplemented!()
+        let to_del: HashSet<u64> = to_del.iter().map(|t| t.id()).collect();
+        let mut tables = Vec::with_capacity(self.tables.len());
+        for table in self.tables.drain(..) {
+            if to_del.contains(&table.id()) {
+                self.total_size -= table.size();
+            } else {
+                tables.push(table);
+            }
+        }
+        self.tables = tables;
+        Ok(())
     }
 
+    /// Replace all tables in current level. Tables will be sorted by id
+    /// in level 0, and by key range in other levels.
     pub fn init_tables(&mut self, tables: Vec<Table>) {
-        unimplemented!()
+        self.total_size = tables.iter().map(|t| t.size()).sum();
+        self.tables = tables;
+
+        if self.level == 0 {
+            // Key range of tables in level 0 will overlap. Sort tables by id
+            // so that newer tables come later.
+            self.tables.sort_by_key(|t| t.id());
+        } else {
+            self.tables
+                .sort_by(|x, y| COMPARATOR.compare_key(x.smallest(), y.smallest()));
+        }
     }
 
+    /// Append iterators of tables in this level to `iters`, newer tables
+    /// first.
     pub(crate) fn append_iterators(&self, iters: &mut Vec<TableIterators>, opts: &IteratorOptions) {
-        unimplemented!()
+        let mut opt = if opts.reverse { ITERATOR_REVERSED } else { 0 };
+        if !opts.fill_cache {
+            opt |= ITERATOR_NOCACHE;
+        }
+        if self.level == 0 {
+            // Tables in level 0 are sorted by id, and newer tables have
+            // bigger ids.
+            for table in self.tables.iter().rev() {
+                if opts.pick_table(table) {
+                    let mut iter = table.new_iterator(opt);
+                    iter.set_bounds(&opts.lower_bound, &opts.upper_bound);
+                    iters.push(TableIterators::from(iter));
+                }
+            }
+            return;
+        }
+
+        let mut tables = self.tables.clone();
+        opts.pick_tables(&mut tables);
+        if !tables.is_empty() {
+            let mut iter = ConcatIterator::from_tables(tables, opt);
+            iter.set_bounds(opts.lower_bound.clone(), opts.upper_bound.clone());
+            iters.push(TableIterators::from(iter));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::key_with_ts;
+    use crate::table::tests::get_test_table_options;
+    use bytes::BytesMut;
+
+    fn build_table(id: u64, kvs: Vec<(&'static str, u64, &'static str)>) -> Table {
+        let opts = get_test_table_options();
+        let mut builder = crate::TableBuilder::new(opts.clone());
+        for (k, ts, v) in kvs {
+            builder
+                .add(
+                    &key_with_ts(BytesMut::from(k), ts),
+                    Value::new(Bytes::from(v)),
+                    0,
+                )
+                .unwrap();
+        }
+        Table::open_in_memory(builder.finish().unwrap(), id, opts).unwrap()
+    }
+
+    fn get(handler: &LevelHandler, key: &'static str, ts: u64) -> Option<(Bytes, u64)> {
+        handler
+            .get(&key_with_ts(BytesMut::from(key), ts))
+            .unwrap()
+            .map(|v| (v.value, v.version))
+    }
+
+    #[test]
+    fn test_level0_get() {
+        let mut handler = LevelHandler::new(AgateOptions::default(), 0);
+        handler.init_tables(vec![
+            build_table(2, vec![("a", 3, "a3"), ("b", 2, "b2")]),
+            build_table(1, vec![("a", 1, "a1"), ("c", 1, "c1")]),
+        ]);
+
+        assert_eq!(get(&handler, "a", 5), Some((Bytes::from("a3"), 3)));
+        assert_eq!(get(&handler, "a", 2), Some((Bytes::from("a1"), 1)));
+        assert_eq!(get(&handler, "b", 1), None);
+        assert_eq!(get(&handler, "c", 1), Some((Bytes::from("c1"), 1)));
+        assert_eq!(get(&handler, "d", 1), None);
+    }
+
+    #[test]
+    fn test_level_get() {
+        let mut handler = LevelHandler::new(AgateOptions::default(), 1);
+        handler.init_tables(vec![
+            build_table(2, vec![("d", 2, "d2"), ("d", 1, "d1"), ("f", 1, "f1")]),
+            build_table(1, vec![("a", 1, "a1"), ("c", 1, "c1")]),
+        ]);
+        assert_eq!(handler.tables[0].id(), 1);
+
+        assert_eq!(get(&handler, "a", 1), Some((Bytes::from("a1"), 1)));
+        assert_eq!(get(&handler, "b", 1), None);
+        assert_eq!(get(&handler, "d", 5), Some((Bytes::from("d2"), 2)));
+        assert_eq!(get(&handler, "d", 1), Some((Bytes::from("d1"), 1)));
+        assert_eq!(get(&handler, "e", 1), None);
+        assert_eq!(get(&handler, "g", 1), None);
+    }
+
+    #[test]
+    fn test_overlapping_and_replace_tables() {
+        use crate::format::{key_with_ts_first, key_with_ts_last};
+
+        let range = |left: &'static str, right: &'static str| {
+            KeyRange::new(
+                key_with_ts_first(BytesMut::from(left)),
+                key_with_ts_last(BytesMut::from(right)),
+            )
+        };
+
+        let mut handler = LevelHandler::new(AgateOptions::default(), 1);
+        handler.init_tables(vec![
+            build_table(1, vec![("a", 1, "a1"), ("c", 1, "c1")]),
+            build_table(2, vec![("e", 1, "e1"), ("g", 1, "g1")]),
+            build_table(3, vec![("i", 1, "i1"), ("k", 1, "k1")]),
+        ]);
+        assert_eq!(handler.overlapping_tables(&range("b", "f")), (0, 2));
+        assert_eq!(handler.overlapping_tables(&range("d", "d")), (1, 1));
+        assert_eq!(handler.overlapping_tables(&range("h", "z")), (2, 3));
+        assert_eq!(handler.overlapping_tables(&KeyRange::Inf), (0, 3));
+        assert_eq!(handler.overlapping_tables(&KeyRange::Empty), (0, 0));
+
+        let to_del = handler.tables[0..2].to_vec();
+        let to_add = vec![build_table(4, vec![("b", 1, "b1"), ("g", 1, "g1")])];
+        handler.replace_tables(&to_del, &to_add).unwrap();
+        let ids: Vec<u64> = handler.tables.iter().map(|t| t.id()).collect();
+        assert_eq!(ids, vec![4, 3]);
+        let total_size: u64 = handler.tables.iter().map(|t| t.size()).sum();
+        assert_eq!(handler.total_size, total_size);
+
+        let to_del = handler.tables[1..].to_vec();
+        handler.delete_tables(&to_del).unwrap();
+        assert_eq!(handler.num_tables(), 1);
+        assert_eq!(handler.total_size, handler.tables[0].size());
     }
 }
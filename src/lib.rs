@@ -1,15 +1,23 @@
 #![allow(dead_code)]
 
 mod bloom;
+mod cache;
 mod checksum;
+mod closer;
+mod compression;
 mod db;
+mod discard;
+mod encryption;
 mod entry;
 mod error;
 mod format;
 mod iterator;
 mod iterator_trait;
+mod key_registry;
 mod levels;
+mod manifest;
 mod memtable;
+mod metrics;
 mod ops;
 mod opt;
 mod table;
@@ -17,15 +25,23 @@ mod util;
 mod value;
 mod value_log;
 mod wal;
+mod watermark;
 
+pub use cache::{BlockCache, IndexCache};
 pub use format::{get_ts, key_with_ts};
-pub use opt::ChecksumVerificationMode;
 pub use opt::Options as TableOptions;
+pub use opt::PrefixExtractor;
+pub use opt::{ChecksumVerificationMode, CompressionType};
 pub use table::builder::Builder as TableBuilder;
 pub use table::Table;
 pub use value::Value;
 
 pub use db::{Agate, AgateOptions};
 pub use error::{Error, Result};
+pub use iterator::{Item, Iterator, IteratorOptions};
 pub use iterator_trait::AgateIterator;
+pub use levels::LevelInfo;
+pub use metrics::{CacheStats, Metrics, WriteStallReason, WriteStallStats};
+pub use ops::snapshot::Snapshot;
+pub use ops::transaction::Transaction;
 pub use skiplist::Skiplist;
@@ -0,0 +1,481 @@
+use crate::checksum;
+use crate::table;
+use crate::util::sync_dir;
+use crate::AgateOptions;
+use crate::{Error, Result};
+
+use bytes::{Buf, BufMut};
+use prost::Message;
+use proto::meta::{
+    checksum::Algorithm as ChecksumAlgorithm, manifest_change::Operation, Checksum, ManifestChange,
+    ManifestChangeSet,
+};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+pub const MANIFEST_FILENAME: &str = "MANIFEST";
+const MANIFEST_REWRITE_FILENAME: &str = "MANIFEST-REWRITE";
+const MANIFEST_DELETIONS_REWRITE_THRESHOLD: usize = 10000;
+const MANIFEST_DELETIONS_RATIO: usize = 10;
+
+const MAGIC_TEXT: &[u8; 4] = b"Agat";
+const MAGIC_VERSION: u32 = 1;
+
+/// Tables in one level of LSM tree.
+#[derive(Default, Debug, Clone)]
+pub struct LevelManifest {
+    pub tables: HashSet<u64>,
+}
+
+/// Information about a single table, including which level it belongs to.
+#[derive(Debug, Clone)]
+pub struct TableManifest {
+    pub level: u8,
+    pub key_id: u64,
+    pub compression: u32,
+}
+
+/// `Manifest` represents the contents of MANIFEST file, which records
+/// the layout of LSM tree.
+#[derive(Default, Debug, Clone)]
+pub struct Manifest {
+    pub levels: Vec<LevelManifest>,
+    pub tables: HashMap<u64, TableManifest>,
+    /// Number of changes applied, which is used to decide whether
+    /// MANIFEST should be rewritten.
+    pub creations: usize,
+    pub deletions: usize,
+    /// One more than the largest table ID ever created.
+    pub next_file_id: u64,
+    /// Deleted tables, of which files may be left on disk by a crash.
+    pub deleted: HashSet<u64>,
+}
+
+impl Manifest {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get CREATE changes that could rebuild current manifest.
+    fn as_changes(&self) -> Vec<ManifestChange> {
+        self.tables
+            .iter()
+            .map(|(id, tm)| new_create_change(*id, tm.level as usize, tm.key_id, tm.compression))
+            .collect()
+    }
+
+    fn apply_change(&mut self, change: &ManifestChange) -> Result<()> {
+        match Operation::from_i32(change.op) {
+            Some(Operation::Create) => {
+                if self.tables.contains_key(&change.id) {
+                    return Err(Error::InvalidManifest(format!(
+                        "table {} already exists",
+                        change.id
+                    )));
+                }
+                self.tables.insert(
+                    change.id,
+                    TableManifest {
+                        level: change.level as u8,
+                        key_id: change.key_id,
+                        compression: change.compression,
+                    },
+                );
+                while self.levels.len() <= change.level as usize {
+                    self.levels.push(LevelManifest::default());
+                }
+                self.levels[change.level as usize].tables.insert(change.id);
+                self.creations += 1;
+                self.next_file_id = self.next_file_id.max(change.id + 1);
+            }
+            Some(Operation::Delete) => {
+                let tm = self.tables.remove(&change.id).ok_or_else(|| {
+                    Error::InvalidManifest(format!("removes non-existing table {}", change.id))
+                })?;
+                self.levels[tm.level as usize].tables.remove(&change.id);
+                self.deletions += 1;
+                self.deleted.insert(change.id);
+            }
+            None => {
+                return Err(Error::InvalidManifest(format!(
+                    "unknown operation {}",
+                    change.op
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply a change set to manifest. Changes in a change set should be
+    /// applied atomically, so manifest is unchanged if any change fails.
+    fn apply_change_set(&mut self, change_set: &ManifestChangeSet) -> Result<()> {
+        let mut manifest = self.clone();
+        for change in change_set.changes.iter() {
+            manifest.apply_change(change)?;
+        }
+        manifest.next_file_id = manifest.next_file_id.max(change_set.next_file_id);
+        manifest.deleted.extend(change_set.deleted.iter().copied());
+        *self = manifest;
+        Ok(())
+    }
+}
+
+struct Core {
+    /// `None` in in-memory mode
+    file: Option<File>,
+    manifest: Manifest,
+}
+
+/// `ManifestFile` persists changes of LSM tree layout.
+///
+/// Change sets are appended to MANIFEST file, each framed by its length
+/// and CRC32C checksum. When there are too many deletions, MANIFEST will
+/// be rewritten with only the current tables.
+pub struct ManifestFile {
+    directory: PathBuf,
+    deletions_rewrite_threshold: usize,
+    core: Mutex<Core>,
+}
+
+impl ManifestFile {
+    /// Open or create MANIFEST in `opts.dir`. The file will be replayed
+    /// and truncated to the last valid change set.
+    pub fn open_or_create_manifest_file(opts: &AgateOptions) -> Result<Self> {
+        if opts.in_memory {
+            return Ok(Self {
+                directory: PathBuf::new(),
+                deletions_rewrite_threshold: MANIFEST_DELETIONS_REWRITE_THRESHOLD,
+                core: Mutex::new(Core {
+                    file: None,
+                    manifest: Manifest::new(),
+                }),
+            });
+        }
+        Self::help_open_or_create_manifest_file(&opts.dir, MANIFEST_DELETIONS_REWRITE_THRESHOLD)
+    }
+
+    fn help_open_or_create_manifest_file(
+        dir: impl AsRef<Path>,
+        deletions_threshold: usize,
+    ) -> Result<Self> {
+        let path = dir.as_ref().join(MANIFEST_FILENAME);
+
+        if !path.exists() {
+            let manifest = Manifest::new();
+            let (file, net_creations) = help_rewrite(&dir, &manifest)?;
+            assert_eq!(net_creations, 0);
+            return Ok(Self {
+                directory: dir.as_ref().to_path_buf(),
+                deletions_rewrite_threshold: deletions_threshold,
+                core: Mutex::new(Core {
+                    file: Some(file),
+                    manifest,
+                }),
+            });
+        }
+
+        let mut file = OpenOptions::new().read(true).write(true).open(&path)?;
+        let (manifest, trunc_offset) = replay_manifest_file(&mut file)?;
+        // Truncate file so that we won't append after a corrupted change set.
+        file.set_len(trunc_offset)?;
+        file.seek(SeekFrom::End(0))?;
+
+        Ok(Self {
+            directory: dir.as_ref().to_path_buf(),
+            deletions_rewrite_threshold: deletions_threshold,
+            core: Mutex::new(Core {
+                file: Some(file),
+                manifest,
+            }),
+        })
+    }
+
+    /// Apply changes to manifest and persist them to MANIFEST.
+    pub fn add_changes(&self, changes: Vec<ManifestChange>) -> Result<()> {
+        let change_set = ManifestChangeSet {
+            changes,
+            ..Default::default()
+        };
+        let mut core = self.core.lock().unwrap();
+        core.manifest.apply_change_set(&change_set)?;
+
+        if core.file.is_none() {
+            return Ok(());
+        }
+
+        if core.manifest.deletions > self.deletions_rewrite_threshold
+            && core.manifest.deletions
+                > MANIFEST_DELETIONS_RATIO * (core.manifest.creations - core.manifest.deletions)
+        {
+            self.rewrite(&mut core)?;
+        } else {
+            let mut buf = vec![];
+            encode_change_set(&change_set, &mut buf);
+            let file = core.file.as_mut().unwrap();
+            file.write_all(&buf)?;
+            file.sync_all()?;
+        }
+
+        Ok(())
+    }
+
+    fn rewrite(&self, core: &mut Core) -> Result<()> {
+        // Files of deleted tables are removed once they are no longer used,
+        // so only the ones still on disk need to be recorded.
+        let directory = &self.directory;
+        core.manifest
+            .deleted
+            .retain(|id| table::new_filename(*id, directory).exists());
+        // Close current MANIFEST before renaming.
+        core.file.take();
+        let (file, net_creations) = help_rewrite(&self.directory, &core.manifest)?;
+        core.file = Some(file);
+        core.manifest.creations = net_creations;
+        core.manifest.deletions = 0;
+        Ok(())
+    }
+
+    /// Get a copy of current manifest.
+    pub fn manifest(&self) -> Manifest {
+        self.core.lock().unwrap().manifest.clone()
+    }
+}
+
+/// Encode change set as length, CRC32C checksum and protobuf data.
+fn encode_change_set(change_set: &ManifestChangeSet, buf: &mut Vec<u8>) {
+    let mut data = vec![];
+    change_set.encode(&mut data).unwrap();
+    buf.put_u32(data.len() as u32);
+    buf.put_u32(checksum::calculate_checksum(&data, ChecksumAlgorithm::Crc32c) as u32);
+    buf.extend_from_slice(&data);
+}
+
+/// Write a new MANIFEST which contains only current tables of `manifest`.
+///
+/// Returns the opened MANIFEST and number of tables in it.
+fn help_rewrite(dir: impl AsRef<Path>, manifest: &Manifest) -> Result<(File, usize)> {
+    let rewrite_path = dir.as_ref().join(MANIFEST_REWRITE_FILENAME);
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(&rewrite_path)?;
+
+    let mut buf = Vec::with_capacity(8);
+    buf.extend_from_slice(MAGIC_TEXT);
+    buf.put_u32(MAGIC_VERSION);
+
+    let net_creations = manifest.tables.len();
+    let change_set = ManifestChangeSet {
+        changes: manifest.as_changes(),
+        next_file_id: manifest.next_file_id,
+        deleted: manifest.deleted.iter().copied().collect(),
+    };
+    encode_change_set(&change_set, &mut buf);
+    file.write_all(&buf)?;
+    file.sync_all()?;
+    drop(file);
+
+    let manifest_path = dir.as_ref().join(MANIFEST_FILENAME);
+    fs::rename(&rewrite_path, &manifest_path)?;
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&manifest_path)?;
+    file.seek(SeekFrom::End(0))?;
+    sync_dir(&dir)?;
+
+    Ok((file, net_creations))
+}
+
+/// Read exactly `buf.len()` bytes. Returns `false` if file ends early.
+fn read_full(file: &mut File, buf: &mut [u8]) -> Result<bool> {
+    match file.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Replay MANIFEST from the beginning.
+///
+/// Returns the manifest and the offset of the end of last valid change set.
+/// A change set that is not completely written is ignored, while checksum
+/// mismatch is reported as an error.
+fn replay_manifest_file(file: &mut File) -> Result<(Manifest, u64)> {
+    file.seek(SeekFrom::Start(0))?;
+
+    let mut magic_buf = [0; 8];
+    if !read_full(file, &mut magic_buf)? {
+        return Err(Error::InvalidManifest("bad magic".to_string()));
+    }
+    if &magic_buf[..4] != MAGIC_TEXT {
+        return Err(Error::InvalidManifest("bad magic".to_string()));
+    }
+    let version = (&magic_buf[4..]).get_u32();
+    if version != MAGIC_VERSION {
+        return Err(Error::InvalidManifest(format!(
+            "unsupported version {}, expected {}",
+            version, MAGIC_VERSION
+        )));
+    }
+
+    let mut manifest = Manifest::new();
+    let mut offset = magic_buf.len() as u64;
+    loop {
+        let mut header = [0; 8];
+        if !read_full(file, &mut header)? {
+            break;
+        }
+        let mut header = &header[..];
+        let length = header.get_u32();
+        let crc = header.get_u32();
+
+        let mut data = vec![0; length as usize];
+        if !read_full(file, &mut data)? {
+            break;
+        }
+        checksum::verify_checksum(
+            &data,
+            &Checksum {
+                algo: ChecksumAlgorithm::Crc32c as i32,
+                sum: crc as u64,
+            },
+        )
+        .map_err(|_| Error::InvalidManifest("bad checksum".to_string()))?;
+
+        let change_set = ManifestChangeSet::decode(&data[..])?;
+        manifest.apply_change_set(&change_set)?;
+        offset += 8 + length as u64;
+    }
+
+    Ok((manifest, offset))
+}
+
+pub fn new_create_change(id: u64, level: usize, key_id: u64, compression: u32) -> ManifestChange {
+    ManifestChange {
+        id,
+        op: Operation::Create as i32,
+        level: level as u32,
+        key_id,
+        encryption_algo: 0,
+        compression,
+    }
+}
+
+pub fn new_delete_change(id: u64) -> ManifestChange {
+    ManifestChange {
+        id,
+        op: Operation::Delete as i32,
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_manifest_replay() {
+        let tmp_dir = tempdir().unwrap();
+        {
+            let mf = ManifestFile::help_open_or_create_manifest_file(tmp_dir.path(), 10).unwrap();
+            mf.add_changes(vec![
+                new_create_change(1, 0, 0, 0),
+                new_create_change(2, 1, 0, 0),
+            ])
+            .unwrap();
+            mf.add_changes(vec![new_delete_change(1), new_create_change(3, 1, 0, 0)])
+                .unwrap();
+        }
+
+        let mf = ManifestFile::help_open_or_create_manifest_file(tmp_dir.path(), 10).unwrap();
+        let manifest = mf.manifest();
+        assert_eq!(manifest.tables.len(), 2);
+        assert!(manifest.levels[0].tables.is_empty());
+        assert_eq!(manifest.levels[1].tables, [2, 3].iter().cloned().collect());
+        assert_eq!(manifest.creations, 3);
+        assert_eq!(manifest.deletions, 1);
+    }
+
+    #[test]
+    fn test_manifest_invalid_changes() {
+        let tmp_dir = tempdir().unwrap();
+        let mf = ManifestFile::help_open_or_create_manifest_file(tmp_dir.path(), 10).unwrap();
+        mf.add_changes(vec![new_create_change(1, 0, 0, 0)]).unwrap();
+        assert!(matches!(
+            mf.add_changes(vec![new_create_change(1, 0, 0, 0)]),
+            Err(Error::InvalidManifest(_))
+        ));
+        // Change set is applied atomically.
+        assert!(matches!(
+            mf.add_changes(vec![new_create_change(2, 0, 0, 0), new_delete_change(3)]),
+            Err(Error::InvalidManifest(_))
+        ));
+        assert_eq!(mf.manifest().tables.len(), 1);
+    }
+
+    #[test]
+    fn test_manifest_rewrite() {
+        let tmp_dir = tempdir().unwrap();
+        // File of a deleted table is left on disk.
+        fs::write(table::new_filename(50, tmp_dir.path()), b"").unwrap();
+        let mf = ManifestFile::help_open_or_create_manifest_file(tmp_dir.path(), 10).unwrap();
+        mf.add_changes(vec![new_create_change(0, 0, 0, 0)]).unwrap();
+        for i in 0..100 {
+            mf.add_changes(vec![
+                new_create_change(i + 1, 0, 0, 0),
+                new_delete_change(i),
+            ])
+            .unwrap();
+        }
+        let manifest = mf.manifest();
+        assert!(manifest.deletions <= 10);
+        assert_eq!(manifest.tables.len(), 1);
+        drop(mf);
+
+        let mf = ManifestFile::help_open_or_create_manifest_file(tmp_dir.path(), 10).unwrap();
+        let manifest = mf.manifest();
+        assert_eq!(manifest.tables.len(), 1);
+        assert!(manifest.tables.contains_key(&100));
+        assert!(!tmp_dir.path().join(MANIFEST_REWRITE_FILENAME).exists());
+        // Table IDs and deleted tables left on disk are kept by rewrite.
+        assert_eq!(manifest.next_file_id, 101);
+        assert!(manifest.deleted.contains(&50));
+        assert!(!manifest.deleted.contains(&49));
+    }
+
+    #[test]
+    fn test_manifest_corruption() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join(MANIFEST_FILENAME);
+        {
+            let mf = ManifestFile::help_open_or_create_manifest_file(tmp_dir.path(), 10).unwrap();
+            mf.add_changes(vec![new_create_change(1, 0, 0, 0)]).unwrap();
+        }
+        let len = fs::metadata(&path).unwrap().len();
+
+        // A change set which is not completely written is discarded.
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[0, 0, 0, 10, 0, 0]).unwrap();
+        drop(file);
+        let mf = ManifestFile::help_open_or_create_manifest_file(tmp_dir.path(), 10).unwrap();
+        assert_eq!(mf.manifest().tables.len(), 1);
+        drop(mf);
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+
+        // Checksum mismatch is reported.
+        let mut data = fs::read(&path).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        fs::write(&path, &data).unwrap();
+        assert!(matches!(
+            ManifestFile::help_open_or_create_manifest_file(tmp_dir.path(), 10),
+            Err(Error::InvalidManifest(_))
+        ));
+    }
+}
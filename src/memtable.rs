@@ -1,25 +1,40 @@
+use crate::entry::Entry;
+use crate::format::get_ts;
+use crate::iterator_trait::AgateIterator;
 use crate::util::Comparator;
-use crate::value::Value;
+use crate::value::{Value, ValuePointer, VALUE_POINTER};
 use crate::wal::Wal;
 use crate::AgateOptions;
 use crate::Result;
 use bytes::Bytes;
-use skiplist::Skiplist;
+use skiplist::{IterRef, Skiplist};
 use std::collections::VecDeque;
 use std::mem::{self, ManuallyDrop, MaybeUninit};
 
 use std::ptr;
 use std::sync::Mutex;
 
-const MEMTABLE_VIEW_MAX: usize = 20;
+pub(crate) const MEMTABLE_VIEW_MAX: usize = 20;
 
-/// MemTableCore guards WAL and max_version.
+/// Move `head` to the end of entry at `vptr`, if it's after `head`.
+fn update_vlog_head(head: &mut ValuePointer, vptr: &ValuePointer) {
+    let end = vptr.offset + vptr.len;
+    if (vptr.file_id, end) > (head.file_id, head.offset) {
+        head.file_id = vptr.file_id;
+        head.offset = end;
+    }
+}
+
+/// MemTableCore guards WAL, max_version and vlog_head.
 /// These data will only be modified on memtable put.
 /// Therefore, separating wal and max_version enables
 /// concurrent read/write of MemTable.
 struct MemTableCore {
     wal: Option<Wal>,
     max_version: u64,
+    /// Value log entries before this position are in this memtable or
+    /// older ones.
+    vlog_head: ValuePointer,
 }
 
 pub struct MemTable {
@@ -36,20 +51,119 @@ impl MemTable {
             core: Mutex::new(MemTableCore {
                 wal,
                 max_version: 0,
+                vlog_head: ValuePointer::default(),
             }),
         }
     }
 
+    /// Replay WAL into skiplist. This is used to recover memtable after restart.
+    ///
+    /// Replay stops at the first corrupted entry, which may be caused by an
+    /// unclean shutdown, and WAL will be truncated to the last valid entry.
     pub fn update_skip_list(&self) -> Result<()> {
-        unimplemented!()
+        let mut core = self.core.lock().unwrap();
+        let core = &mut *core;
+        let wal = match core.wal {
+            Some(ref mut wal) => wal,
+            None => return Ok(()),
+        };
+
+        let mut it = wal.iter()?;
+        while let Some(entry) = it.next()? {
+            let ts = get_ts(entry.key);
+            if ts > core.max_version {
+                core.max_version = ts;
+            }
+            if entry.meta & VALUE_POINTER != 0 {
+                let mut vptr = ValuePointer::default();
+                vptr.decode(entry.value)?;
+                update_vlog_head(&mut core.vlog_head, &vptr);
+            }
+            let value = Value {
+                meta: entry.meta,
+                user_meta: entry.user_meta,
+                expires_at: entry.expires_at,
+                value: Bytes::copy_from_slice(entry.value),
+                version: 0,
+            };
+            self.skl.put(Bytes::copy_from_slice(entry.key), value);
+        }
+        let end = it.valid_end_offset();
+        wal.truncate(end)
+    }
+
+    /// Get the max version of all entries in this memtable.
+    pub fn max_version(&self) -> u64 {
+        self.core.lock().unwrap().max_version
+    }
+
+    /// Record that the value log entry at `vptr` is in this memtable.
+    pub fn update_vlog_head(&self, vptr: &ValuePointer) {
+        update_vlog_head(&mut self.core.lock().unwrap().vlog_head, vptr);
+    }
+
+    /// Get the position in value log, before which all entries are in this
+    /// memtable or older ones. `file_id` is 0 if no entry is in value log.
+    pub fn vlog_head(&self) -> ValuePointer {
+        self.core.lock().unwrap().vlog_head.clone()
+    }
+
+    /// Write an entry to WAL (if any) and then insert it into skiplist.
+    /// `key` should contain timestamp.
+    pub fn put(&self, key: Bytes, value: Value) -> Result<()> {
+        let mut core = self.core.lock().unwrap();
+        if let Some(ref mut wal) = core.wal {
+            let entry = Entry {
+                key: key.clone(),
+                value: value.value.clone(),
+                meta: value.meta,
+                user_meta: value.user_meta,
+                expires_at: value.expires_at,
+                version: 0,
+            };
+            wal.write_entry(&entry)?;
+        }
+
+        let ts = get_ts(&key);
+        if ts > core.max_version {
+            core.max_version = ts;
+        }
+        drop(core);
+
+        self.skl.put(key, value);
+        Ok(())
     }
 
-    pub fn put(&self, _key: Bytes, _value: Value) -> Result<()> {
-        unimplemented!()
+    /// Returns `true` if memtable should be flushed before accepting new writes.
+    pub fn is_full(&self) -> bool {
+        if self.skl.mem_size() as u64 >= self.opt.mem_table_size {
+            return true;
+        }
+        let core = self.core.lock().unwrap();
+        match core.wal {
+            Some(ref wal) => wal.should_flush(),
+            None => false,
+        }
+    }
+
+    /// Close memtable and remove its WAL file.
+    ///
+    /// This is called after memtable has been flushed to an SST. Readers may
+    /// still hold its skiplist from `MemTablesView`.
+    pub fn delete(self) -> Result<()> {
+        let core = self.core.into_inner().unwrap();
+        if let Some(wal) = core.wal {
+            wal.close_and_remove()?;
+        }
+        Ok(())
     }
 
     pub fn sync_wal(&self) -> Result<()> {
-        unimplemented!()
+        let mut core = self.core.lock().unwrap();
+        if let Some(ref mut wal) = core.wal {
+            wal.sync()?;
+        }
+        Ok(())
     }
 }
 
@@ -74,6 +188,85 @@ impl Drop for MemTablesView {
     }
 }
 
+/// `SkiplistIterator` iterates on a memtable, emitting keys with timestamp.
+pub struct SkiplistIterator {
+    iter: IterRef<Skiplist<Comparator>, Comparator>,
+    reversed: bool,
+}
+
+impl SkiplistIterator {
+    pub fn new(skl: &Skiplist<Comparator>, reversed: bool) -> Self {
+        Self {
+            iter: skl.iter(),
+            reversed,
+        }
+    }
+}
+
+impl AgateIterator for SkiplistIterator {
+    fn next(&mut self) {
+        if !self.reversed {
+            self.iter.next();
+        } else {
+            self.iter.prev();
+        }
+    }
+
+    fn rewind(&mut self) {
+        if !self.reversed {
+            self.iter.seek_to_first();
+        } else {
+            self.iter.seek_to_last();
+        }
+    }
+
+    fn seek(&mut self, key: &Bytes) {
+        if !self.reversed {
+            self.iter.seek(key);
+        } else {
+            self.iter.seek_for_prev(key);
+        }
+    }
+
+    fn prev(&mut self) {
+        if !self.reversed {
+            self.iter.prev();
+        } else {
+            self.iter.next();
+        }
+    }
+
+    fn seek_to_last(&mut self) {
+        if !self.reversed {
+            self.iter.seek_to_last();
+        } else {
+            self.iter.seek_to_first();
+        }
+    }
+
+    fn seek_for_prev(&mut self, key: &Bytes) {
+        if !self.reversed {
+            self.iter.seek_for_prev(key);
+        } else {
+            self.iter.seek(key);
+        }
+    }
+
+    fn key(&self) -> &[u8] {
+        self.iter.key()
+    }
+
+    fn value(&self) -> Value {
+        let mut value = Value::default();
+        value.decode(self.iter.value());
+        value
+    }
+
+    fn valid(&self) -> bool {
+        self.iter.valid()
+    }
+}
+
 pub struct MemTables {
     mutable: MemTable,
     immutable: VecDeque<MemTable>,
@@ -84,14 +277,15 @@ impl MemTables {
         Self { mutable, immutable }
     }
 
-    /// Get view of all current memtables
+    /// Get view of all current memtables, ordered from the newest to the oldest.
     pub fn view(&self) -> MemTablesView {
         // Maybe flush is better.
         assert!(self.immutable.len() < MEMTABLE_VIEW_MAX);
         let mut array: [MaybeUninit<Skiplist<Comparator>>; MEMTABLE_VIEW_MAX] =
             unsafe { MaybeUninit::uninit().assume_init() };
         array[0] = MaybeUninit::new(self.mutable.skl.clone());
-        for (i, s) in self.immutable.iter().enumerate() {
+        // Newer immutable memtables are pushed to the back.
+        for (i, s) in self.immutable.iter().rev().enumerate() {
             array[i + 1] = MaybeUninit::new(s.skl.clone());
         }
         MemTablesView {
@@ -104,4 +298,34 @@ impl MemTables {
     pub fn table_mut(&self) -> &MemTable {
         &self.mutable
     }
+
+    /// Get the oldest immutable memtable, which should be flushed first.
+    pub fn table_flush(&self) -> Option<&MemTable> {
+        self.immutable.front()
+    }
+
+    /// Number of immutable memtables waiting to be flushed
+    pub fn num_immutable(&self) -> usize {
+        self.immutable.len()
+    }
+
+    /// Replace mutable memtable with `memtable`, and the previous mutable
+    /// memtable becomes the newest immutable memtable.
+    pub(crate) fn use_new_table(&mut self, memtable: MemTable) {
+        let old = mem::replace(&mut self.mutable, memtable);
+        self.immutable.push_back(old);
+    }
+
+    /// Max version of all entries in memtables.
+    pub fn max_version(&self) -> u64 {
+        self.immutable
+            .iter()
+            .map(|mt| mt.max_version())
+            .fold(self.mutable.max_version(), u64::max)
+    }
+
+    /// Remove the oldest immutable memtable after it has been flushed.
+    pub(crate) fn pop_flushed(&mut self) -> Option<MemTable> {
+        self.immutable.pop_front()
+    }
 }
@@ -0,0 +1,196 @@
+use crate::cache::{BlockCache, Cache, CacheValue, IndexCache};
+use crate::Error;
+
+use std::hash::Hash;
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Reason why writes are delayed or blocked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteStallReason {
+    /// Number of level 0 tables reaches `num_level_zero_tables`, and each
+    /// write is slowed down.
+    Level0SlowDown,
+    /// Number of level 0 tables reaches `num_level_zero_tables_stall`, and
+    /// writes are blocked until compaction makes room.
+    Level0Stop,
+    /// Number of memtables reaches `num_memtables`, and writes are blocked
+    /// until a memtable is flushed.
+    MemtableLimit,
+}
+
+impl WriteStallReason {
+    pub const ALL: [WriteStallReason; 3] = [
+        WriteStallReason::Level0SlowDown,
+        WriteStallReason::Level0Stop,
+        WriteStallReason::MemtableLimit,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WriteStallReason::Level0SlowDown => "level0_slowdown",
+            WriteStallReason::Level0Stop => "level0_stop",
+            WriteStallReason::MemtableLimit => "memtable_limit",
+        }
+    }
+}
+
+/// Accumulated write stalls of one reason.
+#[derive(Debug, Clone)]
+pub struct WriteStallStats {
+    pub reason: WriteStallReason,
+    /// Number of times writes are stalled.
+    pub count: u64,
+    /// Total time writes are stalled.
+    pub duration: Duration,
+}
+
+/// Statistics of block cache or index cache.
+#[derive(Debug, Clone)]
+pub struct CacheStats {
+    /// Number of values found in cache.
+    pub hits: u64,
+    /// Number of values not found in cache.
+    pub misses: u64,
+    /// Total size of cached values in bytes.
+    pub size: u64,
+    pub capacity: u64,
+}
+
+/// Metrics of an agatedb instance.
+#[derive(Default)]
+pub struct Metrics {
+    write_stall_count: [AtomicU64; 3],
+    write_stall_micros: [AtomicU64; 3],
+    flush_errors: AtomicU64,
+    compaction_errors: AtomicU64,
+    /// Message of the last flush or compaction error.
+    last_background_error: Mutex<Option<String>>,
+    block_cache: Option<Arc<BlockCache>>,
+    index_cache: Option<Arc<IndexCache>>,
+}
+
+impl Metrics {
+    pub(crate) fn new(
+        block_cache: Option<Arc<BlockCache>>,
+        index_cache: Option<Arc<IndexCache>>,
+    ) -> Self {
+        Self {
+            block_cache,
+            index_cache,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn record_write_stall(&self, reason: WriteStallReason, duration: Duration) {
+        let idx = reason as usize;
+        self.write_stall_count[idx].fetch_add(1, Ordering::Relaxed);
+        self.write_stall_micros[idx].fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_flush_error(&self, err: &Error) {
+        self.flush_errors.fetch_add(1, Ordering::Relaxed);
+        *self.last_background_error.lock().unwrap() = Some(err.to_string());
+    }
+
+    pub(crate) fn record_compaction_error(&self, err: &Error) {
+        self.compaction_errors.fetch_add(1, Ordering::Relaxed);
+        *self.last_background_error.lock().unwrap() = Some(err.to_string());
+    }
+
+    /// Get number of failed memtable flushes.
+    pub fn flush_errors(&self) -> u64 {
+        self.flush_errors.load(Ordering::Relaxed)
+    }
+
+    /// Get number of failed compactions.
+    pub fn compaction_errors(&self) -> u64 {
+        self.compaction_errors.load(Ordering::Relaxed)
+    }
+
+    /// Get message of the last flush or compaction error.
+    pub fn last_background_error(&self) -> Option<String> {
+        self.last_background_error.lock().unwrap().clone()
+    }
+
+    /// Get write stalls of all reasons.
+    pub fn write_stalls(&self) -> Vec<WriteStallStats> {
+        WriteStallReason::ALL
+            .iter()
+            .map(|reason| {
+                let idx = *reason as usize;
+                WriteStallStats {
+                    reason: *reason,
+                    count: self.write_stall_count[idx].load(Ordering::Relaxed),
+                    duration: Duration::from_micros(
+                        self.write_stall_micros[idx].load(Ordering::Relaxed),
+                    ),
+                }
+            })
+            .collect()
+    }
+
+    /// Get write stalls of `reason`.
+    pub fn write_stall(&self, reason: WriteStallReason) -> WriteStallStats {
+        self.write_stalls().swap_remove(reason as usize)
+    }
+
+    /// Get statistics of block cache. Returns `None` if block cache is
+    /// disabled.
+    pub fn block_cache(&self) -> Option<CacheStats> {
+        self.block_cache.as_deref().map(cache_stats)
+    }
+
+    /// Get statistics of index cache. Returns `None` if indices of all
+    /// tables are kept in memory.
+    pub fn index_cache(&self) -> Option<CacheStats> {
+        self.index_cache.as_deref().map(cache_stats)
+    }
+}
+
+fn cache_stats<K: Hash + Eq + Clone, V: CacheValue>(cache: &Cache<K, V>) -> CacheStats {
+    CacheStats {
+        hits: cache.hits(),
+        misses: cache.misses(),
+        size: cache.size(),
+        capacity: cache.capacity(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_stall_metrics() {
+        let metrics = Metrics::default();
+        metrics.record_write_stall(WriteStallReason::Level0Stop, Duration::from_millis(3));
+        metrics.record_write_stall(WriteStallReason::Level0Stop, Duration::from_millis(2));
+        metrics.record_write_stall(WriteStallReason::MemtableLimit, Duration::from_millis(1));
+
+        let stall = metrics.write_stall(WriteStallReason::Level0Stop);
+        assert_eq!(stall.count, 2);
+        assert_eq!(stall.duration, Duration::from_millis(5));
+        let stall = metrics.write_stall(WriteStallReason::MemtableLimit);
+        assert_eq!(stall.count, 1);
+        let stall = metrics.write_stall(WriteStallReason::Level0SlowDown);
+        assert_eq!(stall.count, 0);
+        assert_eq!(stall.duration, Duration::from_millis(0));
+    }
+
+    #[test]
+    fn test_background_error_metrics() {
+        let metrics = Metrics::default();
+        assert_eq!(metrics.last_background_error(), None);
+        metrics.record_flush_error(&Error::DBClosed);
+        metrics.record_compaction_error(&Error::CompactionError("failed".to_string()));
+        assert_eq!(metrics.flush_errors(), 1);
+        assert_eq!(metrics.compaction_errors(), 1);
+        assert_eq!(
+            metrics.last_background_error().unwrap(),
+            Error::CompactionError("failed".to_string()).to_string()
+        );
+    }
+}
@@ -1,24 +1,161 @@
-use std::sync::atomic::{AtomicU64, Ordering};
+use super::transaction::Transaction;
+use crate::watermark::WaterMark;
+use crate::{AgateOptions, Error, Result};
 
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+/// Conflict keys of a committed transaction.
+struct CommittedTxn {
+    ts: u64,
+    conflict_keys: HashSet<u64>,
+}
+
+struct Core {
+    next_txn_ts: u64,
+    /// Transactions committed after the oldest active transaction started,
+    /// which may conflict with active transactions.
+    committed_txns: Vec<CommittedTxn>,
+    last_cleanup_ts: u64,
+}
+
+/// `Oracle` allocates timestamps for transactions, and detects conflicts
+/// between them at commit time.
 pub struct Oracle {
-    next_txn_ts: AtomicU64,
-    discard_ts: AtomicU64,
+    detect_conflicts: bool,
+    core: Mutex<Core>,
+    /// Serializes commits, so that transactions are written in the order
+    /// of their commit timestamps.
+    pub(crate) write_ch_lock: Mutex<()>,
+    /// Tracks read timestamps of active transactions.
+    read_mark: WaterMark,
+    /// Tracks commit timestamps of transactions being written. Reading at
+    /// `read_ts` waits until all commits up to `read_ts` are visible.
+    txn_mark: WaterMark,
 }
 
 impl Oracle {
+    pub fn new(opts: &AgateOptions) -> Self {
+        Self {
+            detect_conflicts: opts.detect_conflicts,
+            core: Mutex::new(Core {
+                next_txn_ts: 1,
+                committed_txns: vec![],
+                last_cleanup_ts: 0,
+            }),
+            write_ch_lock: Mutex::new(()),
+            read_mark: WaterMark::new("agate.pending_reads"),
+            txn_mark: WaterMark::new("agate.txn_timestamp"),
+        }
+    }
+
+    /// Reset oracle after opening a database of which max version is
+    /// `max_version`.
+    pub(crate) fn init(&self, max_version: u64) {
+        self.core.lock().unwrap().next_txn_ts = max_version + 1;
+        self.read_mark.init(max_version);
+        self.txn_mark.init(max_version);
+    }
+
+    /// Allocate a read timestamp, which should be released by `done_read`.
+    ///
+    /// Blocks until all transactions committed at or below the timestamp
+    /// are visible.
     pub fn read_ts(&self) -> u64 {
-        self.next_txn_ts.load(Ordering::SeqCst) - 1
+        let read_ts = {
+            let core = self.core.lock().unwrap();
+            let read_ts = core.next_txn_ts - 1;
+            self.read_mark.begin(read_ts);
+            read_ts
+        };
+        self.txn_mark.wait_for_mark(read_ts);
+        read_ts
     }
 
     pub fn next_ts(&self) -> u64 {
-        self.next_txn_ts.load(Ordering::SeqCst)
+        self.core.lock().unwrap().next_txn_ts
     }
 
     pub fn increment_next_ts(&self) {
-        self.next_txn_ts.fetch_add(1, Ordering::SeqCst);
+        self.core.lock().unwrap().next_txn_ts += 1;
+    }
+
+    /// Versions at or below the returned timestamp are invisible to all
+    /// active and future transactions except the latest one of each key,
+    /// so they could be discarded by compaction.
+    pub fn discard_at_or_below(&self) -> u64 {
+        self.read_mark.done_until()
+    }
+
+    /// Release a read timestamp allocated by `read_ts`.
+    pub(crate) fn done_read(&self, read_ts: u64) {
+        self.read_mark.done(read_ts);
+    }
+
+    /// Returns `true` if any key read by `txn` has been written by
+    /// transactions committed after `txn` started.
+    fn has_conflict(core: &Core, txn: &Transaction) -> bool {
+        let reads = txn.reads.lock().unwrap();
+        if reads.is_empty() {
+            return false;
+        }
+        core.committed_txns
+            .iter()
+            .filter(|committed| committed.ts > txn.read_ts)
+            .any(|committed| reads.iter().any(|fp| committed.conflict_keys.contains(fp)))
+    }
+
+    /// Remove committed transactions which no active transaction could
+    /// conflict with.
+    fn cleanup_committed_transactions(&self, core: &mut Core) {
+        if !self.detect_conflicts {
+            return;
+        }
+        let max_read_ts = self.read_mark.done_until();
+        if max_read_ts <= core.last_cleanup_ts {
+            return;
+        }
+        core.last_cleanup_ts = max_read_ts;
+        core.committed_txns.retain(|txn| txn.ts > max_read_ts);
+    }
+
+    /// Allocate commit timestamp for `txn`, or returns `Error::Conflict` if
+    /// it conflicts with transactions committed after it started.
+    ///
+    /// `write_ch_lock` should be held until the transaction is written and
+    /// `done_commit` is called.
+    pub(crate) fn new_commit_ts(&self, txn: &mut Transaction) -> Result<u64> {
+        let mut core = self.core.lock().unwrap();
+        if self.detect_conflicts && Self::has_conflict(&core, txn) {
+            return Err(Error::Conflict);
+        }
+
+        // Reads of `txn` are done. Release its read timestamp before
+        // cleanup, so that its conflict history could be removed.
+        txn.done_read();
+        self.cleanup_committed_transactions(&mut core);
+
+        let ts = core.next_txn_ts;
+        core.next_txn_ts += 1;
+        self.txn_mark.begin(ts);
+
+        if self.detect_conflicts {
+            core.committed_txns.push(CommittedTxn {
+                ts,
+                conflict_keys: txn.conflict_keys.clone(),
+            });
+        }
+        Ok(ts)
+    }
+
+    /// Mark transaction of `commit_ts` as written, so that it is visible
+    /// to new transactions.
+    pub(crate) fn done_commit(&self, commit_ts: u64) {
+        self.txn_mark.done(commit_ts);
     }
 
-    pub fn set_discard_ts(&self, discard_ts: u64) {
-        self.discard_ts.store(discard_ts, Ordering::SeqCst);
+    #[cfg(test)]
+    pub(crate) fn num_committed_txns(&self) -> usize {
+        self.core.lock().unwrap().committed_txns.len()
     }
 }
@@ -1,17 +1,153 @@
 use crate::db::Agate;
+use crate::iterator::{Item, Iterator, IteratorOptions};
+use crate::{Error, Result};
 
+use bytes::Bytes;
+
+/// `Snapshot` is a read-only view of the database at `read_ts`.
+///
+/// Versions visible to a snapshot are retained until it is released, either
+/// by `release` or by dropping it. Snapshots could be shared across threads.
 pub struct Snapshot {
     read_ts: u64,
     agate: Agate,
 }
 
+impl Agate {
+    /// Create a snapshot of all committed transactions.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            read_ts: self.core.orc.read_ts(),
+            agate: self.clone(),
+        }
+    }
+}
+
 impl Snapshot {
-    /*
-    pub fn get(&self, key: &mut [u8]) -> Result<Option<Bytes>> {
+    pub fn read_ts(&self) -> u64 {
+        self.read_ts
+    }
+
+    /// Get the value of `key` in this snapshot. Returns `Error::KeyNotFound`
+    /// if key doesn't exist or has been deleted.
+    pub fn get(&self, key: &[u8]) -> Result<Item> {
         if key.is_empty() {
             return Err(Error::EmptyKey);
         }
         self.agate.get_with_ts(key, self.read_ts)
     }
-    */
+
+    /// Create an iterator over keys in this snapshot. The iterator is
+    /// invalid until `rewind` or `seek` is called.
+    pub fn new_iterator(&self, opts: &IteratorOptions) -> Iterator<'_> {
+        Iterator::new(self.agate.core.clone(), self.read_ts, opts, None)
+    }
+
+    /// Create an iterator over keys starting with `prefix`, positioned at the
+    /// first key.
+    pub fn prefix_iterator(&self, prefix: impl Into<Bytes>) -> Iterator<'_> {
+        let mut opts = IteratorOptions::default();
+        opts.prefix = prefix.into();
+        let mut iter = self.new_iterator(&opts);
+        iter.rewind();
+        iter
+    }
+
+    /// Returns all items of keys in range `[start, end)`.
+    pub fn range(&self, start: &[u8], end: &[u8]) -> Vec<Item> {
+        let mut iter = self.new_iterator(&IteratorOptions::default());
+        let mut items = vec![];
+        iter.seek(start);
+        while iter.valid() && &iter.item().key()[..] < end {
+            items.push(iter.item().clone());
+            iter.next();
+        }
+        items
+    }
+
+    /// Release the snapshot, so that versions only visible to it could be
+    /// discarded by compaction. This is called when snapshot is dropped.
+    pub fn release(self) {}
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.agate.core.orc.done_read(self.read_ts);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AgateOptions;
+    use std::sync::Arc;
+    use std::thread;
+    use tempfile::tempdir;
+
+    fn set(agate: &Agate, key: &'static str, value: &'static str) {
+        let mut txn = agate.new_transaction(true);
+        txn.set(Bytes::from(key), Bytes::from(value)).unwrap();
+        txn.commit().unwrap();
+    }
+
+    fn keys(iter: &mut Iterator) -> Vec<Bytes> {
+        let mut keys = vec![];
+        while iter.valid() {
+            keys.push(iter.item().key().clone());
+            iter.next();
+        }
+        keys
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let tmp_dir = tempdir().unwrap();
+        let agate = Agate::open(AgateOptions::default(), tmp_dir.path()).unwrap();
+        set(&agate, "a1", "1");
+        set(&agate, "a2", "1");
+        set(&agate, "b1", "1");
+
+        let snapshot = Arc::new(agate.snapshot());
+        set(&agate, "a1", "2");
+        set(&agate, "a3", "2");
+        let mut txn = agate.new_transaction(true);
+        txn.delete(Bytes::from("a2")).unwrap();
+        txn.commit().unwrap();
+
+        // Snapshot could be shared across threads, and only sees writes
+        // committed before it's created.
+        let s = snapshot.clone();
+        thread::spawn(move || {
+            assert_eq!(s.get(b"a1").unwrap().value().unwrap(), Bytes::from("1"));
+            assert!(matches!(s.get(b"a3"), Err(Error::KeyNotFound)));
+            let mut iter = s.prefix_iterator("a");
+            assert_eq!(keys(&mut iter), vec![Bytes::from("a1"), Bytes::from("a2")]);
+        })
+        .join()
+        .unwrap();
+
+        let range: Vec<_> = snapshot
+            .range(b"a2", b"b2")
+            .iter()
+            .map(|item| item.key().clone())
+            .collect();
+        assert_eq!(range, vec![Bytes::from("a2"), Bytes::from("b1")]);
+
+        let latest = agate.snapshot();
+        let mut iter = latest.new_iterator(&IteratorOptions::default());
+        iter.seek(b"a");
+        assert_eq!(
+            keys(&mut iter),
+            vec![Bytes::from("a1"), Bytes::from("a3"), Bytes::from("b1")]
+        );
+        assert_eq!(
+            latest.get(b"a1").unwrap().value().unwrap(),
+            Bytes::from("2")
+        );
+
+        // Versions visible to snapshot are retained until it's released.
+        assert_eq!(agate.core.orc.discard_at_or_below(), snapshot.read_ts() - 1);
+        Arc::try_unwrap(snapshot).ok().unwrap().release();
+        assert_eq!(agate.core.orc.discard_at_or_below(), latest.read_ts() - 1);
+    }
 }
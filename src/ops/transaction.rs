@@ -1,27 +1,58 @@
 use crate::db::Agate;
 use crate::entry::Entry;
+use crate::format::key_with_ts;
+use crate::iterator::{Item, Iterator, IteratorOptions};
+use crate::iterator_trait::AgateIterator;
+use crate::util::{self, is_deleted_or_expired, KeyComparator, COMPARATOR};
+use crate::value::{Request, Value, VALUE_FIN_TXN, VALUE_TXN};
 use crate::{Error, Result};
 use bytes::Bytes;
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::Duration;
 
 const MAX_KEY_LENGTH: usize = 65000;
 
+/// Prefix of internal keys, which are hidden from iterators.
+pub(crate) const AGATE_PREFIX: &[u8] = b"!agate!";
+/// Key of the entry written at the end of each transaction, whose value is
+/// the commit timestamp.
+pub(crate) const TXN_KEY: &[u8] = b"!agate!txn";
+
 pub struct Transaction {
-    read_ts: u64,
+    pub(crate) read_ts: u64,
     commit_ts: u64,
+    size: u64,
+    count: u64,
 
     update: bool,
+    /// Fingerprints of keys read by this transaction.
+    pub(crate) reads: Mutex<Vec<u64>>,
+    /// Fingerprints of keys written by this transaction.
+    pub(crate) conflict_keys: HashSet<u64>,
     pending_writes: HashMap<Bytes, Entry>,
+    discarded: bool,
+    /// Whether read timestamp has been released.
+    done_read: bool,
     agate: Agate,
 }
 
 impl Agate {
+    /// Create a new transaction. Set `update` to false to create a
+    /// read-only transaction.
     pub fn new_transaction(&self, update: bool) -> Transaction {
         Transaction {
-            read_ts: 0,
+            read_ts: self.core.orc.read_ts(),
             commit_ts: 0,
+            size: 0,
+            count: 0,
             update,
+            reads: Mutex::new(vec![]),
+            conflict_keys: HashSet::new(),
             pending_writes: HashMap::default(),
+            discarded: false,
+            done_read: false,
             agate: self.clone(),
         }
     }
@@ -32,13 +63,44 @@ impl Transaction {
         self.modify(Entry::new(key, value))
     }
 
+    /// Set `key` to `value`, which expires after `ttl`. Expired keys are
+    /// invisible to reads, and dropped by compaction.
+    pub fn set_with_ttl(&mut self, key: Bytes, value: Bytes, ttl: Duration) -> Result<()> {
+        let mut e = Entry::new(key, value);
+        e.set_ttl(ttl);
+        self.modify(e)
+    }
+
+    pub fn set_entry(&mut self, e: Entry) -> Result<()> {
+        self.modify(e)
+    }
+
     pub fn delete(&mut self, key: Bytes) -> Result<()> {
         let mut e = Entry::new(key, Bytes::new());
         e.mark_delete();
         self.modify(e)
     }
 
+    fn check_size(&mut self, e: &Entry) -> Result<()> {
+        let opts = &self.agate.core.opts;
+        let count = self.count + 1;
+        // Extra bytes for the version in key.
+        let size = self.size + e.estimate_size(opts.value_threshold) as u64 + 10;
+        if count >= opts.max_batch_count || size >= opts.max_batch_size {
+            return Err(Error::TxnTooBig);
+        }
+        self.count = count;
+        self.size = size;
+        Ok(())
+    }
+
     fn modify(&mut self, e: Entry) -> Result<()> {
+        if !self.update {
+            return Err(Error::ReadOnlyTxn);
+        }
+        if self.discarded {
+            return Err(Error::DiscardedTxn);
+        }
         if e.key.is_empty() {
             return Err(Error::EmptyKey);
         }
@@ -49,7 +111,355 @@ impl Transaction {
                 &e.key[..MAX_KEY_LENGTH]
             )));
         }
+        let max_value_size = self.agate.core.opts.value_log_file_size;
+        if e.value.len() as u64 > max_value_size {
+            return Err(Error::TooLong(format!(
+                "value's length > {}",
+                max_value_size
+            )));
+        }
+        self.check_size(&e)?;
+
+        self.conflict_keys.insert(farmhash::fingerprint64(&e.key));
         self.pending_writes.insert(e.key.clone(), e);
         Ok(())
     }
+
+    /// Get the value of `key` visible to this transaction.
+    ///
+    /// Writes of this transaction are visible to itself. Returns
+    /// `Error::KeyNotFound` if key doesn't exist or has been deleted.
+    pub fn get(&self, key: &[u8]) -> Result<Item> {
+        if key.is_empty() {
+            return Err(Error::EmptyKey);
+        }
+        if self.discarded {
+            return Err(Error::DiscardedTxn);
+        }
+
+        if self.update {
+            if let Some(e) = self.pending_writes.get(key) {
+                if is_deleted_or_expired(e.meta, e.expires_at) {
+                    return Err(Error::KeyNotFound);
+                }
+                // Pending writes don't have a version yet. Use `read_ts`, as
+                // they are newer than anything else this transaction reads.
+                return Ok(Item::from_entry(e, self.read_ts, self.agate.core.clone()));
+            }
+            // Only keys not written by this transaction could conflict.
+            self.add_read_key(key);
+        }
+
+        self.agate.get_with_ts(key, self.read_ts)
+    }
+
+    /// Record `key` as read by this transaction, for conflict detection.
+    /// Nothing is recorded if conflict detection is disabled.
+    pub(crate) fn add_read_key(&self, key: &[u8]) {
+        if self.update && self.agate.core.opts.detect_conflicts {
+            self.reads
+                .lock()
+                .unwrap()
+                .push(farmhash::fingerprint64(key));
+        }
+    }
+
+    /// Create an iterator over keys visible to this transaction, including
+    /// writes of itself. The iterator is invalid until `rewind` or `seek` is
+    /// called.
+    pub fn new_iterator(&self, opts: &IteratorOptions) -> Iterator<'_> {
+        Iterator::new(self.agate.core.clone(), self.read_ts, opts, Some(self))
+    }
+
+    pub(crate) fn new_pending_writes_iterator(
+        &self,
+        reversed: bool,
+    ) -> Option<PendingWritesIterator> {
+        if !self.update || self.pending_writes.is_empty() {
+            return None;
+        }
+        let mut entries: Vec<(Bytes, Value)> = self
+            .pending_writes
+            .values()
+            .map(|e| {
+                let value = Value {
+                    meta: e.meta,
+                    user_meta: e.user_meta,
+                    expires_at: e.expires_at,
+                    value: e.value.clone(),
+                    version: self.read_ts,
+                };
+                (key_with_ts(&e.key[..], self.read_ts), value)
+            })
+            .collect();
+        entries.sort_by(|x, y| COMPARATOR.compare_key(&x.0, &y.0));
+        if reversed {
+            entries.reverse();
+        }
+        Some(PendingWritesIterator {
+            entries,
+            next_idx: 0,
+            reversed,
+        })
+    }
+
+    /// Commit the transaction.
+    ///
+    /// Returns `Error::Conflict` if any key read by this transaction has been
+    /// modified by another transaction committed after this one started.
+    pub fn commit(mut self) -> Result<()> {
+        if self.discarded {
+            return Err(Error::DiscardedTxn);
+        }
+        if self.pending_writes.is_empty() {
+            return Ok(());
+        }
+
+        let core = self.agate.core.clone();
+        let _guard = core.orc.write_ch_lock.lock().unwrap();
+        self.commit_ts = core.orc.new_commit_ts(&mut self)?;
+
+        let commit_ts = self.commit_ts;
+        let mut entries: Vec<Entry> = self
+            .pending_writes
+            .drain()
+            .map(|(_, mut e)| {
+                e.key = key_with_ts(&e.key[..], commit_ts);
+                e.meta |= VALUE_TXN;
+                e.version = commit_ts;
+                e
+            })
+            .collect();
+        let mut fin = Entry::new(
+            key_with_ts(TXN_KEY, commit_ts),
+            Bytes::from(commit_ts.to_string()),
+        );
+        fin.meta = VALUE_FIN_TXN;
+        entries.push(fin);
+
+        let result = core.write_requests(vec![Request {
+            entries,
+            ptrs: vec![],
+            done: None,
+        }]);
+        core.orc.done_commit(commit_ts);
+        result
+    }
+
+    /// Discard the transaction. Transaction can't be used after discarded.
+    /// This is called when transaction is dropped.
+    pub fn discard(&mut self) {
+        if self.discarded {
+            return;
+        }
+        self.discarded = true;
+        self.done_read();
+    }
+
+    /// Release read timestamp of this transaction.
+    pub(crate) fn done_read(&mut self) {
+        if !self.done_read {
+            self.done_read = true;
+            self.agate.core.orc.done_read(self.read_ts);
+        }
+    }
+}
+
+/// `PendingWritesIterator` iterates on writes of a transaction, which are
+/// versioned at read timestamp of the transaction.
+pub struct PendingWritesIterator {
+    /// Entries sorted by key, or in reverse order if `reversed` is set.
+    entries: Vec<(Bytes, Value)>,
+    next_idx: usize,
+    reversed: bool,
+}
+
+impl AgateIterator for PendingWritesIterator {
+    fn next(&mut self) {
+        self.next_idx += 1;
+    }
+
+    fn rewind(&mut self) {
+        self.next_idx = 0;
+    }
+
+    fn seek(&mut self, key: &Bytes) {
+        self.next_idx = util::search(self.entries.len(), |i| {
+            let cmp = COMPARATOR.compare_key(&self.entries[i].0, key);
+            if !self.reversed {
+                cmp != Ordering::Less
+            } else {
+                cmp != Ordering::Greater
+            }
+        });
+    }
+
+    fn prev(&mut self) {
+        if self.next_idx == 0 {
+            self.next_idx = self.entries.len();
+        } else {
+            self.next_idx -= 1;
+        }
+    }
+
+    fn seek_to_last(&mut self) {
+        self.next_idx = self.entries.len().saturating_sub(1);
+    }
+
+    fn seek_for_prev(&mut self, key: &Bytes) {
+        let idx = util::search(self.entries.len(), |i| {
+            let cmp = COMPARATOR.compare_key(&self.entries[i].0, key);
+            if !self.reversed {
+                cmp == Ordering::Greater
+            } else {
+                cmp == Ordering::Less
+            }
+        });
+        self.next_idx = if idx == 0 {
+            self.entries.len()
+        } else {
+            idx - 1
+        };
+    }
+
+    fn key(&self) -> &[u8] {
+        &self.entries[self.next_idx].0
+    }
+
+    fn value(&self) -> Value {
+        self.entries[self.next_idx].1.clone()
+    }
+
+    fn valid(&self) -> bool {
+        self.next_idx < self.entries.len()
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        self.discard();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AgateOptions;
+    use tempfile::tempdir;
+
+    fn with_agate(f: impl FnOnce(Agate)) {
+        let tmp_dir = tempdir().unwrap();
+        let mut opts = AgateOptions::default();
+        opts.value_threshold = 32;
+        f(Agate::open(opts, tmp_dir.path()).unwrap());
+    }
+
+    fn get(txn: &Transaction, key: &'static str) -> Result<Bytes> {
+        txn.get(key.as_bytes()).and_then(|item| item.value())
+    }
+
+    #[test]
+    fn test_txn_read_your_writes() {
+        with_agate(|agate| {
+            let mut txn = agate.new_transaction(true);
+            txn.set(Bytes::from("a"), Bytes::from("a1")).unwrap();
+            txn.set(Bytes::from("b"), Bytes::from(vec![b'b'; 64]))
+                .unwrap();
+            assert_eq!(get(&txn, "a").unwrap(), Bytes::from("a1"));
+            txn.delete(Bytes::from("a")).unwrap();
+            assert!(matches!(get(&txn, "a"), Err(Error::KeyNotFound)));
+            txn.commit().unwrap();
+
+            let txn = agate.new_transaction(false);
+            assert!(matches!(get(&txn, "a"), Err(Error::KeyNotFound)));
+            let item = txn.get(b"b").unwrap();
+            assert_eq!(item.value().unwrap(), Bytes::from(vec![b'b'; 64]));
+            assert_eq!(item.version(), txn.read_ts);
+            // The commit marker is internal and never visible.
+            assert!(matches!(txn.get(TXN_KEY), Err(Error::KeyNotFound)));
+        });
+    }
+
+    #[test]
+    fn test_txn_versions() {
+        with_agate(|agate| {
+            let mut read_txns = vec![];
+            for i in 1..=3 {
+                read_txns.push(agate.new_transaction(false));
+                let mut txn = agate.new_transaction(true);
+                txn.set(Bytes::from("key"), Bytes::from(format!("v{}", i)))
+                    .unwrap();
+                txn.commit().unwrap();
+            }
+
+            // Each transaction sees the snapshot when it started.
+            assert!(matches!(get(&read_txns[0], "key"), Err(Error::KeyNotFound)));
+            assert_eq!(get(&read_txns[1], "key").unwrap(), Bytes::from("v1"));
+            assert_eq!(get(&read_txns[2], "key").unwrap(), Bytes::from("v2"));
+            let txn = agate.new_transaction(false);
+            assert_eq!(get(&txn, "key").unwrap(), Bytes::from("v3"));
+        });
+    }
+
+    #[test]
+    fn test_txn_conflict() {
+        with_agate(|agate| {
+            let mut txn1 = agate.new_transaction(true);
+            let mut txn2 = agate.new_transaction(true);
+
+            assert!(get(&txn1, "key").is_err());
+            txn1.set(Bytes::from("other"), Bytes::from("1")).unwrap();
+            txn2.set(Bytes::from("key"), Bytes::from("2")).unwrap();
+            txn2.commit().unwrap();
+            assert!(matches!(txn1.commit(), Err(Error::Conflict)));
+
+            // Writes without reads never conflict.
+            let mut txn1 = agate.new_transaction(true);
+            let mut txn2 = agate.new_transaction(true);
+            txn1.set(Bytes::from("key"), Bytes::from("3")).unwrap();
+            txn2.set(Bytes::from("key"), Bytes::from("4")).unwrap();
+            txn2.commit().unwrap();
+            txn1.commit().unwrap();
+
+            let txn = agate.new_transaction(false);
+            assert_eq!(get(&txn, "key").unwrap(), Bytes::from("3"));
+        });
+    }
+
+    #[test]
+    fn test_txn_cleanup_committed() {
+        with_agate(|agate| {
+            let orc = &agate.core.orc;
+            let reader = agate.new_transaction(false);
+            for i in 0..3 {
+                let mut txn = agate.new_transaction(true);
+                txn.set(Bytes::from("key"), Bytes::from(format!("v{}", i)))
+                    .unwrap();
+                txn.commit().unwrap();
+            }
+            // Transactions committed after `reader` started are kept.
+            assert_eq!(orc.num_committed_txns(), 3);
+            assert_eq!(orc.discard_at_or_below(), reader.read_ts);
+
+            drop(reader);
+            let mut txn = agate.new_transaction(true);
+            txn.set(Bytes::from("key"), Bytes::from("v3")).unwrap();
+            txn.commit().unwrap();
+            assert_eq!(orc.num_committed_txns(), 1);
+        });
+    }
+
+    #[test]
+    fn test_txn_read_only() {
+        with_agate(|agate| {
+            let mut txn = agate.new_transaction(false);
+            assert!(matches!(
+                txn.set(Bytes::from("key"), Bytes::from("value")),
+                Err(Error::ReadOnlyTxn)
+            ));
+            assert!(matches!(txn.get(b""), Err(Error::EmptyKey)));
+            txn.discard();
+            assert!(matches!(get(&txn, "key"), Err(Error::DiscardedTxn)));
+        });
+    }
 }
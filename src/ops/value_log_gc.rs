@@ -0,0 +1,101 @@
+use crate::db::Agate;
+use crate::entry::Entry;
+use crate::format::get_ts;
+use crate::util::is_deleted_or_expired;
+use crate::value::{Request, ValuePointer, VALUE_FIN_TXN, VALUE_POINTER, VALUE_TXN};
+use crate::{Error, Result};
+
+impl Agate {
+    /// Garbage collect the value log with the most discarded bytes, if at
+    /// least `discard_ratio` of it has been discarded by compaction.
+    ///
+    /// Live entries are appended to the current value log, and the old
+    /// file is deleted once no read could access it. Returns
+    /// `Error::NoRewrite` if no value log is garbage collected, in which case
+    /// it's fine to try again later.
+    pub fn run_value_log_gc(&self, discard_ratio: f64) -> Result<()> {
+        if discard_ratio.is_nan() || discard_ratio <= 0.0 || discard_ratio >= 1.0 {
+            return Err(Error::InvalidRequest(format!(
+                "discard ratio should be in (0, 1), got {}",
+                discard_ratio
+            )));
+        }
+        let core = &self.core;
+        let vlog = core.vlog.as_ref().ok_or(Error::NoRewrite)?;
+        let _guard = vlog.lock_gc();
+
+        vlog.delete_pending_files(core.orc.discard_at_or_below())?;
+        let fid = vlog
+            .pick_log_for_gc(discard_ratio)
+            .ok_or(Error::NoRewrite)?;
+        self.rewrite_value_log(fid)?;
+
+        // Reads started before rewriting finishes may still get pointers to
+        // the old value log, which is deleted after they are all done.
+        let delete_ts = core.orc.read_ts();
+        core.orc.done_read(delete_ts);
+        vlog.mark_for_deletion(fid, delete_ts);
+        vlog.delete_pending_files(core.orc.discard_at_or_below())
+    }
+
+    /// Write live entries of value log `fid` again. An entry is live if the
+    /// LSM tree still points to it.
+    fn rewrite_value_log(&self, fid: u32) -> Result<()> {
+        let core = &self.core;
+        let vlog = core.vlog.as_ref().unwrap();
+
+        let mut entries = vec![];
+        let mut size = 0;
+        vlog.iterate(fid, 0, |mut entry, entry_vptr| {
+            let value = match core.get(&entry.key)? {
+                Some(value) => value,
+                None => return Ok(()),
+            };
+            if value.version != get_ts(&entry.key)
+                || value.meta & VALUE_POINTER == 0
+                || is_deleted_or_expired(value.meta, value.expires_at)
+            {
+                return Ok(());
+            }
+            let mut vptr = ValuePointer::default();
+            vptr.decode(&value.value)?;
+            if vptr.file_id != fid || vptr.offset != entry_vptr.offset {
+                return Ok(());
+            }
+            // A key in the mutable memtable can't be overwritten, so the old
+            // value log should be kept until it's flushed.
+            if core.in_mutable_memtable(&entry.key) {
+                return Err(Error::NoRewrite);
+            }
+
+            // Rewritten entries are no longer part of a transaction, so that
+            // they are replayed without transaction markers.
+            entry.meta = value.meta & !(VALUE_POINTER | VALUE_TXN | VALUE_FIN_TXN);
+            entry.user_meta = value.user_meta;
+            entry.expires_at = value.expires_at;
+            let entry_size = entry.estimate_size(core.opts.value_threshold) as u64;
+            if entries.len() as u64 + 1 > core.opts.max_batch_count
+                || size + entry_size > core.opts.max_batch_size
+            {
+                core.write_requests(vec![new_request(std::mem::take(&mut entries))])?;
+                size = 0;
+            }
+            size += entry_size;
+            entries.push(entry);
+            Ok(())
+        })?;
+        core.write_requests(vec![new_request(entries)])?;
+
+        // Rewritten entries should be persisted before the old value log is
+        // deleted.
+        core.sync_writes()
+    }
+}
+
+fn new_request(entries: Vec<Entry>) -> Request {
+    Request {
+        entries,
+        ptrs: vec![],
+        done: None,
+    }
+}
@@ -1,3 +1,8 @@
+use crate::cache::{BlockCache, IndexCache};
+
+use proto::meta::DataKey;
+use std::sync::Arc;
+
 #[derive(Debug, Clone)]
 pub struct Options {
     /// size of each block inside SST
@@ -8,7 +13,60 @@ pub struct Options {
     pub bloom_false_positive: f64,
     /// checksum mode
     pub checksum_mode: ChecksumVerificationMode,
+    /// prefix extractor, of which output is also added to bloom filter
+    pub prefix_extractor: Option<PrefixExtractor>,
+    /// compression of data blocks
+    pub compression: CompressionType,
+    /// cache of data blocks, shared by tables
+    pub block_cache: Option<Arc<BlockCache>>,
+    /// cache of table indices, shared by tables. If `None`, each table
+    /// keeps its index in memory.
+    pub index_cache: Option<Arc<IndexCache>>,
+    /// data key to encrypt blocks and index with. Table is not encrypted
+    /// if `None`.
+    pub data_key: Option<DataKey>,
 }
+
+/// Compression algorithm of SST data blocks. Values are recorded in manifest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CompressionType {
+    #[default]
+    None = 0,
+    Snappy = 1,
+    Zstd = 2,
+    Lz4 = 3,
+}
+
+impl CompressionType {
+    /// Convert value recorded in manifest to compression type.
+    pub fn from_u32(value: u32) -> Option<CompressionType> {
+        match value {
+            0 => Some(CompressionType::None),
+            1 => Some(CompressionType::Snappy),
+            2 => Some(CompressionType::Zstd),
+            3 => Some(CompressionType::Lz4),
+            _ => None,
+        }
+    }
+}
+
+/// Extracts prefixes of user keys, so that iterations over a prefix could
+/// skip SSTs by bloom filter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrefixExtractor {
+    /// The first `n` bytes of keys. Keys shorter than `n` have no prefix.
+    FixedLength(usize),
+}
+
+impl PrefixExtractor {
+    /// Returns prefix of `key`, or `None` if key is not in domain.
+    pub fn extract<'a>(&self, key: &'a [u8]) -> Option<&'a [u8]> {
+        match *self {
+            PrefixExtractor::FixedLength(n) => key.get(..n),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum ChecksumVerificationMode {
     NoVerification,
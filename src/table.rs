@@ -9,17 +9,21 @@ pub type TableIterator = TableRefIterator<Arc<TableInner>>;
 
 use crate::bloom::Bloom;
 use crate::checksum;
+use crate::compression;
+use crate::encryption;
+use crate::format::user_key;
 use crate::iterator_trait::AgateIterator;
-use crate::opt::{ChecksumVerificationMode, Options};
+use crate::opt::{ChecksumVerificationMode, CompressionType, Options, PrefixExtractor};
 use crate::Error;
 use crate::Result;
 
-use iterator::{TableRefIterator, ITERATOR_NOCACHE, ITERATOR_REVERSED};
+use iterator::TableRefIterator;
+pub use iterator::{ITERATOR_NOCACHE, ITERATOR_REVERSED};
 
 use bytes::{Buf, Bytes};
 use memmap2::{Mmap, MmapOptions};
 use prost::Message;
-use proto::meta::{BlockOffset, Checksum, TableIndex};
+use proto::meta::{Checksum, TableIndex};
 use std::fs;
 use std::io::Write;
 use std::path::{Path, PathBuf};
@@ -27,7 +31,7 @@ use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 
 #[cfg(test)]
-mod tests;
+pub(crate) mod tests;
 
 /// MmapFile stores SST data. `File` refers to a file on disk,
 /// and `Memory` refers to data in memory.
@@ -81,8 +85,12 @@ pub struct TableInner {
     checksum: Bytes,
     /// estimated size, only used on encryption or compression
     estimated_size: u32,
-    /// index of SST
-    index: TableIndex,
+    /// index of SST, which is `None` if it's kept in index cache instead
+    index: Option<Arc<TableIndex>>,
+    /// number of blocks in SST
+    num_blocks: usize,
+    /// max version of keys in SST
+    max_version: u64,
     /// start position of index
     index_start: usize,
     /// length of index
@@ -146,7 +154,9 @@ impl TableInner {
             id,
             checksum: Bytes::new(),
             estimated_size: 0,
-            index: TableIndex::default(),
+            index: None,
+            num_blocks: 0,
+            max_version: 0,
             index_start: 0,
             index_len: 0,
             opts,
@@ -176,7 +186,9 @@ impl TableInner {
             biggest: Bytes::new(),
             checksum: Bytes::new(),
             estimated_size: 0,
-            index: TableIndex::default(),
+            index: None,
+            num_blocks: 0,
+            max_version: 0,
             index_start: 0,
             index_len: 0,
             has_bloom_filter: false,
@@ -192,8 +204,7 @@ impl TableInner {
     }
 
     fn init_biggest_and_smallest(&mut self) -> Result<()> {
-        let ko = self.init_index()?;
-        self.smallest = Bytes::from(ko.key.clone());
+        self.init_index()?;
         let mut it = TableRefIterator::new(&self, ITERATOR_REVERSED | ITERATOR_NOCACHE);
         it.rewind();
         if !it.valid() {
@@ -206,7 +217,7 @@ impl TableInner {
         Ok(())
     }
 
-    fn init_index(&mut self) -> Result<&BlockOffset> {
+    fn init_index(&mut self) -> Result<()> {
         let mut read_pos = self.table_size;
 
         // read checksum length from last 4 bytes
@@ -230,64 +241,106 @@ impl TableInner {
         let data = self.read(read_pos, self.index_len)?;
         checksum::verify_checksum(&data, &chksum)?;
 
-        self.index = self.read_table_index()?;
+        let index = Arc::new(self.read_table_index()?);
+        self.smallest = Bytes::copy_from_slice(&index.offsets[0].key);
+        self.num_blocks = index.offsets.len();
+        self.max_version = index.max_version;
 
-        // TODO: compression
-        self.estimated_size = self.table_size as u32;
+        // Size of compressed or encrypted table doesn't reflect size of data.
+        self.estimated_size =
+            if self.opts.compression == CompressionType::None && self.opts.data_key.is_none() {
+                self.table_size as u32
+            } else {
+                index.estimated_size
+            };
 
         // bloom filter
-        self.has_bloom_filter = !self.index.bloom_filter.is_empty();
+        self.has_bloom_filter = !index.bloom_filter.is_empty();
 
-        Ok(&self.index.offsets[0])
+        // With index cache, index is loaded again by `fetch_index` once
+        // evicted.
+        match &self.opts.index_cache {
+            Some(cache) => cache.insert(self.id, index),
+            None => self.index = Some(index),
+        }
+
+        Ok(())
     }
 
     // split the table into at least (n - 1) ranges (when n >= blocks) based on block offsets
-    fn key_splits(&mut self, n: usize, prefix: Bytes) -> Vec<Bytes> {
+    fn key_splits(&mut self, n: usize, prefix: Bytes) -> Result<Vec<Bytes>> {
         if n == 0 {
-            return vec![];
+            return Ok(vec![]);
         }
 
-        let offset_length = self.offsets_length();
+        let index = self.fetch_index()?;
+        let offset_length = index.offsets.len();
         let jump = (offset_length / n).max(1);
 
         let mut result = vec![];
 
         for i in (0..offset_length).step_by(jump) {
-            let block = self.offsets(i).unwrap();
+            let block = &index.offsets[i];
             if block.key.starts_with(&prefix) {
                 result.push(Bytes::copy_from_slice(&block.key))
             }
         }
 
-        result
+        Ok(result)
     }
 
-    fn fetch_index(&self) -> &TableIndex {
-        &self.index
-        // TODO: encryption
+    /// Get index of SST. If index has been evicted from index cache, it is
+    /// read from SST again and put back into cache.
+    pub(crate) fn fetch_index(&self) -> Result<Arc<TableIndex>> {
+        if let Some(index) = &self.index {
+            return Ok(index.clone());
+        }
+        let cache = self.opts.index_cache.as_ref().unwrap();
+        if let Some(index) = cache.get(&self.id) {
+            return Ok(index);
+        }
+        let index = self.read_table_index().map_err(|e| {
+            Error::TableRead(format!(
+                "failed to read index of {}: {}",
+                self.filename(),
+                e
+            ))
+        })?;
+        let index = Arc::new(index);
+        cache.insert(self.id, index.clone());
+        Ok(index)
     }
 
     fn offsets_length(&self) -> usize {
-        self.fetch_index().offsets.len()
-    }
-
-    fn offsets(&self, idx: usize) -> Option<&BlockOffset> {
-        self.fetch_index().offsets.get(idx)
+        self.num_blocks
     }
 
-    fn block(&self, idx: usize, _use_cache: bool) -> Result<Arc<Block>> {
+    /// Read a block. Blocks read are added to block cache if `use_cache`
+    /// is set.
+    fn block(&self, idx: usize, use_cache: bool) -> Result<Arc<Block>> {
         use ChecksumVerificationMode::*;
 
-        // TODO: support cache
         if idx >= self.offsets_length() {
             return Err(Error::TableRead("block out of index".to_string()));
         }
-        let block_offset = self
-            .offsets(idx)
+        let key = (self.id, idx);
+        if let Some(block) = self.opts.block_cache.as_ref().and_then(|c| c.get(&key)) {
+            return Ok(block);
+        }
+        let index = self.fetch_index()?;
+        let block_offset = index
+            .offsets
+            .get(idx)
             .ok_or_else(|| Error::TableRead(format!("failed to get offset block {}", idx)))?;
 
         let offset = block_offset.offset as usize;
-        let data = self.read(offset, block_offset.len as usize)?;
+        let mut data = self.read(offset, block_offset.len as usize)?;
+        if let Some(key) = &self.opts.data_key {
+            data = Bytes::from(encryption::decrypt_with_iv(&data, &key.data)?);
+        }
+        if self.opts.compression != CompressionType::None {
+            data = Bytes::from(compression::decompress(&data, self.opts.compression)?);
+        }
 
         let mut read_pos = data.len() - 4; // first read checksum length
         let checksum_len = (&data[read_pos..read_pos + 4]).get_u32() as usize;
@@ -330,6 +383,12 @@ impl TableInner {
             blk.verify_checksum()?;
         }
 
+        if use_cache {
+            if let Some(cache) = &self.opts.block_cache {
+                cache.insert(key, blk.clone());
+            }
+        }
+
         Ok(blk)
     }
 
@@ -338,8 +397,8 @@ impl TableInner {
     }
 
     /// Get number of keys in SST
-    pub fn key_count(&self) -> u32 {
-        self.fetch_index().key_count
+    pub fn key_count(&self) -> Result<u32> {
+        Ok(self.fetch_index()?.key_count)
     }
 
     /// Get size of index
@@ -348,8 +407,8 @@ impl TableInner {
     }
 
     /// Get size of bloom filter
-    pub fn bloom_filter_size(&self) -> usize {
-        self.fetch_index().bloom_filter.len()
+    pub fn bloom_filter_size(&self) -> Result<usize> {
+        Ok(self.fetch_index()?.bloom_filter.len())
     }
 
     /// Get size of SST
@@ -357,6 +416,16 @@ impl TableInner {
         self.table_size as u64
     }
 
+    /// Get estimated size of data in SST, which is not compressed
+    pub fn estimated_size(&self) -> u32 {
+        self.estimated_size
+    }
+
+    /// Get compression of data blocks
+    pub fn compression(&self) -> CompressionType {
+        self.opts.compression
+    }
+
     /// Get smallest key of current table
     pub fn smallest(&self) -> &Bytes {
         &self.smallest
@@ -381,13 +450,13 @@ impl TableInner {
         self.id
     }
 
-    pub fn does_not_have(&self, hash: u32) -> bool {
+    pub fn does_not_have(&self, hash: u32) -> Result<bool> {
         if self.has_bloom_filter {
-            let index = self.fetch_index();
+            let index = self.fetch_index()?;
             let bloom = Bloom::new(&index.bloom_filter);
-            !bloom.may_contain(hash)
+            Ok(!bloom.may_contain(hash))
         } else {
-            false
+            Ok(false)
         }
     }
 
@@ -395,8 +464,19 @@ impl TableInner {
         self.has_bloom_filter
     }
 
+    /// Prefix extractor of which output has been added to bloom filter.
+    pub fn prefix_extractor(&self) -> Result<Option<PrefixExtractor>> {
+        Ok(match self.fetch_index()?.bloom_prefix_len {
+            0 => None,
+            n => Some(PrefixExtractor::FixedLength(n as usize)),
+        })
+    }
+
     pub(crate) fn read_table_index(&self) -> Result<TableIndex> {
-        let data = self.read(self.index_start, self.index_len)?;
+        let mut data = self.read(self.index_start, self.index_len)?;
+        if let Some(key) = &self.opts.data_key {
+            data = Bytes::from(encryption::decrypt_with_iv(&data, &key.data)?);
+        }
         // TODO: prefetch
         let result = Message::decode(data)?;
         Ok(result)
@@ -405,11 +485,12 @@ impl TableInner {
     fn verify_checksum(&self) -> Result<()> {
         use ChecksumVerificationMode::*;
 
-        let table_index = self.fetch_index();
+        let table_index = self.fetch_index()?;
         for i in 0..table_index.offsets.len() {
             // When using OnBlockRead or OnTableAndBlockRead, we do not need to verify block
             // checksum now. But we still need to check if there is an encoding error in block.
-            let block = self.block(i, true)?;
+            // Verification reads all blocks, which shouldn't fill cache.
+            let block = self.block(i, false)?;
             if !matches!(self.opts.checksum_mode, OnBlockRead | OnTableAndBlockRead) {
                 block.verify_checksum()?;
             }
@@ -456,13 +537,15 @@ impl TableInner {
     }
 
     fn max_version(&self) -> u64 {
-        unimplemented!()
-        // self.fetch_index()?.max_version()
+        self.max_version
     }
 }
 
 impl Drop for TableInner {
     fn drop(&mut self) {
+        if let Some(cache) = &self.opts.index_cache {
+            cache.remove(&self.id);
+        }
         if let MmapFile::File { file, mmap, name } =
             std::mem::replace(&mut self.file, MmapFile::None)
         {
@@ -493,7 +576,7 @@ pub struct Block {
 }
 
 impl Block {
-    fn size(&self) -> u64 {
+    pub(crate) fn size(&self) -> u64 {
         3 * std::mem::size_of::<usize>() as u64
             + self.data.len() as u64
             + self.checksum.len() as u64
@@ -506,7 +589,7 @@ impl Block {
     }
 }
 
-fn parse_file_id(name: &str) -> Result<u64> {
+pub(crate) fn parse_file_id(name: &str) -> Result<u64> {
     if !name.ends_with(".sst") {
         return Err(Error::InvalidFilename(name.to_string()));
     }
@@ -543,9 +626,9 @@ impl Table {
         self.inner.offsets_length()
     }
 
-    /// Get all block offsets
-    pub(crate) fn offsets(&self, idx: usize) -> Option<&BlockOffset> {
-        self.inner.offsets(idx)
+    /// Get index of this table
+    pub(crate) fn fetch_index(&self) -> Result<Arc<TableIndex>> {
+        self.inner.fetch_index()
     }
 
     /// Get one block from table
@@ -558,6 +641,17 @@ impl Table {
         TableRefIterator::new(self.inner.clone(), opt)
     }
 
+    /// Get filename of SST. Returns `<memtable>` if in-memory.
+    pub fn filename(&self) -> String {
+        self.inner.filename()
+    }
+
+    /// Get id of data key this table is encrypted with, or 0 if it's not
+    /// encrypted.
+    pub fn key_id(&self) -> u64 {
+        self.inner.opts.data_key.as_ref().map_or(0, |k| k.key_id)
+    }
+
     /// Get max version of this table
     pub fn max_version(&self) -> u64 {
         self.inner.max_version()
@@ -567,7 +661,14 @@ impl Table {
         self.inner.has_bloom_filter()
     }
 
-    pub fn does_not_have(&self, hash: u32) -> bool {
+    /// Prefix extractor of which output has been added to bloom filter.
+    pub fn prefix_extractor(&self) -> Result<Option<PrefixExtractor>> {
+        self.inner.prefix_extractor()
+    }
+
+    /// Check bloom filter for key hash. Returns `false` if there's no bloom
+    /// filter.
+    pub fn does_not_have(&self, hash: u32) -> Result<bool> {
         self.inner.does_not_have(hash)
     }
 
@@ -576,6 +677,16 @@ impl Table {
         self.inner.size()
     }
 
+    /// Get estimated size of data in SST, which is not compressed
+    pub fn estimated_size(&self) -> u32 {
+        self.inner.estimated_size()
+    }
+
+    /// Get compression of data blocks
+    pub fn compression(&self) -> CompressionType {
+        self.inner.compression()
+    }
+
     /// Get ID of SST
     pub fn id(&self) -> u64 {
         self.inner.id()
@@ -589,6 +700,13 @@ impl Table {
         self.inner.smallest()
     }
 
+    /// Returns `true` if key range of this table intersects with user key
+    /// range `[lower, upper)`. Empty bound means unbounded.
+    pub fn overlaps_with_range(&self, lower: &[u8], upper: &[u8]) -> bool {
+        (lower.is_empty() || user_key(self.biggest()) >= lower)
+            && (upper.is_empty() || user_key(self.smallest()) < upper)
+    }
+
     pub fn is_in_memory(&self) -> bool {
         self.inner.is_in_memory()
     }
@@ -1,8 +1,8 @@
 use crate::bloom::Bloom;
-use crate::format::user_key;
-use crate::opt::Options;
+use crate::format::{get_ts, user_key};
+use crate::opt::{CompressionType, Options, PrefixExtractor};
 use crate::value::Value;
-use crate::{checksum, util};
+use crate::{checksum, compression, encryption, util, Result};
 
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use prost::Message;
@@ -40,6 +40,8 @@ pub struct Builder {
     entry_offsets: Vec<u32>,
     table_index: TableIndex,
     key_hashes: Vec<u32>,
+    /// hash of last prefix added to `key_hashes`
+    last_prefix_hash: Option<u32>,
     options: Options,
     max_version: u64,
 }
@@ -52,6 +54,7 @@ impl Builder {
             buf: BytesMut::with_capacity((16 << 20) + options.table_size as usize),
             table_index: TableIndex::default(),
             key_hashes: Vec::with_capacity(1024),
+            last_prefix_hash: None,
             base_key: Bytes::new(),
             base_offset: 0,
             entry_offsets: vec![],
@@ -69,9 +72,24 @@ impl Builder {
         util::bytes_diff(&self.base_key, key)
     }
 
-    fn add_helper(&mut self, key: &Bytes, v: Value, vlog_len: u32) {
+    fn add_helper(&mut self, key: &Bytes, v: Value, vlog_len: u64) {
         self.key_hashes.push(farmhash::fingerprint32(user_key(key)));
-        // TODO: check ts
+        if let Some(prefix) = self
+            .options
+            .prefix_extractor
+            .and_then(|e| e.extract(user_key(key)))
+        {
+            // Keys are sorted, so keys with the same prefix are adjacent.
+            let hash = farmhash::fingerprint32(prefix);
+            if self.last_prefix_hash != Some(hash) {
+                self.key_hashes.push(hash);
+                self.last_prefix_hash = Some(hash);
+            }
+        }
+        let version = get_ts(key);
+        if version > self.max_version {
+            self.max_version = version;
+        }
         let diff_key = if self.base_key.is_empty() {
             self.base_key = key.clone();
             key
@@ -94,12 +112,13 @@ impl Builder {
         v.encode(&mut self.buf);
 
         let sst_size = v.encoded_size() as usize + diff_key.len() + 4;
-        self.table_index.estimated_size += sst_size as u32 + vlog_len;
+        let size = (sst_size as u64 + vlog_len).min(u32::MAX as u64) as u32;
+        self.table_index.estimated_size = self.table_index.estimated_size.saturating_add(size);
     }
 
-    fn finish_block(&mut self) {
+    fn finish_block(&mut self) -> Result<()> {
         if self.entry_offsets.is_empty() {
-            return;
+            return Ok(());
         }
         for offset in &self.entry_offsets {
             self.buf.put_u32_le(*offset);
@@ -109,7 +128,18 @@ impl Builder {
         let cs = self.build_checksum(&self.buf[self.base_offset as usize..]);
         self.write_checksum(cs);
 
+        if self.options.compression != CompressionType::None || self.options.data_key.is_some() {
+            let block = &self.buf[self.base_offset as usize..];
+            let mut data = compression::compress(block, self.options.compression)?;
+            if let Some(key) = &self.options.data_key {
+                data = encryption::encrypt_with_iv(&data, &key.data)?;
+            }
+            self.buf.truncate(self.base_offset as usize);
+            self.buf.put_slice(&data);
+        }
+
         self.add_block_to_index();
+        Ok(())
     }
 
     fn add_block_to_index(&mut self) {
@@ -139,16 +169,18 @@ impl Builder {
         estimated_size > self.options.block_size as u32
     }
 
-    /// Add key-value pair to table
-    pub fn add(&mut self, key: &Bytes, value: Value, vlog_len: u32) {
+    /// Add key-value pair to table. Fails if the finished block can't be
+    /// compressed or encrypted.
+    pub fn add(&mut self, key: &Bytes, value: Value, vlog_len: u64) -> Result<()> {
         if self.should_finish_block(key, &value) {
-            self.finish_block();
+            self.finish_block()?;
             self.base_key.clear();
             assert!(self.buf.len() < u32::MAX as usize);
             self.base_offset = self.buf.len() as u32;
             self.entry_offsets.clear();
         }
         self.add_helper(key, value, vlog_len);
+        Ok(())
     }
 
     /// Check if entries reach its capacity
@@ -165,21 +197,27 @@ impl Builder {
     }
 
     /// Finalize the table
-    pub fn finish(&mut self) -> Bytes {
-        self.finish_block();
+    pub fn finish(&mut self) -> Result<Bytes> {
+        self.finish_block()?;
         if self.buf.is_empty() {
-            return Bytes::new();
+            return Ok(Bytes::new());
         }
-        let mut bytes = BytesMut::new();
-        // TODO: move boundaries and build index if we need to encrypt or compress
         if self.options.bloom_false_positive > 0.0 {
             let bits_per_key =
                 Bloom::bloom_bits_per_key(self.key_hashes.len(), self.options.bloom_false_positive);
             let bloom = Bloom::build_from_key_hashes(&self.key_hashes, bits_per_key);
             self.table_index.bloom_filter = bloom.to_vec();
+            if let Some(PrefixExtractor::FixedLength(n)) = self.options.prefix_extractor {
+                self.table_index.bloom_prefix_len = n as u32;
+            }
         }
+        self.table_index.max_version = self.max_version;
         // append index to buffer
+        let mut bytes = vec![];
         self.table_index.encode(&mut bytes).unwrap();
+        if let Some(key) = &self.options.data_key {
+            bytes = encryption::encrypt_with_iv(&bytes, &key.data)?;
+        }
         assert!(bytes.len() < u32::MAX as usize);
         self.buf.put_slice(&bytes);
         self.buf.put_u32(bytes.len() as u32);
@@ -187,7 +225,7 @@ impl Builder {
         let cs = self.build_checksum(&bytes);
         self.write_checksum(cs);
         // TODO: eliminate clone if we do not need builder any more after finish
-        self.buf.clone().freeze()
+        Ok(self.buf.clone().freeze())
     }
 
     fn build_checksum(&self, data: &[u8]) -> Checksum {
@@ -225,6 +263,11 @@ mod tests {
             bloom_false_positive: 0.01,
             table_size: 30 << 20,
             checksum_mode: crate::opt::ChecksumVerificationMode::OnTableAndBlockRead,
+            prefix_extractor: None,
+            compression: CompressionType::None,
+            block_cache: None,
+            index_cache: None,
+            data_key: None,
         };
 
         let mut builder = Builder::new(opts.clone());
@@ -242,10 +285,10 @@ mod tests {
             } else if builder.should_finish_block(&k, &vs) {
                 block_first_keys.push(k.clone());
             }
-            builder.add(&k, vs, 0);
+            builder.add(&k, vs, 0).unwrap();
         }
 
-        let table = Table::create(&filename, builder.finish(), opts).unwrap();
+        let table = Table::create(&filename, builder.finish().unwrap(), opts).unwrap();
 
         // TODO: data key in options
 
@@ -269,6 +312,11 @@ mod tests {
             bloom_false_positive: if with_blooms { 0.01 } else { 0.0 },
             table_size: 0,
             checksum_mode: ChecksumVerificationMode::OnTableRead,
+            prefix_extractor: None,
+            compression: CompressionType::None,
+            block_cache: None,
+            index_cache: None,
+            data_key: None,
         };
 
         let table = build_test_table(key_prefix, key_count, opts);
@@ -281,7 +329,7 @@ mod tests {
         while it.valid() {
             count += 1;
             let hash = farmhash::fingerprint32(user_key(it.key()));
-            assert!(!table.does_not_have(hash));
+            assert!(!table.does_not_have(hash).unwrap());
             it.next();
         }
         assert_eq!(key_count, count);
@@ -293,6 +341,86 @@ mod tests {
         test_with_bloom_filter(true);
     }
 
+    #[test]
+    fn test_prefix_bloom_filter() {
+        let opts = Options {
+            block_size: 0,
+            bloom_false_positive: 0.01,
+            table_size: 0,
+            checksum_mode: ChecksumVerificationMode::OnTableRead,
+            prefix_extractor: Some(PrefixExtractor::FixedLength(3)),
+            compression: CompressionType::None,
+            block_cache: None,
+            index_cache: None,
+            data_key: None,
+        };
+        // Keys are `p0000` to `p0999`, with prefixes `p00` to `p09`.
+        let table = build_test_table(b"p", 1000, opts);
+        assert_eq!(
+            table.prefix_extractor().unwrap(),
+            Some(PrefixExtractor::FixedLength(3))
+        );
+        for i in 0..10 {
+            let prefix = format!("p0{}", i);
+            assert!(!table
+                .does_not_have(farmhash::fingerprint32(prefix.as_bytes()))
+                .unwrap());
+        }
+        let missing = (0..100)
+            .filter(|i| {
+                table
+                    .does_not_have(farmhash::fingerprint32(format!("q{:02}", i).as_bytes()))
+                    .unwrap()
+            })
+            .count();
+        assert!(missing > 90, "{}", missing);
+
+        let mut opts = table.inner.opts.clone();
+        opts.prefix_extractor = None;
+        let table = build_test_table(b"p", 1000, opts);
+        assert_eq!(table.prefix_extractor().unwrap(), None);
+    }
+
+    #[test]
+    fn test_compression() {
+        for compression in [
+            CompressionType::Snappy,
+            CompressionType::Lz4,
+            CompressionType::Zstd,
+        ] {
+            let opts = Options {
+                block_size: 4 * 1024,
+                bloom_false_positive: 0.01,
+                table_size: 0,
+                checksum_mode: ChecksumVerificationMode::OnTableAndBlockRead,
+                prefix_extractor: None,
+                compression,
+                block_cache: None,
+                index_cache: None,
+                data_key: None,
+            };
+            let table = build_test_table(b"key", 10000, opts.clone());
+            let mut uncompressed_opts = opts;
+            uncompressed_opts.compression = CompressionType::None;
+            let uncompressed = build_test_table(b"key", 10000, uncompressed_opts);
+            assert!(table.size() < uncompressed.size());
+            assert!(u64::from(table.estimated_size()) > table.size());
+
+            let mut it = table.new_iterator(0);
+            let mut expected = uncompressed.new_iterator(0);
+            it.rewind();
+            expected.rewind();
+            while expected.valid() {
+                assert!(it.valid());
+                assert_eq!(it.key(), expected.key());
+                assert_eq!(it.value().value, expected.value().value);
+                it.next();
+                expected.next();
+            }
+            assert!(!it.valid());
+        }
+    }
+
     #[test]
     fn test_empty_builder() {
         let opt = Options {
@@ -300,11 +428,16 @@ mod tests {
             block_size: 0,
             table_size: 0,
             checksum_mode: crate::opt::ChecksumVerificationMode::NoVerification,
+            prefix_extractor: None,
+            compression: CompressionType::None,
+            block_cache: None,
+            index_cache: None,
+            data_key: None,
         };
 
         let mut b = Builder::new(opt);
 
-        b.finish();
+        b.finish().unwrap();
     }
 
     #[test]
@@ -11,6 +11,8 @@ pub struct ConcatIterator {
     iters: Vec<Option<TableIterator>>,
     tables: Vec<Table>,
     opt: usize,
+    lower_bound: Bytes,
+    upper_bound: Bytes,
 }
 
 impl ConcatIterator {
@@ -24,16 +26,31 @@ impl ConcatIterator {
             iters,
             tables,
             opt,
+            lower_bound: Bytes::new(),
+            upper_bound: Bytes::new(),
         }
     }
 
+    /// Limit the iterator to user keys in range `[lower, upper)`. Empty
+    /// bound means unbounded. Tables out of range are never visited.
+    pub fn set_bounds(&mut self, lower: Bytes, upper: Bytes) {
+        self.tables
+            .retain(|t| t.overlaps_with_range(&lower, &upper));
+        self.iters = self.tables.iter().map(|_| None).collect();
+        self.cur = None;
+        self.lower_bound = lower;
+        self.upper_bound = upper;
+    }
+
     fn set_idx(&mut self, idx: usize) {
         if idx >= self.iters.len() {
             self.cur = None;
             return;
         }
         if self.iters[idx].is_none() {
-            self.iters[idx] = Some(self.tables[idx].new_iterator(self.opt));
+            let mut iter = self.tables[idx].new_iterator(self.opt);
+            iter.set_bounds(&self.lower_bound, &self.upper_bound);
+            self.iters[idx] = Some(iter);
         }
         self.cur = Some(idx);
     }
@@ -45,18 +62,36 @@ impl ConcatIterator {
     fn iter_ref(&self) -> &TableIterator {
         self.iters[self.cur.unwrap()].as_ref().unwrap()
     }
-}
 
-impl AgateIterator for ConcatIterator {
-    fn next(&mut self) {
-        let cur = self.cur.unwrap();
-        let cur_iter = self.iter_mut();
-        cur_iter.next();
-        if cur_iter.valid() {
+    /// Returns `true` if moving in the direction (`next` or `prev` if
+    /// `backward`) visits tables from smallest keys to biggest keys.
+    fn is_ascending(&self, backward: bool) -> bool {
+        (self.opt & ITERATOR_REVERSED == 0) != backward
+    }
+
+    /// Position the iterator of current table at its first entry in the
+    /// direction.
+    fn rewind_current(&mut self, backward: bool) {
+        if !backward {
+            self.iter_mut().rewind();
+        } else {
+            self.iter_mut().seek_to_last();
+        }
+    }
+
+    fn step(&mut self, backward: bool) {
+        let mut cur = self.cur.unwrap();
+        if !backward {
+            self.iter_mut().next();
+        } else {
+            self.iter_mut().prev();
+        }
+        if self.iter_ref().valid() {
             return;
         }
+        let ascending = self.is_ascending(backward);
         loop {
-            if self.opt & ITERATOR_REVERSED == 0 {
+            if ascending {
                 self.set_idx(cur + 1);
             } else if cur == 0 {
                 self.cur = None;
@@ -64,34 +99,33 @@ impl AgateIterator for ConcatIterator {
                 self.set_idx(cur - 1);
             }
 
-            if self.cur.is_some() {
-                self.iter_mut().rewind();
-                if self.iter_ref().valid() {
-                    return;
-                }
-            } else {
+            match self.cur {
+                Some(idx) => cur = idx,
+                None => return,
+            }
+            self.rewind_current(backward);
+            if self.iter_ref().valid() {
                 return;
             }
         }
     }
 
-    fn rewind(&mut self) {
+    fn rewind_helper(&mut self, backward: bool) {
         if self.iters.is_empty() {
             return;
         }
-        if self.opt & ITERATOR_REVERSED == 0 {
+        if self.is_ascending(backward) {
             self.set_idx(0);
         } else {
             self.set_idx(self.iters.len() - 1);
         }
-
-        self.iter_mut().rewind();
+        self.rewind_current(backward);
     }
 
-    fn seek(&mut self, key: &Bytes) {
+    fn seek_helper(&mut self, key: &Bytes, backward: bool) {
         use std::cmp::Ordering::*;
         let idx;
-        if self.opt & ITERATOR_REVERSED == 0 {
+        if self.is_ascending(backward) {
             idx = crate::util::search(self.tables.len(), |idx| {
                 COMPARATOR.compare_key(self.tables[idx].biggest(), key) != Less
             });
@@ -112,7 +146,37 @@ impl AgateIterator for ConcatIterator {
         }
 
         self.set_idx(idx);
-        self.iter_mut().seek(key);
+        if !backward {
+            self.iter_mut().seek(key);
+        } else {
+            self.iter_mut().seek_for_prev(key);
+        }
+    }
+}
+
+impl AgateIterator for ConcatIterator {
+    fn next(&mut self) {
+        self.step(false);
+    }
+
+    fn rewind(&mut self) {
+        self.rewind_helper(false);
+    }
+
+    fn seek(&mut self, key: &Bytes) {
+        self.seek_helper(key, false);
+    }
+
+    fn prev(&mut self) {
+        self.step(true);
+    }
+
+    fn seek_to_last(&mut self) {
+        self.rewind_helper(true);
+    }
+
+    fn seek_for_prev(&mut self, key: &Bytes) {
+        self.seek_helper(key, true);
     }
 
     fn key(&self) -> &[u8] {
@@ -195,4 +259,88 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_concat_iterator_bidirectional() {
+        let (tables, cnt) = build_test_tables();
+        let key = |i: usize| format!("{:012x}", i);
+        for opt in [0, ITERATOR_REVERSED] {
+            let reversed = opt != 0;
+            // Index of the i-th entry emitted by `next`.
+            let nth = |i: usize| if reversed { cnt - 1 - i } else { i };
+            let mut iter = ConcatIterator::from_tables(tables.clone(), opt);
+
+            iter.seek_to_last();
+            for i in (0..cnt).rev() {
+                assert!(iter.valid());
+                assert_eq!(user_key(iter.key()), key(nth(i)).as_bytes());
+                iter.prev();
+            }
+            assert!(!iter.valid());
+
+            // Change direction across table boundaries.
+            iter.rewind();
+            for i in 0..cnt - 1 {
+                iter.next();
+                assert_eq!(user_key(iter.key()), key(nth(i + 1)).as_bytes());
+                iter.prev();
+                assert_eq!(user_key(iter.key()), key(nth(i)).as_bytes());
+                iter.next();
+            }
+
+            for i in 10..cnt - 10 {
+                iter.seek_for_prev(&key_with_ts(key(nth(i)).as_str(), 0));
+                assert_eq!(user_key(iter.key()), key(nth(i)).as_bytes());
+                iter.prev();
+                assert_eq!(user_key(iter.key()), key(nth(i - 1)).as_bytes());
+            }
+        }
+    }
+
+    #[test]
+    fn test_concat_iterator_bounds() {
+        let (tables, cnt) = build_test_tables();
+        let key = |i: usize| format!("{:012x}", i);
+        let (lower, upper) = (cnt / 3, cnt / 2);
+        for opt in [0, ITERATOR_REVERSED] {
+            let mut iter = ConcatIterator::from_tables(tables.clone(), opt);
+            iter.set_bounds(Bytes::from(key(lower)), Bytes::from(key(upper)));
+            // Tables out of bounds are dropped.
+            assert!(iter.tables.len() < tables.len());
+            assert!(iter
+                .tables
+                .iter()
+                .all(|t| t.overlaps_with_range(key(lower).as_bytes(), key(upper).as_bytes())));
+
+            iter.rewind();
+            let mut keys = vec![];
+            while iter.valid() {
+                keys.push(Bytes::copy_from_slice(user_key(iter.key())));
+                iter.next();
+            }
+            let mut expected: Vec<_> = (lower..upper).map(|i| Bytes::from(key(i))).collect();
+            if opt != 0 {
+                expected.reverse();
+            }
+            assert_eq!(keys, expected);
+
+            // Seek keys are clamped to bounds.
+            let (last, first) = (
+                key_with_ts(key(cnt - 1).as_str(), 0),
+                key_with_ts(key(0).as_str(), 0),
+            );
+            if opt == 0 {
+                iter.seek_for_prev(&last);
+            } else {
+                iter.seek(&last);
+            }
+            assert_eq!(user_key(iter.key()), key(upper - 1).as_bytes());
+            if opt == 0 {
+                iter.seek(&first);
+            } else {
+                iter.seek_for_prev(&first);
+            }
+            assert_eq!(user_key(iter.key()), key(lower).as_bytes());
+        }
+    }
 }
@@ -1,10 +1,12 @@
 use super::builder::{Header, HEADER_SIZE};
 use super::{Block, TableInner};
+use crate::format::key_with_ts;
 use crate::iterator_trait::AgateIterator;
 use crate::util::{self, KeyComparator, COMPARATOR};
 use crate::value::Value;
 use crate::Error;
 use bytes::{Bytes, BytesMut};
+use std::cmp::Ordering;
 use std::sync::Arc;
 
 /// Errors that may encounter during iterator operation
@@ -226,6 +228,10 @@ pub struct TableRefIterator<T: AsRef<TableInner>> {
     block_iterator: Option<BlockIterator>,
     err: Option<IteratorError>,
     opt: usize,
+    /// Newest version of the inclusive lower bound key. Empty if unbounded.
+    lower_bound: Bytes,
+    /// Newest version of the exclusive upper bound key. Empty if unbounded.
+    upper_bound: Bytes,
 }
 
 impl<T: AsRef<TableInner>> TableRefIterator<T> {
@@ -237,9 +243,98 @@ impl<T: AsRef<TableInner>> TableRefIterator<T> {
             block_iterator: None,
             err: None,
             opt,
+            lower_bound: Bytes::new(),
+            upper_bound: Bytes::new(),
         }
     }
 
+    /// Limit the iterator to user keys in range `[lower, upper)`. Empty
+    /// bound means unbounded.
+    pub fn set_bounds(&mut self, lower: &[u8], upper: &[u8]) {
+        let bound_key = |key: &[u8]| {
+            if key.is_empty() {
+                Bytes::new()
+            } else {
+                key_with_ts(key, u64::MAX)
+            }
+        };
+        self.lower_bound = bound_key(lower);
+        self.upper_bound = bound_key(upper);
+    }
+
+    fn below_lower_bound(&self) -> bool {
+        self.valid()
+            && !self.lower_bound.is_empty()
+            && COMPARATOR.compare_key(self.key(), &self.lower_bound) == Ordering::Less
+    }
+
+    fn beyond_upper_bound(&self) -> bool {
+        self.valid()
+            && !self.upper_bound.is_empty()
+            && COMPARATOR.compare_key(self.key(), &self.upper_bound) != Ordering::Less
+    }
+
+    fn check_bounds(&mut self) {
+        if self.below_lower_bound() || self.beyond_upper_bound() {
+            self.err = Some(IteratorError::EOF);
+        }
+    }
+
+    fn seek_to_first_bounded(&mut self) {
+        if self.lower_bound.is_empty() {
+            self.seek_to_first();
+        } else {
+            let key = self.lower_bound.clone();
+            self.seek_inner(&key);
+        }
+        self.check_bounds();
+    }
+
+    fn seek_to_last_bounded(&mut self) {
+        if self.upper_bound.is_empty() {
+            self.seek_to_last_inner();
+        } else {
+            let key = self.upper_bound.clone();
+            self.seek_for_prev_inner(&key);
+            if self.beyond_upper_bound() {
+                self.prev_inner();
+            }
+        }
+        self.check_bounds();
+    }
+
+    fn seek_bounded(&mut self, key: &Bytes) {
+        if self.lower_bound.is_empty()
+            || COMPARATOR.compare_key(key, &self.lower_bound) != Ordering::Less
+        {
+            self.seek_inner(key);
+            self.check_bounds();
+        } else {
+            self.seek_to_first_bounded();
+        }
+    }
+
+    fn seek_for_prev_bounded(&mut self, key: &Bytes) {
+        if self.upper_bound.is_empty()
+            || COMPARATOR.compare_key(key, &self.upper_bound) == Ordering::Less
+        {
+            self.seek_for_prev_inner(key);
+            self.check_bounds();
+        } else {
+            self.seek_to_last_bounded();
+        }
+    }
+
+    fn next_bounded(&mut self) {
+        self.next_inner();
+        self.check_bounds();
+    }
+
+    fn prev_bounded(&mut self) {
+        self.prev_inner();
+        self.check_bounds();
+    }
+
     /// Reset iterator
     ///
     /// This function will only be used in tests outside this mod
@@ -278,7 +373,7 @@ impl<T: AsRef<TableInner>> TableRefIterator<T> {
         }
     }
 
-    pub fn seek_to_last(&mut self) {
+    pub fn seek_to_last_inner(&mut self) {
         let num_blocks = self.table.as_ref().offsets_length();
         if num_blocks == 0 {
             self.err = Some(IteratorError::EOF);
@@ -313,10 +408,16 @@ impl<T: AsRef<TableInner>> TableRefIterator<T> {
             self.reset();
         }
 
-        let idx = util::search(self.table.as_ref().offsets_length(), |idx| {
-            let block_offset = self.table.as_ref().offsets(idx).unwrap();
+        let index = match self.table.as_ref().fetch_index() {
+            Ok(index) => index,
+            Err(err) => {
+                self.err = Some(err.into());
+                return;
+            }
+        };
+        let idx = util::search(index.offsets.len(), |idx| {
             matches!(
-                COMPARATOR.compare_key(&block_offset.key, key),
+                COMPARATOR.compare_key(&index.offsets[idx].key, key),
                 std::cmp::Ordering::Greater
             )
         });
@@ -340,12 +441,13 @@ impl<T: AsRef<TableInner>> TableRefIterator<T> {
         self.seek_from(key, SeekPos::Origin);
     }
 
-    /// seek_for_prev will reset iterator and seek to <= key.
-    ///
-    /// This function will only be called in tests outside this mod
-    pub(crate) fn seek_for_prev(&mut self, key: &Bytes) {
+    /// seek_for_prev_inner will reset iterator and seek to <= key.
+    pub(crate) fn seek_for_prev_inner(&mut self, key: &Bytes) {
         self.seek_from(key, SeekPos::Origin);
-        if self.key() != key {
+        if !self.valid() {
+            // All keys are smaller than `key`.
+            self.seek_to_last_inner();
+        } else if self.key() != key {
             self.prev_inner();
         }
     }
@@ -435,27 +537,52 @@ impl<T: AsRef<TableInner>> AgateIterator for TableRefIterator<T> {
     /// before using it again.
     fn next(&mut self) {
         if self.opt & ITERATOR_REVERSED == 0 {
-            self.next_inner();
+            self.next_bounded();
         } else {
-            self.prev_inner();
+            self.prev_bounded();
         }
     }
 
     /// Reset the iterator to first element
     fn rewind(&mut self) {
         if self.opt & ITERATOR_REVERSED == 0 {
-            self.seek_to_first();
+            self.seek_to_first_bounded();
         } else {
-            self.seek_to_last();
+            self.seek_to_last_bounded();
         }
     }
 
     /// Seek to first entry >= key
     fn seek(&mut self, key: &Bytes) {
         if self.opt & ITERATOR_REVERSED == 0 {
-            self.seek_inner(key);
+            self.seek_bounded(key);
+        } else {
+            self.seek_for_prev_bounded(key);
+        }
+    }
+
+    fn prev(&mut self) {
+        if self.opt & ITERATOR_REVERSED == 0 {
+            self.prev_bounded();
+        } else {
+            self.next_bounded();
+        }
+    }
+
+    fn seek_to_last(&mut self) {
+        if self.opt & ITERATOR_REVERSED == 0 {
+            self.seek_to_last_bounded();
+        } else {
+            self.seek_to_first_bounded();
+        }
+    }
+
+    /// Seek to last entry <= key
+    fn seek_for_prev(&mut self, key: &Bytes) {
+        if self.opt & ITERATOR_REVERSED == 0 {
+            self.seek_for_prev_bounded(key);
         } else {
-            self.seek_for_prev(key);
+            self.seek_bounded(key);
         }
     }
 
@@ -4,6 +4,8 @@ use enum_dispatch::enum_dispatch;
 use super::concat_iterator::ConcatIterator;
 use super::TableIterator;
 use crate::iterator_trait::AgateIterator;
+use crate::memtable::SkiplistIterator;
+use crate::ops::transaction::PendingWritesIterator;
 use crate::util::{KeyComparator, COMPARATOR};
 use crate::Value;
 
@@ -15,6 +17,8 @@ pub enum Iterators {
     MergeIterator(MergeIterator),
     ConcatIterator(ConcatIterator),
     TableIterator(TableIterator),
+    SkiplistIterator(SkiplistIterator),
+    PendingWritesIterator(PendingWritesIterator),
     #[cfg(test)]
     VecIterator(tests::VecIterator),
 }
@@ -28,6 +32,9 @@ pub struct MergeIterator {
     right: IteratorNode,
     is_left_small: bool,
     reverse: bool,
+    /// Whether the iterator is moving by `prev`. Children are positioned
+    /// in the same direction.
+    backward: bool,
     current_key: BytesMut,
 }
 
@@ -70,6 +77,30 @@ impl IteratorNode {
         self.iter.seek(key);
         self.set_key();
     }
+
+    fn prev(&mut self) {
+        self.iter.prev();
+        self.set_key();
+    }
+
+    fn seek_to_last(&mut self) {
+        self.iter.seek_to_last();
+        self.set_key();
+    }
+
+    fn seek_for_prev(&mut self, key: &Bytes) {
+        self.iter.seek_for_prev(key);
+        self.set_key();
+    }
+
+    /// Move one step in the direction.
+    fn step(&mut self, backward: bool) {
+        if !backward {
+            self.next();
+        } else {
+            self.prev();
+        }
+    }
 }
 
 impl MergeIterator {
@@ -125,26 +156,65 @@ impl MergeIterator {
             return;
         }
 
+        // Moving backward in a reversed iterator visits smaller keys first.
+        let reverse = self.reverse != self.backward;
         match COMPARATOR.compare_key(&self.smaller().key, &self.bigger().key) {
             Equal => {
-                self.right.next();
+                self.right.step(self.backward);
                 if !self.is_left_small {
                     self.swap_small();
                 }
             }
             Less => {
-                if self.reverse {
+                if reverse {
                     self.swap_small();
                 }
             }
             Greater => {
-                if !self.reverse {
+                if !reverse {
                     self.swap_small();
                 }
             }
         }
     }
 
+    /// Change direction of iteration, and move to the entry next to current
+    /// one in the new direction.
+    fn switch_direction(&mut self, backward: bool) {
+        if !self.valid() {
+            return;
+        }
+        let key = Bytes::copy_from_slice(&self.current_key);
+        self.smaller_mut().step(backward);
+        // Entries of the other child should all be at the other side of
+        // current key.
+        let other = self.bigger_mut();
+        if !backward {
+            other.seek(&key);
+        } else {
+            other.seek_for_prev(&key);
+        }
+        if other.valid && other.key == key {
+            other.step(backward);
+        }
+        self.backward = backward;
+        self.fix();
+        self.set_current();
+    }
+
+    /// Move current child until it passes current key.
+    fn step_current(&mut self) {
+        while self.valid() {
+            if self.smaller().key != self.current_key {
+                break;
+            }
+            let backward = self.backward;
+            self.smaller_mut().step(backward);
+            self.fix();
+        }
+        self.set_current();
+    }
+
     fn set_current(&mut self) {
         self.current_key.clear();
         if self.is_left_small {
@@ -169,6 +239,7 @@ impl MergeIterator {
                     left: IteratorNode::new(left),
                     right: IteratorNode::new(right),
                     is_left_small: true,
+                    backward: false,
                     current_key: BytesMut::new(),
                 }))
             }
@@ -181,6 +252,7 @@ impl MergeIterator {
                     left: IteratorNode::new(Self::from_iterators(left, reverse)),
                     right: IteratorNode::new(Self::from_iterators(right, reverse)),
                     is_left_small: true,
+                    backward: false,
                     current_key: BytesMut::new(),
                 }))
             }
@@ -190,17 +262,15 @@ impl MergeIterator {
 
 impl AgateIterator for MergeIterator {
     fn next(&mut self) {
-        while self.valid() {
-            if self.smaller().key != self.current_key {
-                break;
-            }
-            self.smaller_mut().next();
-            self.fix();
+        if self.backward {
+            self.switch_direction(false);
+        } else {
+            self.step_current();
         }
-        self.set_current();
     }
 
     fn rewind(&mut self) {
+        self.backward = false;
         self.left.rewind();
         self.right.rewind();
         self.fix();
@@ -208,12 +278,37 @@ impl AgateIterator for MergeIterator {
     }
 
     fn seek(&mut self, key: &Bytes) {
+        self.backward = false;
         self.left.seek(key);
         self.right.seek(key);
         self.fix();
         self.set_current();
     }
 
+    fn prev(&mut self) {
+        if !self.backward {
+            self.switch_direction(true);
+        } else {
+            self.step_current();
+        }
+    }
+
+    fn seek_to_last(&mut self) {
+        self.backward = true;
+        self.left.seek_to_last();
+        self.right.seek_to_last();
+        self.fix();
+        self.set_current();
+    }
+
+    fn seek_for_prev(&mut self, key: &Bytes) {
+        self.backward = true;
+        self.left.seek_for_prev(key);
+        self.right.seek_for_prev(key);
+        self.fix();
+        self.set_current();
+    }
+
     fn key(&self) -> &[u8] {
         &self.smaller().key
     }
@@ -258,17 +353,39 @@ mod tests {
         }
 
         fn seek(&mut self, key: &Bytes) {
+            use std::cmp::Ordering::*;
+            let before = if self.reversed { Greater } else { Less };
             let found_entry_idx = crate::util::search(self.vec.len(), |idx| {
-                use std::cmp::Ordering::*;
-                if self.reversed {
-                    COMPARATOR.compare_key(&self.vec[idx], &key) != Greater
-                } else {
-                    COMPARATOR.compare_key(&self.vec[idx], &key) != Less
-                }
+                COMPARATOR.compare_key(&self.vec[idx], key) != before
             });
             self.pos = found_entry_idx;
         }
 
+        fn prev(&mut self) {
+            if self.pos == 0 {
+                self.pos = self.vec.len();
+            } else {
+                self.pos -= 1;
+            }
+        }
+
+        fn seek_to_last(&mut self) {
+            self.pos = self.vec.len().saturating_sub(1);
+        }
+
+        fn seek_for_prev(&mut self, key: &Bytes) {
+            use std::cmp::Ordering::*;
+            let past = if self.reversed { Less } else { Greater };
+            let found_entry_idx = crate::util::search(self.vec.len(), |idx| {
+                COMPARATOR.compare_key(&self.vec[idx], key) == past
+            });
+            self.pos = if found_entry_idx == 0 {
+                self.vec.len()
+            } else {
+                found_entry_idx - 1
+            };
+        }
+
         fn key(&self) -> &[u8] {
             &self.vec[self.pos]
         }
@@ -421,4 +538,70 @@ mod tests {
 
         check_reverse_sequence(MergeIterator::from_iterators(rev_iters, true), 0xfff);
     }
+
+    /// Check `iter` emits `expected` by `next`, and could move in both
+    /// directions.
+    fn check_bidirectional(mut iter: Box<Iterators>, expected: Vec<Bytes>) {
+        let n = expected.len();
+        iter.seek_to_last();
+        for i in (0..n).rev() {
+            assert!(iter.valid());
+            assert_bytes_eq!(iter.key(), &expected[i]);
+            iter.prev();
+        }
+        assert!(!iter.valid());
+
+        iter.rewind();
+        for i in 0..n - 1 {
+            iter.next();
+            assert_bytes_eq!(iter.key(), &expected[i + 1]);
+            iter.prev();
+            assert_bytes_eq!(iter.key(), &expected[i]);
+            iter.next();
+        }
+
+        for i in 10..n - 10 {
+            iter.seek_for_prev(&expected[i]);
+            assert_bytes_eq!(iter.key(), &expected[i]);
+            iter.prev();
+            assert_bytes_eq!(iter.key(), &expected[i - 1]);
+            iter.next();
+            iter.next();
+            assert_bytes_eq!(iter.key(), &expected[i + 1]);
+        }
+    }
+
+    #[test]
+    fn test_merge_iters_bidirectional() {
+        // Keys of iterators overlap.
+        let predicates: Vec<fn(usize) -> bool> =
+            vec![|x| x % 2 == 0, |x| x % 3 == 0, |x| x % 5 == 1];
+        let expected = gen_vec_data(0xfff, |x| predicates.iter().any(|p| p(x)));
+
+        let iters = predicates
+            .iter()
+            .map(|p| {
+                Box::new(Iterators::from(VecIterator::new(
+                    gen_vec_data(0xfff, p),
+                    false,
+                )))
+            })
+            .collect();
+        check_bidirectional(
+            MergeIterator::from_iterators(iters, false),
+            expected.clone(),
+        );
+
+        let iters = predicates
+            .iter()
+            .map(|p| {
+                let mut data = gen_vec_data(0xfff, p);
+                data.reverse();
+                Box::new(Iterators::from(VecIterator::new(data, true)))
+            })
+            .collect();
+        let mut rev_expected = expected;
+        rev_expected.reverse();
+        check_bidirectional(MergeIterator::from_iterators(iters, true), rev_expected);
+    }
 }
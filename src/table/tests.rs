@@ -27,6 +27,11 @@ pub(crate) fn get_test_table_options() -> Options {
         table_size: 0,
         bloom_false_positive: 0.01,
         checksum_mode: ChecksumVerificationMode::OnTableRead,
+        prefix_extractor: None,
+        compression: CompressionType::None,
+        block_cache: None,
+        index_cache: None,
+        data_key: None,
     }
 }
 
@@ -97,9 +102,11 @@ pub(crate) fn build_table_data(mut kv_pairs: Vec<(Bytes, Bytes)>, opts: Options)
     kv_pairs.sort_by(|x, y| x.0.cmp(&y.0));
 
     for (k, v) in kv_pairs {
-        builder.add(&key_with_ts(&k[..], 0), Value::new_with_meta(v, b'A', 0), 0);
+        builder
+            .add(&key_with_ts(&k[..], 0), Value::new_with_meta(v, b'A', 0), 0)
+            .unwrap();
     }
-    builder.finish()
+    builder.finish().unwrap()
 }
 
 #[test]
@@ -339,6 +346,52 @@ fn test_uni_iterator() {
     assert_eq!(count, 10000);
 }
 
+#[test]
+fn test_iterator_bounds() {
+    let opts = get_test_table_options();
+    let table = build_test_table(b"key", 1000, opts);
+    for opt in [0, ITERATOR_REVERSED] {
+        let mut it = table.new_iterator(opt);
+        it.set_bounds(&key(b"key", 100), &key(b"key", 200));
+        it.rewind();
+        let mut keys = vec![];
+        while it.valid() {
+            keys.push(Bytes::copy_from_slice(user_key(it.key())));
+            it.next();
+        }
+        let mut expected: Vec<_> = (100..200).map(|i| key(b"key", i)).collect();
+        if opt == ITERATOR_REVERSED {
+            expected.reverse();
+        }
+        assert_eq!(keys, expected);
+    }
+
+    let mut it = table.new_iterator(0);
+    it.set_bounds(&key(b"key", 100), &key(b"key", 200));
+    // Seek keys are clamped to bounds.
+    it.seek(&key_with_ts(&key(b"key", 50)[..], 0));
+    assert_eq!(user_key(it.key()), key(b"key", 100));
+    it.prev();
+    assert!(!it.valid());
+    it.seek_for_prev(&key_with_ts(&key(b"key", 500)[..], 0));
+    assert_eq!(user_key(it.key()), key(b"key", 199));
+    it.next();
+    assert!(!it.valid());
+    it.seek(&key_with_ts(&key(b"key", 200)[..], 0));
+    assert!(!it.valid());
+    it.seek_for_prev(&key_with_ts(&key(b"key", 99)[..], 0));
+    assert!(!it.valid());
+    it.seek_to_last();
+    assert_eq!(user_key(it.key()), key(b"key", 199));
+
+    // Only lower bound.
+    it.set_bounds(&key(b"key", 990), b"");
+    it.seek_to_last();
+    assert_eq!(user_key(it.key()), key(b"key", 999));
+    it.rewind();
+    assert_eq!(user_key(it.key()), key(b"key", 990));
+}
+
 // TODO: concat iterators and merge iterators
 
 fn value(i: usize) -> Bytes {
@@ -353,19 +406,24 @@ fn test_table_big_values() {
         bloom_false_positive: 0.01,
         table_size: (n as u64) * (1 << 20),
         checksum_mode: ChecksumVerificationMode::OnTableRead,
+        prefix_extractor: None,
+        compression: CompressionType::None,
+        block_cache: None,
+        index_cache: None,
+        data_key: None,
     };
     let mut builder = Builder::new(opts.clone());
 
     for i in 0..n {
         let key = key_with_ts(&key(b"", i)[..], i as u64 + 1);
         let vs = Value::new(value(i));
-        builder.add(&key, vs, 0);
+        builder.add(&key, vs, 0).unwrap();
     }
 
     let tmp_dir = tempdir().unwrap();
     let filename = tmp_dir.path().join("1.sst".to_string());
 
-    let table = Table::create(&filename, builder.finish(), opts).unwrap();
+    let table = Table::create(&filename, builder.finish().unwrap(), opts).unwrap();
 
     let mut it = table.new_iterator(0);
     assert!(it.valid());
@@ -2,10 +2,12 @@ pub use skiplist::FixedLengthSuffixComparator as Comparator;
 pub use skiplist::{FixedLengthSuffixComparator, KeyComparator};
 
 use crate::format::user_key;
+use crate::value::VALUE_DELETE;
 use crate::Result;
 
 use std::fs::File;
 use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::{cmp, ptr};
 
 pub static COMPARATOR: FixedLengthSuffixComparator = make_comparator();
@@ -75,3 +77,19 @@ pub fn same_key(a: &[u8], b: &[u8]) -> bool {
     }
     return user_key(a) == user_key(b);
 }
+
+/// Returns `true` if the value is deleted, or has expired.
+pub fn is_deleted_or_expired(meta: u8, expires_at: u64) -> bool {
+    if meta & VALUE_DELETE != 0 {
+        return true;
+    }
+    if expires_at == 0 {
+        return false;
+    }
+    expires_at <= unix_time().as_secs()
+}
+
+/// Get time elapsed since unix epoch.
+pub fn unix_time() -> Duration {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
+}
@@ -1,11 +1,10 @@
 use crate::entry::Entry;
 use crate::entry::EntryRef;
-use crate::wal::Header;
+use crate::wal::{self, Header, CRC_SIZE};
 use crate::{Error, Result};
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use crossbeam_channel::Sender;
-use std::io::{Cursor, Read};
-use std::mem::MaybeUninit;
+use std::io::{self, Cursor, Read};
 
 pub const VALUE_DELETE: u8 = 1 << 0;
 pub const VALUE_POINTER: u8 = 1 << 1;
@@ -59,33 +58,35 @@ fn var_size(n: u64) -> usize {
     1
 }
 
+/// Decode a varint, returning the value and the number of bytes read.
 fn decode_var(bytes: &[u8]) -> (u64, usize) {
-    if !bytes.is_empty() && bytes[0] == 0 {
-        return (0, 1);
-    }
     let mut ans = 0;
-    let mut index = 0;
-    while index > bytes.len() && index <= 9 {
-        ans |= (bytes[index] as u64) << (index * 7);
-        index += 1;
-    }
-    if index > 0 && index <= 9 {
-        return (ans, index);
-    }
-    if index == 10 && (bytes[index] == 0 || bytes[index] == 1) {
-        return (ans, index);
+    for (index, b) in bytes.iter().take(10).enumerate() {
+        // The 10th byte could only hold the highest bit of u64.
+        if index == 9 && *b > 1 {
+            break;
+        }
+        ans |= ((b & 0x7f) as u64) << (index * 7);
+        if b & 0x80 == 0 {
+            return (ans, index + 1);
+        }
     }
-    panic!("data is truncated or corrupted {:?}", &bytes[..index]);
+    panic!(
+        "data is truncated or corrupted {:?}",
+        &bytes[..bytes.len().min(10)]
+    );
 }
 
+/// Encode `data` as a varint, of which each byte holds 7 bits and sets the
+/// highest bit if more bytes follow.
 fn encode_var(bytes: &mut [u8], mut data: u64) -> usize {
     let mut i = 0;
-    while data >= 0x128 && i < bytes.len() {
-        bytes[i] = data as u8 & 0x7f;
+    while data >= 0x80 && i < bytes.len() {
+        bytes[i] = data as u8 | 0x80;
         i += 1;
         data >>= 7;
     }
-    if data < 0x128 && i < bytes.len() {
+    if i < bytes.len() {
         bytes[i] = data as u8;
         return i + 1;
     }
@@ -126,7 +127,7 @@ impl Value {
     }
 
     pub fn encode(&self, buf: &mut BytesMut) {
-        let mut arr: [u8; 12] = unsafe { MaybeUninit::uninit().assume_init() };
+        let mut arr = [0; 12];
         arr[0] = self.meta;
         arr[1] = self.user_meta;
         let written = encode_var(&mut arr[2..], self.expires_at);
@@ -146,63 +147,134 @@ pub struct Request {
     pub done: Option<Sender<Result<()>>>,
 }
 
+/// Format tag of value pointer with u32 `len` and `offset`.
+const COMPACT_POINTER: u8 = 0;
+/// Format tag of value pointer with u64 `len` and `offset`.
+const WIDE_POINTER: u8 = 1;
+
+/// Size of value pointer of which `len` and `offset` fit in u32.
+const COMPACT_POINTER_SIZE: usize = 1 + 4 * 3;
+/// Size of value pointer with u64 `len` and `offset`.
+const WIDE_POINTER_SIZE: usize = 1 + 4 + 8 * 2;
+
 /// `ValuePointer` records the position of value saved in value log.
+///
+/// Pointers are encoded in little endian after a format tag, with u32 `len`
+/// and `offset` if they fit, otherwise with u64 ones.
 #[derive(Clone, Default, Debug)]
 pub struct ValuePointer {
     pub file_id: u32,
-    pub len: u32,
-    pub offset: u32,
+    pub len: u64,
+    pub offset: u64,
 }
 
 impl ValuePointer {
-    pub fn decode(&mut self, mut bytes: &[u8]) {
+    pub fn decode(&mut self, mut bytes: &[u8]) -> Result<()> {
+        let size = match bytes.first() {
+            Some(&COMPACT_POINTER) => COMPACT_POINTER_SIZE,
+            Some(&WIDE_POINTER) => WIDE_POINTER_SIZE,
+            _ => return Err(Error::VarDecode("unknown value pointer format")),
+        };
+        if bytes.len() < size {
+            return Err(Error::VarDecode("value pointer is truncated"));
+        }
+        let tag = bytes.get_u8();
         self.file_id = bytes.get_u32_le();
-        self.len = bytes.get_u32_le();
-        self.offset = bytes.get_u32_le();
+        if tag == WIDE_POINTER {
+            self.len = bytes.get_u64_le();
+            self.offset = bytes.get_u64_le();
+        } else {
+            self.len = bytes.get_u32_le() as u64;
+            self.offset = bytes.get_u32_le() as u64;
+        }
+        Ok(())
     }
 
     pub fn encode(&self, buf: &mut BytesMut) {
-        buf.put_u32_le(self.file_id);
-        buf.put_u32_le(self.len);
-        buf.put_u32_le(self.offset);
+        if self.is_wide() {
+            buf.put_u8(WIDE_POINTER);
+            buf.put_u32_le(self.file_id);
+            buf.put_u64_le(self.len);
+            buf.put_u64_le(self.offset);
+        } else {
+            buf.put_u8(COMPACT_POINTER);
+            buf.put_u32_le(self.file_id);
+            buf.put_u32_le(self.len as u32);
+            buf.put_u32_le(self.offset as u32);
+        }
+    }
+
+    fn is_wide(&self) -> bool {
+        self.len > u32::MAX as u64 || self.offset > u32::MAX as u64
+    }
+
+    pub fn encoded_size(&self) -> usize {
+        if self.is_wide() {
+            WIDE_POINTER_SIZE
+        } else {
+            COMPACT_POINTER_SIZE
+        }
     }
 
-    pub fn encoded_size() -> usize {
-        std::mem::size_of::<u32>() * 3
+    /// Max size of an encoded pointer.
+    pub fn max_encoded_size() -> usize {
+        WIDE_POINTER_SIZE
     }
 }
 
 /// `EntryReader` reads entries from the `Cursor` with the `entry` function.
 pub struct EntryReader {
-    key: Vec<u8>,
-    value: Vec<u8>,
+    /// encoded header followed by key and value
+    buf: Vec<u8>,
     header: Header,
 }
 
 impl EntryReader {
     pub fn new() -> Self {
         Self {
-            key: vec![],
-            value: vec![],
+            buf: vec![],
             header: Header::default(),
         }
     }
 
-    /// Entry returns header, key and value.
-    pub fn entry(&mut self, reader: &mut Cursor<&[u8]>) -> Result<EntryRef> {
+    /// Entry returns header, key and value. Key and value are decrypted
+    /// together by `decrypt`, and then verified with checksum following
+    /// them.
+    pub fn entry(
+        &mut self,
+        reader: &mut Cursor<&[u8]>,
+        decrypt: impl FnOnce(&mut [u8]) -> Result<()>,
+    ) -> Result<EntryRef<'_>> {
+        let start = reader.position() as usize;
         self.header.decode(reader)?;
         if self.header.key_len > (1 << 16) {
             return Err(Error::LogRead(
                 "key length must not be larger than 1 << 16".to_string(),
             ));
         }
-        self.key.resize(self.header.key_len as usize, 0);
-        reader.read_exact(&mut self.key)?;
-        self.value.resize(self.header.value_len as usize, 0);
-        reader.read_exact(&mut self.value)?;
+        let header_len = reader.position() as usize - start;
+        let key_len = self.header.key_len as usize;
+        let remaining = reader.get_ref().len() - reader.position() as usize;
+        if (remaining as u64) < key_len as u64 + self.header.value_len {
+            // Don't allocate buffer for corrupted length.
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        self.buf.clear();
+        self.buf
+            .extend_from_slice(&reader.get_ref()[start..start + header_len]);
+        self.buf
+            .resize(header_len + key_len + self.header.value_len as usize, 0);
+        reader.read_exact(&mut self.buf[header_len..])?;
+        decrypt(&mut self.buf[header_len..])?;
+
+        let mut crc = [0; CRC_SIZE];
+        reader.read_exact(&mut crc)?;
+        wal::verify_entry_checksum(&self.buf, u32::from_be_bytes(crc))?;
+
+        let kv = &self.buf[header_len..];
         Ok(EntryRef {
-            key: &self.key,
-            value: &self.value,
+            key: &kv[..key_len],
+            value: &kv[key_len..],
             meta: self.header.meta,
             user_meta: self.header.user_meta,
             expires_at: self.header.expires_at,
@@ -211,3 +283,65 @@ impl EntryReader {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_value_expires_at() {
+        for expires_at in [0, 1, 127, 128, 300, 1 << 35, u64::MAX] {
+            let value = Value {
+                meta: VALUE_DELETE,
+                user_meta: 7,
+                expires_at,
+                value: Bytes::from("value"),
+                version: 0,
+            };
+            let mut buf = BytesMut::new();
+            value.encode(&mut buf);
+            assert_eq!(buf.len(), value.encoded_size() as usize);
+
+            let mut decoded = Value::default();
+            decoded.decode(&buf.freeze());
+            assert_eq!(
+                (decoded.meta, decoded.user_meta, decoded.expires_at),
+                (VALUE_DELETE, 7, expires_at)
+            );
+            assert_eq!(decoded.value, value.value);
+        }
+    }
+
+    #[test]
+    fn test_value_pointer() {
+        let cases = [
+            (1, 100, 200, COMPACT_POINTER_SIZE),
+            (2, u32::MAX as u64, u32::MAX as u64, COMPACT_POINTER_SIZE),
+            (3, 100, 5 << 30, WIDE_POINTER_SIZE),
+            (4, 5 << 30, 20, WIDE_POINTER_SIZE),
+        ];
+        for (file_id, len, offset, size) in cases {
+            let ptr = ValuePointer {
+                file_id,
+                len,
+                offset,
+            };
+            let mut buf = BytesMut::new();
+            ptr.encode(&mut buf);
+            assert_eq!(buf.len(), size);
+            assert_eq!(ptr.encoded_size(), size);
+
+            let mut decoded = ValuePointer::default();
+            decoded.decode(&buf).unwrap();
+            assert_eq!(
+                (decoded.file_id, decoded.len, decoded.offset),
+                (file_id, len, offset)
+            );
+            assert!(decoded.decode(&buf[..size - 1]).is_err());
+        }
+
+        let mut decoded = ValuePointer::default();
+        assert!(decoded.decode(&[]).is_err());
+        assert!(decoded.decode(&[2; WIDE_POINTER_SIZE]).is_err());
+    }
+}
@@ -1,16 +1,23 @@
+use crate::entry::Entry;
 use crate::error::InvalidValuePointerError;
+use crate::util::sync_dir;
 use crate::value::{self, Request, ValuePointer};
-use crate::wal::{Header, Wal};
+use crate::wal::{self, Header, Wal, CRC_SIZE, VLOG_HEADER_SIZE};
 use crate::AgateOptions;
 use crate::{Error, Result};
 
 use bytes::{Bytes, BytesMut};
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use std::collections::{HashMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
 use std::path::{Path, PathBuf};
-use std::sync::atomic::AtomicU32;
+use std::sync::atomic::AtomicU64;
 use std::sync::Arc;
 
+pub const HEAD_FILENAME: &str = "VLOG-HEAD";
+const HEAD_REWRITE_FILENAME: &str = "VLOG-HEAD-REWRITE";
+
 fn vlog_file_path(dir: impl AsRef<Path>, fid: u32) -> PathBuf {
     dir.as_ref().join(format!("{:06}.vlog", fid))
 }
@@ -24,7 +31,9 @@ struct Core {
     files_map: HashMap<u32, Arc<RwLock<Wal>>>,
     /// maximum file ID opened
     max_fid: u32,
-    files_to_delete: Vec<u32>,
+    /// Garbage collected value logs, together with timestamps after which
+    /// no read would access them.
+    files_to_delete: Vec<(u32, u64)>,
     num_entries_written: u32,
 }
 
@@ -46,7 +55,12 @@ pub struct ValueLog {
     /// value log file mapping, use `RwLock` to support concurrent read
     core: Arc<RwLock<Core>>,
     /// offset of next write
-    writeable_log_offset: AtomicU32,
+    writeable_log_offset: AtomicU64,
+    /// serializes garbage collection
+    gc_lock: Mutex<()>,
+    /// Position before which all entries have been flushed to LSM tree,
+    /// which is persisted to VLOG-HEAD. `None` if it's never recorded.
+    head: Mutex<Option<ValuePointer>>,
     opts: AgateOptions,
 }
 
@@ -61,10 +75,10 @@ impl ValueLog {
                 core: Arc::new(RwLock::new(Core::new())),
                 dir_path: opts.value_dir.clone(),
                 opts,
-                writeable_log_offset: AtomicU32::new(0),
+                writeable_log_offset: AtomicU64::new(0),
+                gc_lock: Mutex::new(()),
+                head: Mutex::new(None),
             };
-            // TODO: garbage collection
-            // TODO: discard stats
             core.open()?;
             Some(core)
         };
@@ -87,6 +101,12 @@ impl ValueLog {
                         let fid: u32 = filename[..filename.len() - 5].parse().map_err(|err| {
                             Error::InvalidFilename(format!("failed to parse file ID {:?}", err))
                         })?;
+                        // Files without a complete header are left by crashes
+                        // right after creation, which contain no entry.
+                        if file.metadata()?.len() < VLOG_HEADER_SIZE as u64 {
+                            fs::remove_file(file.path())?;
+                            continue;
+                        }
                         let wal = Wal::open(file.path(), self.opts.clone())?;
                         let wal = Arc::new(RwLock::new(wal));
                         if core.files_map.insert(fid, wal).is_some() {
@@ -121,9 +141,11 @@ impl ValueLog {
         assert!(core.files_map.insert(fid, wal.clone()).is_none());
         assert!(core.max_fid < fid);
         core.max_fid = fid;
-        // TODO: add vlog header
+        if let Some(stats) = &self.opts.discard_stats {
+            stats.add_file(fid);
+        }
         self.writeable_log_offset
-            .store(0, std::sync::atomic::Ordering::SeqCst);
+            .store(VLOG_HEADER_SIZE as u64, std::sync::atomic::Ordering::SeqCst);
         core.num_entries_written = 0;
         Ok((fid, wal))
     }
@@ -131,7 +153,7 @@ impl ValueLog {
     fn sorted_fids(&self) -> Vec<u32> {
         let core = self.core.read();
         let mut to_be_deleted = HashSet::new();
-        for fid in &core.files_to_delete {
+        for (fid, _) in &core.files_to_delete {
             to_be_deleted.insert(*fid);
         }
         let mut result = vec![];
@@ -146,14 +168,47 @@ impl ValueLog {
     }
 
     /// Open value log directory
+    ///
+    /// The last value log may be preallocated or torn by a crash, so it's
+    /// truncated to the end of its last valid entry, and deleted if there's
+    /// no entry. New entries are always written to a new value log.
     fn open(&self) -> Result<()> {
         self.populate_files_map()?;
-        // TODO: find empty files and iterate vlogs
+        let head_path = self.dir_path.join(HEAD_FILENAME);
+        if head_path.exists() {
+            let mut head = ValuePointer::default();
+            head.decode(&fs::read(&head_path)?)?;
+            *self.head.lock() = Some(head);
+        }
+
+        let mut core = self.core.write();
+        let max_fid = core.max_fid;
+        if let Some(log) = core.files_map.get(&max_fid).cloned() {
+            let mut log = log.write();
+            let end = {
+                let mut iter = log.iter()?;
+                while iter.next()?.is_some() {}
+                iter.valid_end_offset()
+            };
+            if end == VLOG_HEADER_SIZE as u64 {
+                drop(log);
+                core.files_map.remove(&max_fid);
+                fs::remove_file(self.file_path(max_fid))?;
+            } else {
+                log.truncate(end)?;
+            }
+        }
+        if let Some(stats) = &self.opts.discard_stats {
+            let fids: Vec<u32> = core.files_map.keys().copied().collect();
+            stats.set_files(&fids)?;
+        }
+        drop(core);
+
         self.create_vlog_file()?;
         Ok(())
     }
 
-    fn w_offset(&self) -> u32 {
+    fn w_offset(&self) -> u64 {
         self.writeable_log_offset
             .load(std::sync::atomic::Ordering::SeqCst)
     }
@@ -163,16 +218,21 @@ impl ValueLog {
     pub fn write(&self, requests: &mut [Request]) -> Result<()> {
         let result = self.write_inner(requests);
         if self.opts.sync_writes {
-            let core = self.core.read();
-            let current_log_id = core.max_fid;
-            let current_log_ptr = core.files_map.get(&current_log_id).unwrap().clone();
-            let mut current_log = current_log_ptr.write();
-            drop(core);
-            current_log.sync()?;
+            self.sync()?;
         }
         result
     }
 
+    /// Sync current value log to disk.
+    pub fn sync(&self) -> Result<()> {
+        let core = self.core.read();
+        let current_log_id = core.max_fid;
+        let current_log_ptr = core.files_map.get(&current_log_id).unwrap().clone();
+        let mut current_log = current_log_ptr.write();
+        drop(core);
+        current_log.sync()
+    }
+
     pub fn write_inner(&self, requests: &mut [Request]) -> Result<()> {
         let core = self.core.read();
         let mut current_log_id = core.max_fid;
@@ -186,17 +246,16 @@ impl ValueLog {
             if buf.is_empty() {
                 return Ok(());
             }
-            let n = buf.len() as u32;
+            let n = buf.len() as u64;
             let start = self
                 .writeable_log_offset
                 .fetch_add(n, std::sync::atomic::Ordering::SeqCst);
             let end_offset = start + n;
 
             // expand file size if space is not enough
-            // TODO: handle value >= 4GB case
             let mut current_log = current_log_lck.write();
             if end_offset >= current_log.size() {
-                current_log.set_len(end_offset as u64)?;
+                current_log.set_len(end_offset)?;
             }
             // As `start..end_offset` is only used by current write routine, we
             // could safely unlock the log lock and copy data inside.
@@ -217,7 +276,7 @@ impl ValueLog {
         // `to_disk` returns `true` if we need a new vLog.
         let to_disk = |current_log: &RwLock<Wal>| -> Result<bool> {
             let core = self.core.read();
-            if self.w_offset() as u64 > self.opts.value_log_file_size
+            if self.w_offset() > self.opts.value_log_file_size
                 || core.num_entries_written > self.opts.value_log_max_entries
             {
                 let mut current_log = current_log.write();
@@ -237,7 +296,12 @@ impl ValueLog {
             for mut entry in req.entries.iter_mut() {
                 buf.clear();
 
-                if self.opts.skip_vlog(entry) {
+                // Entries of transactions are always written, even if they
+                // are stored inline in LSM tree, so that a transaction could
+                // be replayed as a whole once its marker is found.
+                if self.opts.skip_vlog(entry)
+                    && entry.meta & (value::VALUE_TXN | value::VALUE_FIN_TXN) == 0
+                {
                     req.ptrs.push(ValuePointer::default());
                     continue;
                 }
@@ -248,12 +312,8 @@ impl ValueLog {
                     ..Default::default()
                 };
 
-                let orig_meta = entry.meta;
-                entry.meta &= !value::VALUE_FIN_TXN | value::VALUE_TXN;
-
-                let plen = Wal::encode_entry(&mut buf, entry);
-                entry.meta = orig_meta;
-                p.len = plen as u32;
+                let plen = current_log.read().encode_entry(&mut buf, entry, p.offset)?;
+                p.len = plen as u64;
                 req.ptrs.push(p);
                 write(&buf, &current_log)?;
 
@@ -295,9 +355,155 @@ impl ValueLog {
         }
     }
 
+    /// Pick the value log with the most discarded bytes for garbage
+    /// collection, of which at least `discard_ratio` has been discarded.
+    /// The current value log is never picked.
+    pub(crate) fn pick_log_for_gc(&self, discard_ratio: f64) -> Option<u32> {
+        let stats = self.opts.discard_stats.as_ref()?;
+        let max_fid = self.core.read().max_fid;
+        let fids: Vec<u32> = self
+            .sorted_fids()
+            .into_iter()
+            .filter(|fid| *fid != max_fid)
+            .collect();
+        let (fid, discarded) = stats.max_discard(&fids)?;
+        let size = self.core.read().files_map.get(&fid)?.read().size();
+        if (discarded as f64) < discard_ratio * size as f64 {
+            return None;
+        }
+        Some(fid)
+    }
+
+    /// Iterate over valid entries of value log `fid` from `offset`,
+    /// together with their value pointers.
+    pub(crate) fn iterate(
+        &self,
+        fid: u32,
+        offset: u64,
+        mut f: impl FnMut(Entry, ValuePointer) -> Result<()>,
+    ) -> Result<()> {
+        let log = self
+            .core
+            .read()
+            .files_map
+            .get(&fid)
+            .cloned()
+            .ok_or(Error::VlogNotFound(fid))?;
+        let log = log.read();
+        let mut iter = log.iter_from(offset)?;
+        loop {
+            let offset = iter.valid_end_offset();
+            let entry = match iter.next()? {
+                Some(entry) => Entry {
+                    key: Bytes::copy_from_slice(entry.key),
+                    value: Bytes::copy_from_slice(entry.value),
+                    meta: entry.meta,
+                    user_meta: entry.user_meta,
+                    expires_at: entry.expires_at,
+                    version: 0,
+                },
+                None => return Ok(()),
+            };
+            let vptr = ValuePointer {
+                file_id: fid,
+                len: iter.valid_end_offset() - offset,
+                offset,
+            };
+            f(entry, vptr)?;
+        }
+    }
+
+    /// Get the position before which all entries have been flushed to LSM
+    /// tree. Returns `None` if it's never recorded.
+    pub(crate) fn head(&self) -> Option<ValuePointer> {
+        self.head.lock().clone()
+    }
+
+    /// Move head forward to `head`, which is called after entries before
+    /// `head` are flushed to LSM tree.
+    ///
+    /// Head is written to a temporary file and renamed to VLOG-HEAD, so
+    /// that VLOG-HEAD is never partially written.
+    pub(crate) fn update_head(&self, head: &ValuePointer) -> Result<()> {
+        let mut current = self.head.lock();
+        if let Some(current) = &*current {
+            if (head.file_id, head.offset) <= (current.file_id, current.offset) {
+                return Ok(());
+            }
+        }
+
+        let mut buf = BytesMut::new();
+        head.encode(&mut buf);
+        let rewrite_path = self.dir_path.join(HEAD_REWRITE_FILENAME);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&rewrite_path)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&rewrite_path, self.dir_path.join(HEAD_FILENAME))?;
+        sync_dir(&self.dir_path)?;
+
+        *current = Some(head.clone());
+        Ok(())
+    }
+
+    /// Iterate over entries written at or after `head`, which are from the
+    /// oldest value log to the newest.
+    pub(crate) fn replay(
+        &self,
+        head: &ValuePointer,
+        mut f: impl FnMut(Entry, ValuePointer) -> Result<()>,
+    ) -> Result<()> {
+        for fid in self.sorted_fids() {
+            if fid < head.file_id {
+                continue;
+            }
+            let offset = if fid == head.file_id { head.offset } else { 0 };
+            self.iterate(fid, offset, &mut f)?;
+        }
+        Ok(())
+    }
+
+    /// Mark value log `fid` as garbage collected. It will be deleted once
+    /// reads at or below `delete_ts` are all done.
+    pub(crate) fn mark_for_deletion(&self, fid: u32, delete_ts: u64) {
+        self.core.write().files_to_delete.push((fid, delete_ts));
+    }
+
+    /// Delete garbage collected value logs, which are no longer accessed by
+    /// reads at or below `discard_ts`.
+    pub(crate) fn delete_pending_files(&self, discard_ts: u64) -> Result<()> {
+        let mut core = self.core.write();
+        let (deleted, pending): (Vec<_>, Vec<_>) = std::mem::take(&mut core.files_to_delete)
+            .into_iter()
+            .partition(|(_, delete_ts)| *delete_ts <= discard_ts);
+        core.files_to_delete = pending;
+        for (fid, _) in &deleted {
+            core.files_map.remove(fid);
+        }
+        drop(core);
+
+        for (fid, _) in deleted {
+            fs::remove_file(self.file_path(fid))?;
+            if let Some(stats) = &self.opts.discard_stats {
+                stats.remove(fid)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Serializes garbage collection.
+    pub(crate) fn lock_gc(&self) -> parking_lot::MutexGuard<'_, ()> {
+        self.gc_lock.lock()
+    }
+
     /// Read data from vlogs.
     /// The returned value is a `Bytes`, including the whole entry.
     /// You may need to manually decode it with `Wal::decode_wntry`.
+    /// Returns `Error::InvalidChecksum` if the entry is corrupted.
     ///
     /// TODO: let user to decide when to unlock instead of blocking.
     /// TODO: return header together with k-v pair.
@@ -308,20 +514,20 @@ impl ValueLog {
         let original_buf = buf.slice(..);
         drop(r);
 
-        // TODO: verify checksum
-
         let mut header = Header::default();
         header.decode(&mut buf)?;
         let kv = buf;
 
-        if (kv.len() as u32) < header.key_len + header.value_len {
+        let kv_len = header.key_len as u64 + header.value_len;
+        if (kv.len() as u64) < kv_len + CRC_SIZE as u64 {
             return Err(InvalidValuePointerError {
                 vptr: value_ptr,
                 kvlen: kv.len(),
-                range: header.key_len..header.key_len + header.value_len,
+                range: header.key_len as u64..kv_len,
             }
             .into());
         }
+        wal::verify_entry(&original_buf)?;
         Ok(original_buf)
     }
 }
@@ -330,6 +536,7 @@ impl ValueLog {
 mod tests {
     use super::*;
     use crate::entry::Entry;
+    use crate::key_registry::KeyRegistry;
     use tempfile::tempdir;
     use value::VALUE_POINTER;
 
@@ -380,4 +587,67 @@ mod tests {
         assert_eq!(&e2.key[..], b"samplekeyb");
         assert_eq!(&e2.value[..], val2);
     }
+
+    #[test]
+    fn test_value_log_large_offset() {
+        let mut opts = AgateOptions::default();
+        let tmp_dir = tempdir().unwrap();
+        opts.dir = tmp_dir.path().to_path_buf();
+        opts.value_dir = tmp_dir.path().to_path_buf();
+        opts.value_threshold = 32;
+        opts.value_log_file_size = 1024;
+        opts.encryption_key = vec![7; 16];
+        opts.key_registry = Some(Arc::new(KeyRegistry::open(&opts).unwrap()));
+        let vlog = ValueLog::new(opts).unwrap().unwrap();
+
+        // Value log is a sparse file, so writing beyond 4 GiB is cheap.
+        // Entries are written across the u32 offset boundary.
+        let offset = u32::MAX as u64 - 200;
+        vlog.writeable_log_offset
+            .store(offset, std::sync::atomic::Ordering::SeqCst);
+        let entries = (0..10)
+            .map(|i| {
+                Entry::new(
+                    Bytes::from(format!("key{}", i)),
+                    Bytes::from(format!("{:064}", i)),
+                )
+            })
+            .collect();
+        let mut reqs = vec![Request {
+            entries,
+            ptrs: vec![],
+            done: None,
+        }];
+        vlog.write(&mut reqs).unwrap();
+
+        let req = reqs.pop().unwrap();
+        assert_eq!(req.ptrs[0].offset, offset);
+        assert!(req.ptrs.last().unwrap().offset > u32::MAX as u64);
+        for (i, ptr) in req.ptrs.iter().enumerate() {
+            let mut buf = BytesMut::new();
+            ptr.encode(&mut buf);
+            let mut decoded = ValuePointer::default();
+            decoded.decode(&buf).unwrap();
+            assert_eq!((decoded.offset, decoded.len), (ptr.offset, ptr.len));
+
+            let mut buf = vlog.read(decoded).unwrap();
+            let entry = Wal::decode_entry(&mut buf).unwrap();
+            assert_eq!(entry.key, format!("key{}", i));
+            assert_eq!(entry.value, format!("{:064}", i));
+        }
+
+        // Value log is rotated after the write, as it exceeds the file size.
+        let last = req.ptrs.last().unwrap();
+        let core = vlog.core.read();
+        let wal = core.files_map[&last.file_id].read();
+        let mut iter = wal.iter_from(offset).unwrap();
+        let mut cnt = 0;
+        while let Some(entry) = iter.next().unwrap() {
+            assert_eq!(entry.key, format!("key{}", cnt).as_bytes());
+            assert_eq!(entry.value, format!("{:064}", cnt).as_bytes());
+            cnt += 1;
+        }
+        assert_eq!(cnt, 10);
+        assert_eq!(iter.valid_end_offset(), last.offset + last.len);
+    }
 }
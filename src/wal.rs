@@ -1,3 +1,5 @@
+use crate::checksum;
+use crate::encryption::{self, IV_SIZE};
 use crate::entry::{Entry, EntryRef};
 use crate::util::sync_dir;
 use crate::value::{EntryReader, ValuePointer};
@@ -7,11 +9,36 @@ use crate::Result;
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use memmap2::{MmapMut, MmapOptions};
 use prost::{decode_length_delimiter, encode_length_delimiter, length_delimiter_len};
-use std::fs::{File, OpenOptions};
+use proto::meta::{checksum::Algorithm as ChecksumAlgorithm, Checksum, DataKey};
+use std::fs::{self, File, OpenOptions};
 use std::io::Cursor;
 use std::path::PathBuf;
 
-pub const MAX_HEADER_SIZE: usize = 21;
+/// Max size of an encoded varint of a `u32`.
+const MAX_VARINT_U32_SIZE: usize = 5;
+/// Max size of an encoded varint of a `u64`.
+const MAX_VARINT_U64_SIZE: usize = 10;
+
+/// Max size of an encoded `Header`: `meta` and `user_meta`, followed by
+/// varints of `key_len` (u32), `value_len` (u64) and `expires_at` (u64).
+pub const MAX_HEADER_SIZE: usize = 1 + 1 + MAX_VARINT_U32_SIZE + 2 * MAX_VARINT_U64_SIZE;
+
+/// Size of CRC32C checksum following each entry.
+pub const CRC_SIZE: usize = 4;
+
+/// Length of base IV in header of WAL. IV of an entry is base IV followed
+/// by offset of the entry.
+const BASE_IV_SIZE: usize = IV_SIZE - 4;
+
+/// Magic text at the beginning of WAL and value log.
+const MAGIC_TEXT: &[u8; 4] = b"AgLg";
+/// Format version of WAL and value log, which should be bumped on
+/// incompatible format changes.
+const MAGIC_VERSION: u32 = 1;
+
+/// Size of header at the beginning of WAL and value log, which consists
+/// of magic text, format version, data key id and base IV.
+pub const VLOG_HEADER_SIZE: usize = MAGIC_TEXT.len() + 4 + 8 + BASE_IV_SIZE;
 
 /// `Header` stores metadata of an entry in WAL and in value log.
 #[derive(Default, Debug, PartialEq)]
@@ -19,7 +46,7 @@ pub struct Header {
     /// length of key
     pub key_len: u32,
     /// length of value
-    pub value_len: u32,
+    pub value_len: u64,
     /// entry expire date
     pub expires_at: u64,
     /// metadata
@@ -66,7 +93,7 @@ impl Header {
         self.meta = bytes.get_u8();
         self.user_meta = bytes.get_u8();
         self.key_len = decode_length_delimiter(&mut bytes)? as u32;
-        self.value_len = decode_length_delimiter(&mut bytes)? as u32;
+        self.value_len = decode_length_delimiter(&mut bytes)? as u64;
         self.expires_at = decode_length_delimiter(&mut bytes)? as u64;
         Ok(())
     }
@@ -74,17 +101,23 @@ impl Header {
 
 /// WAL of a memtable or a value log
 ///
-/// TODO: This WAL simply stores key-value pair in sequence without checksum,
-/// encryption and compression. These will be done later.
+/// WAL starts with a header of data key id and base IV, followed by
+/// entries. Keys and values of entries are encrypted if data key id is not 0.
+///
+/// TODO: This WAL simply stores key-value pair in sequence without
+/// compression. This will be done later.
 /// TODO: delete WAL file when reference to WAL (or memtable) comes to 0
 pub struct Wal {
     path: PathBuf,
     file: File,
     mmap_file: MmapMut,
     opts: AgateOptions,
-    write_at: u32,
+    write_at: u64,
     buf: BytesMut,
-    size: u32,
+    size: u64,
+    /// `None` if WAL is not encrypted
+    data_key: Option<DataKey>,
+    base_iv: Vec<u8>,
 }
 
 impl Wal {
@@ -114,39 +147,107 @@ impl Wal {
         let mut wal = Wal {
             path,
             file,
-            size: mmap_file.len() as u32,
+            size: mmap_file.len() as u64,
             mmap_file,
             opts,
-            write_at: 0,
-            // TODO: current implementation doesn't have keyID and baseIV header
+            write_at: VLOG_HEADER_SIZE as u64,
             buf: BytesMut::new(),
+            data_key: None,
+            base_iv: vec![],
         };
 
         if bootstrap {
             wal.bootstrap()?;
+        } else {
+            wal.read_header()?;
         }
 
-        // TODO: We should read vlog headers and data key from WAL after we implement
-        // checksum / encryption support.
-
         Ok(wal)
     }
 
+    /// Write header with the latest data key to a new WAL.
     fn bootstrap(&mut self) -> Result<()> {
+        self.data_key = self.opts.latest_data_key()?;
+        self.base_iv = encryption::random_bytes(BASE_IV_SIZE);
+        let mut header = &mut self.mmap_file[..VLOG_HEADER_SIZE];
+        header.put_slice(MAGIC_TEXT);
+        header.put_u32(MAGIC_VERSION);
+        header.put_u64(self.data_key.as_ref().map_or(0, |k| k.key_id));
+        header.put_slice(&self.base_iv);
         self.zero_next_entry()?;
         Ok(())
     }
 
+    fn read_header(&mut self) -> Result<()> {
+        if (self.size as usize) < VLOG_HEADER_SIZE {
+            return Err(Error::LogRead(format!(
+                "header of {} is truncated",
+                self.path.display()
+            )));
+        }
+        let mut header = &self.mmap_file[..VLOG_HEADER_SIZE];
+        if header.iter().all(|b| *b == 0) {
+            // Header is not persisted if crashed right after the file is
+            // created, in which case there is no entry either.
+            return self.bootstrap();
+        }
+        if &header[..MAGIC_TEXT.len()] != MAGIC_TEXT {
+            return Err(Error::LogRead(format!(
+                "bad magic of {}",
+                self.path.display()
+            )));
+        }
+        header.advance(MAGIC_TEXT.len());
+        let version = header.get_u32();
+        if version != MAGIC_VERSION {
+            return Err(Error::LogRead(format!(
+                "unsupported version {} of {}, expected {}",
+                version,
+                self.path.display(),
+                MAGIC_VERSION
+            )));
+        }
+        let key_id = header.get_u64();
+        self.data_key = self.opts.data_key(key_id)?;
+        self.base_iv = header.to_vec();
+        Ok(())
+    }
+
+    /// Encrypt or decrypt key and value of the entry at `offset` in place.
+    ///
+    /// IV is base IV followed by 4 zero bytes, plus `offset`. It's the same
+    /// as base IV followed by `offset` if `offset` fits in u32, and IVs of
+    /// entries never overlap as CTR counter grows slower than offset.
+    fn xor_kv(&self, kv: &mut [u8], offset: u64) -> Result<()> {
+        if let Some(key) = &self.data_key {
+            let mut base_iv = [0; IV_SIZE];
+            base_iv[..BASE_IV_SIZE].copy_from_slice(&self.base_iv);
+            let iv = u128::from_be_bytes(base_iv).wrapping_add(offset as u128);
+            encryption::xor_block(kv, &key.data, &iv.to_be_bytes())?;
+        }
+        Ok(())
+    }
+
     pub(crate) fn write_entry(&mut self, entry: &Entry) -> Result<()> {
-        self.buf.clear();
-        Self::encode_entry(&mut self.buf, entry);
-        self.mmap_file[self.write_at as usize..self.write_at as usize + self.buf.len()]
-            .clone_from_slice(&self.buf[..]);
-        self.write_at += self.buf.len() as u32;
+        let mut buf = std::mem::take(&mut self.buf);
+        buf.clear();
+        self.encode_entry(&mut buf, entry, self.write_at)?;
+        self.mmap_file[self.write_at as usize..self.write_at as usize + buf.len()]
+            .clone_from_slice(&buf[..]);
+        self.write_at += buf.len() as u64;
+        self.buf = buf;
         self.zero_next_entry()?;
         Ok(())
     }
 
+    /// Close WAL and remove the file from disk.
+    pub fn close_and_remove(self) -> Result<()> {
+        let path = self.path.clone();
+        drop(self);
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
     pub fn sync(&mut self) -> Result<()> {
         self.mmap_file.flush()?;
         Ok(())
@@ -161,38 +262,48 @@ impl Wal {
         Ok(())
     }
 
-    /// Encode entry to buffer
+    /// Encode entry, which will be written at `offset` of WAL, to buffer
     ///
-    /// The entry is encoded to a header followed by plain key and value.
-    /// +--------+-----+-------+
-    /// | header | key | value |
-    /// +--------+-----+-------+
-    pub(crate) fn encode_entry(mut buf: &mut BytesMut, entry: &Entry) -> usize {
+    /// The entry is encoded to a header followed by key and value, which are
+    /// encrypted if WAL is encrypted, and CRC32C checksum of header and
+    /// plain key and value.
+    /// +--------+-----+-------+-----+
+    /// | header | key | value | crc |
+    /// +--------+-----+-------+-----+
+    pub(crate) fn encode_entry(
+        &self,
+        buf: &mut BytesMut,
+        entry: &Entry,
+        offset: u64,
+    ) -> Result<usize> {
         let header = Header {
             key_len: entry.key.len() as u32,
-            value_len: entry.value.len() as u32,
+            value_len: entry.value.len() as u64,
             expires_at: entry.expires_at,
             meta: entry.meta,
             user_meta: entry.user_meta,
         };
 
         // write header to buffer
-        header.encode(&mut buf);
+        let start = buf.len();
+        header.encode(buf);
 
         // write key and value to buffer
-        // TODO: encryption
+        let kv_start = buf.len();
         buf.extend_from_slice(&entry.key);
         buf.extend_from_slice(&entry.value);
+        let crc = checksum::calculate_checksum(&buf[start..], ChecksumAlgorithm::Crc32c);
+        self.xor_kv(&mut buf[kv_start..], offset)?;
+        buf.put_u32(crc as u32);
 
-        // TODO: add CRC32 check
-
-        buf.len()
+        Ok(buf.len())
     }
 
-    /// Decode entry from buffer
+    /// Decode entry from buffer, of which key and value have been decrypted.
+    /// Returns `Error::InvalidChecksum` if entry is corrupted.
     pub(crate) fn decode_entry(buf: &mut Bytes) -> Result<Entry> {
-        let mut header = Header::default();
-        header.decode(buf)?;
+        let (header, header_len) = verify_entry(buf)?;
+        buf.advance(header_len);
         let kv = buf;
         Ok(Entry {
             meta: header.meta,
@@ -206,23 +317,30 @@ impl Wal {
         })
     }
 
-    /// Read value from WAL (when used as value log)
+    /// Read entry from WAL (when used as value log). Key and value are
+    /// decrypted if WAL is encrypted.
     pub(crate) fn read(&self, p: &ValuePointer) -> Result<Bytes> {
         let offset = p.offset;
         let size = self.mmap_file.len() as u64;
         let value_size = p.len;
         let log_size = self.size;
 
-        if offset as u64 >= size
-            || offset as u64 + value_size as u64 > size
-            || offset as u64 + value_size as u64 > log_size as u64
-        {
+        if offset >= size || offset + value_size > size || offset + value_size > log_size {
             return Err(Error::LogRead("EOF".to_string()));
         }
 
-        Ok(Bytes::copy_from_slice(
-            &self.mmap_file[offset as usize..offset as usize + value_size as usize],
-        ))
+        let mut buf =
+            BytesMut::from(&self.mmap_file[offset as usize..offset as usize + value_size as usize]);
+        if self.data_key.is_some() {
+            let mut kv = &buf[..];
+            let mut header = Header::default();
+            header.decode(&mut kv)?;
+            let kv_start = buf.len() - kv.len();
+            let kv_end =
+                (kv_start + header.key_len as usize + header.value_len as usize).min(buf.len());
+            self.xor_kv(&mut buf[kv_start..kv_end], offset)?;
+        }
+        Ok(buf.freeze())
     }
 
     /// Truncate WAL
@@ -232,48 +350,55 @@ impl Wal {
         if metadata.len() == end {
             return Ok(());
         }
-        self.size = end as u32;
+        self.size = end;
         self.file.set_len(end)?;
         self.file.sync_all()?;
         Ok(())
     }
 
     /// Finish WAL writing
-    pub(crate) fn done_writing(&mut self, offset: u32) -> Result<()> {
+    pub(crate) fn done_writing(&mut self, offset: u64) -> Result<()> {
         if self.opts.sync_writes {
             self.file.sync_all()?;
         }
-        self.truncate(offset as u64)?;
+        self.truncate(offset)?;
         Ok(())
     }
 
     /// Get WAL iterator
-    pub fn iter(&mut self) -> Result<WalIterator> {
-        Ok(WalIterator::new(Cursor::new(
-            &self.mmap_file[0..self.size as usize],
-        )))
+    pub fn iter(&self) -> Result<WalIterator<'_>> {
+        self.iter_from(VLOG_HEADER_SIZE as u64)
+    }
+
+    /// Get WAL iterator starting from the entry at `offset`.
+    pub fn iter_from(&self, offset: u64) -> Result<WalIterator<'_>> {
+        Ok(WalIterator::new(self, offset))
     }
 
     pub fn should_flush(&self) -> bool {
-        self.write_at as u64 > self.opts.value_log_file_size
+        self.write_at > self.opts.value_log_file_size
     }
 
     /// Get real size of WAL. After truncating, WAL mmap file will have different
     /// size against real file. `size` stores the actual length.
-    pub(crate) fn size(&self) -> u32 {
+    pub(crate) fn size(&self) -> u64 {
         self.size
     }
 
     /// When using WAL as value log, we will need to extend or shrink actual size
     /// of WAL file from outside functions.
-    pub(crate) fn set_size(&mut self, size: u32) {
+    pub(crate) fn set_size(&mut self, size: u64) {
         self.size = size;
     }
 
     /// When using WAL as value log, we will need to extend or shrink actual size
-    /// of WAL file from outside functions.
+    /// of WAL file from outside functions. File is mapped again if it grows
+    /// beyond the current mapping.
     pub(crate) fn set_len(&mut self, len: u64) -> Result<()> {
         self.file.set_len(len)?;
+        if len > self.mmap_file.len() as u64 {
+            self.mmap_file = unsafe { MmapOptions::new().map_mut(&self.file)? };
+        }
         Ok(())
     }
 
@@ -282,21 +407,66 @@ impl Wal {
     }
 }
 
+/// Verify checksum of `data`, which is an encoded header followed by plain
+/// key and value.
+pub(crate) fn verify_entry_checksum(data: &[u8], crc: u32) -> Result<()> {
+    checksum::verify_checksum(
+        data,
+        &Checksum {
+            algo: ChecksumAlgorithm::Crc32c as i32,
+            sum: crc as u64,
+        },
+    )
+}
+
+/// Verify checksum of an encoded entry, of which key and value have been
+/// decrypted. Returns header of the entry and length of the header.
+pub(crate) fn verify_entry(buf: &[u8]) -> Result<(Header, usize)> {
+    let mut kv = buf;
+    let mut header = Header::default();
+    header.decode(&mut kv)?;
+    let header_len = buf.len() - kv.len();
+    let end = header_len + header.key_len as usize + header.value_len as usize;
+    if buf.len() < end + CRC_SIZE {
+        return Err(Error::LogRead(format!(
+            "entry is truncated, expected {} bytes, got {}",
+            end + CRC_SIZE,
+            buf.len()
+        )));
+    }
+    verify_entry_checksum(&buf[..end], (&buf[end..]).get_u32())?;
+    Ok((header, header_len))
+}
+
 pub struct WalIterator<'a> {
+    wal: &'a Wal,
     /// `reader` stores the file to read
     reader: Cursor<&'a [u8]>,
     /// `entry_reader` operates on `reader` and buffers entry information
     entry_reader: EntryReader,
+    /// end offset of the last valid entry
+    valid_end_offset: u64,
 }
 
 impl<'a> WalIterator<'a> {
-    pub fn new(reader: Cursor<&'a [u8]>) -> Self {
+    pub fn new(wal: &'a Wal, offset: u64) -> Self {
+        let mut reader = Cursor::new(&wal.mmap_file[0..wal.size as usize]);
+        let offset = offset.max(VLOG_HEADER_SIZE as u64).min(wal.size);
+        reader.set_position(offset);
         Self {
+            wal,
             reader,
             entry_reader: EntryReader::new(),
+            valid_end_offset: offset,
         }
     }
 
+    /// Get end offset of the last valid entry. Data after this offset
+    /// is either empty or corrupted.
+    pub fn valid_end_offset(&self) -> u64 {
+        self.valid_end_offset
+    }
+
     /// Get next entry from WAL
     ///
     /// This function will:
@@ -306,13 +476,18 @@ impl<'a> WalIterator<'a> {
     pub fn next(&mut self) -> Result<Option<EntryRef<'_>>> {
         use std::io::ErrorKind;
 
-        let entry = self.entry_reader.entry(&mut self.reader);
+        let offset = self.reader.position();
+        let wal = self.wal;
+        let entry = self
+            .entry_reader
+            .entry(&mut self.reader, |kv| wal.xor_kv(kv, offset));
 
         match entry {
             Ok(entry) => {
                 if entry.is_zero() {
                     return Ok(None);
                 }
+                self.valid_end_offset = self.reader.position();
                 // TODO: process transaction-related metadata
                 Ok(Some(entry))
             }
@@ -320,6 +495,8 @@ impl<'a> WalIterator<'a> {
             Err(Error::Decode(_)) => Ok(None),
             // ignore custom decode error (e.g. header <= 2)
             Err(Error::VarDecode(_)) => Ok(None),
+            // ignore torn or corrupted entry
+            Err(Error::InvalidChecksum(_)) => Ok(None),
             // ignore file length < key, value size
             Err(Error::Io(err)) => {
                 if err.kind() == ErrorKind::UnexpectedEof {
@@ -336,6 +513,8 @@ impl<'a> WalIterator<'a> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::key_registry::KeyRegistry;
+    use std::sync::Arc;
     use tempfile::tempdir;
 
     #[test]
@@ -346,6 +525,131 @@ mod tests {
         Wal::open(tmp_dir.path().join("1.wal"), opts).unwrap();
     }
 
+    #[test]
+    fn test_wal_encryption() {
+        let tmp_dir = tempdir().unwrap();
+        let mut opts = AgateOptions::default();
+        opts.dir = tmp_dir.path().to_path_buf();
+        opts.value_log_file_size = 4096;
+        opts.encryption_key = vec![7; 32];
+        opts.key_registry = Some(Arc::new(KeyRegistry::open(&opts).unwrap()));
+        let wal_path = tmp_dir.path().join("1.wal");
+        let mut wal = Wal::open(wal_path.clone(), opts.clone()).unwrap();
+        let mut offsets = vec![];
+        for i in 0..20 {
+            let entry = Entry::new(
+                Bytes::from(format!("key{}", i)),
+                Bytes::from(format!("value{}", i)),
+            );
+            offsets.push(wal.write_at);
+            wal.write_entry(&entry).unwrap();
+        }
+        let vp = ValuePointer {
+            file_id: 0,
+            len: offsets[4] - offsets[3],
+            offset: offsets[3],
+        };
+        let mut buf = wal.read(&vp).unwrap();
+        drop(wal);
+        assert_eq!(Wal::decode_entry(&mut buf).unwrap().value, "value3");
+
+        let data = fs::read(&wal_path).unwrap();
+        assert_eq!((&data[8..16]).get_u64(), 1);
+        assert!(!data.windows(5).any(|w| w == b"value"));
+
+        // reopen WAL and iterate
+        let wal = Wal::open(wal_path, opts).unwrap();
+        let mut it = wal.iter().unwrap();
+        let mut cnt = 0;
+        while let Some(entry) = it.next().unwrap() {
+            assert_eq!(entry.key, format!("key{}", cnt).as_bytes());
+            assert_eq!(entry.value, format!("value{}", cnt).as_bytes());
+            cnt += 1;
+        }
+        assert_eq!(cnt, 20);
+    }
+
+    #[test]
+    fn test_wal_header() {
+        let tmp_dir = tempdir().unwrap();
+        let mut opts = AgateOptions::default();
+        opts.value_log_file_size = 4096;
+        let wal_path = tmp_dir.path().join("1.wal");
+        let mut wal = Wal::open(wal_path.clone(), opts.clone()).unwrap();
+        wal.write_entry(&Entry::new(Bytes::from("key"), Bytes::from("value")))
+            .unwrap();
+        drop(wal);
+
+        let data = fs::read(&wal_path).unwrap();
+        assert_eq!(&data[..4], MAGIC_TEXT);
+        assert_eq!((&data[4..8]).get_u32(), MAGIC_VERSION);
+        assert_eq!((&data[8..16]).get_u64(), 0);
+
+        let open_with_header = |header: &[u8]| {
+            let mut data = data.clone();
+            data[..header.len()].copy_from_slice(header);
+            fs::write(&wal_path, &data).unwrap();
+            Wal::open(wal_path.clone(), opts.clone())
+        };
+        assert!(matches!(open_with_header(b"AgLx"), Err(Error::LogRead(_))));
+        let mut header = MAGIC_TEXT.to_vec();
+        header.put_u32(MAGIC_VERSION + 1);
+        assert!(matches!(open_with_header(&header), Err(Error::LogRead(_))));
+
+        let wal = open_with_header(&data[..VLOG_HEADER_SIZE]).unwrap();
+        let mut it = wal.iter().unwrap();
+        assert_eq!(it.next().unwrap().unwrap().key, b"key");
+        drop(wal);
+
+        // A file without header is bootstrapped again.
+        let mut wal = open_with_header(&[0; VLOG_HEADER_SIZE]).unwrap();
+        wal.write_entry(&Entry::new(Bytes::from("key"), Bytes::from("value")))
+            .unwrap();
+        drop(wal);
+        assert_eq!(&fs::read(&wal_path).unwrap()[..4], MAGIC_TEXT);
+    }
+
+    #[test]
+    fn test_wal_checksum() {
+        let tmp_dir = tempdir().unwrap();
+        let mut opts = AgateOptions::default();
+        opts.value_log_file_size = 4096;
+        let wal_path = tmp_dir.path().join("1.wal");
+        let mut wal = Wal::open(wal_path.clone(), opts.clone()).unwrap();
+        let entry = Entry::new(Bytes::from("key"), Bytes::from("value"));
+        let mut buf = BytesMut::new();
+        let len = wal.encode_entry(&mut buf, &entry, 0).unwrap();
+        assert_eq!(len, entry.key.len() + entry.value.len() + 5 + CRC_SIZE);
+        let decoded = Wal::decode_entry(&mut buf.clone().freeze()).unwrap();
+        assert_eq!((decoded.key, decoded.value), (entry.key, entry.value));
+        for i in 0..len {
+            let mut corrupted = buf.clone();
+            corrupted[i] ^= 1;
+            assert!(Wal::decode_entry(&mut corrupted.freeze()).is_err());
+        }
+
+        let mut offsets = vec![];
+        for i in 0..20 {
+            let entry = Entry::new(Bytes::from(i.to_string()), Bytes::from(i.to_string()));
+            offsets.push(wal.write_at);
+            wal.write_entry(&entry).unwrap();
+        }
+        // flip a bit in value of entry 10
+        wal.data()[offsets[11] as usize - CRC_SIZE - 1] ^= 1;
+        drop(wal);
+
+        // replay stops at the corrupted entry
+        let wal = Wal::open(wal_path, opts).unwrap();
+        let mut it = wal.iter().unwrap();
+        let mut cnt = 0;
+        while let Some(entry) = it.next().unwrap() {
+            assert_eq!(entry.key, cnt.to_string().as_bytes());
+            cnt += 1;
+        }
+        assert_eq!(cnt, 10);
+        assert_eq!(it.valid_end_offset(), offsets[10]);
+    }
+
     #[test]
     fn test_header_encode() {
         let header = Header {
@@ -363,6 +667,18 @@ mod tests {
         let mut new_header = Header::default();
         new_header.decode(&mut buf).unwrap();
         assert_eq!(new_header, header);
+
+        let header = Header {
+            key_len: u32::MAX,
+            value_len: u64::MAX,
+            expires_at: u64::MAX,
+            user_meta: u8::MAX,
+            meta: u8::MAX,
+        };
+        let mut buf = BytesMut::new();
+        header.encode(&mut buf);
+        assert_eq!(buf.len(), MAX_HEADER_SIZE);
+        assert_eq!(header.encoded_len(), MAX_HEADER_SIZE);
     }
 
     #[test]
@@ -379,7 +695,7 @@ mod tests {
         drop(wal);
 
         // reopen WAL and iterate
-        let mut wal = Wal::open(wal_path, opts).unwrap();
+        let wal = Wal::open(wal_path, opts).unwrap();
         let mut it = wal.iter().unwrap();
         let mut cnt = 0;
         while let Some(entry) = it.next().unwrap() {
@@ -414,7 +730,7 @@ mod tests {
             drop(file);
 
             // reopen WAL and iterate
-            let mut wal = Wal::open(wal_path.clone(), opts.clone()).unwrap();
+            let wal = Wal::open(wal_path.clone(), opts.clone()).unwrap();
             let mut it = wal.iter().unwrap();
             let mut cnt = 0;
             while let Some(entry) = it.next().unwrap() {
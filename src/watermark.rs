@@ -0,0 +1,150 @@
+use std::collections::BTreeMap;
+use std::sync::{Condvar, Mutex};
+
+struct Core {
+    done_until: u64,
+    last_index: u64,
+    /// Number of pending marks of each index. Count may be negative if
+    /// `done` is called before `begin`.
+    pending: BTreeMap<u64, i64>,
+}
+
+/// `WaterMark` tracks indices (timestamps) which are being processed, and
+/// maintains `done_until`, the index below which (inclusive) all indices are
+/// done.
+///
+/// Indices are expected to `begin` in increasing order, but could be `done`
+/// in any order.
+pub struct WaterMark {
+    name: &'static str,
+    core: Mutex<Core>,
+    cond: Condvar,
+}
+
+impl WaterMark {
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            core: Mutex::new(Core {
+                done_until: 0,
+                last_index: 0,
+                pending: BTreeMap::new(),
+            }),
+            cond: Condvar::new(),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Reset watermark, so that all indices up to `index` are done.
+    pub fn init(&self, index: u64) {
+        let mut core = self.core.lock().unwrap();
+        core.done_until = index;
+        core.last_index = index;
+        core.pending.clear();
+        self.cond.notify_all();
+    }
+
+    pub fn begin(&self, index: u64) {
+        let mut core = self.core.lock().unwrap();
+        core.last_index = core.last_index.max(index);
+        Self::update(&mut core, index, 1);
+    }
+
+    pub fn done(&self, index: u64) {
+        let mut core = self.core.lock().unwrap();
+        if Self::update(&mut core, index, -1) {
+            self.cond.notify_all();
+        }
+    }
+
+    fn update(core: &mut Core, index: u64, delta: i64) -> bool {
+        let count = core.pending.entry(index).or_insert(0);
+        *count += delta;
+        if *count == 0 {
+            core.pending.remove(&index);
+        }
+
+        let done_until = match core.pending.iter().find(|(_, count)| **count > 0) {
+            Some((index, _)) => index.saturating_sub(1),
+            None => core.last_index,
+        };
+        if done_until > core.done_until {
+            core.done_until = done_until;
+            return true;
+        }
+        false
+    }
+
+    /// Returns the max index below which (inclusive) all indices are done.
+    pub fn done_until(&self) -> u64 {
+        self.core.lock().unwrap().done_until
+    }
+
+    pub fn last_index(&self) -> u64 {
+        self.core.lock().unwrap().last_index
+    }
+
+    /// Block until all indices up to `index` are done.
+    pub fn wait_for_mark(&self, index: u64) {
+        let mut core = self.core.lock().unwrap();
+        while core.done_until < index {
+            core = self.cond.wait(core).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_watermark() {
+        let mark = WaterMark::new("test");
+        mark.init(10);
+        assert_eq!(mark.done_until(), 10);
+
+        for i in 11..=13 {
+            mark.begin(i);
+        }
+        mark.done(12);
+        assert_eq!(mark.done_until(), 10);
+        mark.done(11);
+        assert_eq!(mark.done_until(), 12);
+
+        // Index could begin several times.
+        mark.begin(14);
+        mark.begin(14);
+        mark.done(13);
+        mark.done(14);
+        assert_eq!(mark.done_until(), 13);
+        mark.done(14);
+        assert_eq!(mark.done_until(), 14);
+        assert_eq!(mark.last_index(), 14);
+    }
+
+    #[test]
+    fn test_wait_for_mark() {
+        let mark = Arc::new(WaterMark::new("test"));
+        mark.init(1);
+        mark.begin(2);
+        mark.begin(3);
+
+        let mark2 = mark.clone();
+        let handle = thread::spawn(move || {
+            mark2.wait_for_mark(2);
+            mark2.done_until()
+        });
+        thread::sleep(Duration::from_millis(10));
+        mark.done(3);
+        mark.done(2);
+        assert_eq!(handle.join().unwrap(), 3);
+        // Marks already done never block.
+        mark.wait_for_mark(1);
+    }
+}